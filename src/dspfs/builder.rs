@@ -75,6 +75,8 @@ impl<S: Store + 'static> DspfsBuilderWithServer<S> {
 
             clients: HashMap::new(),
             serverhandle: None,
+            syncer: None,
+            watchers: HashMap::new(),
         }
     }
 }
@@ -1,11 +1,38 @@
-use crate::message::Message;
-use crate::stream::EncryptedStream;
-use crate::user::PrivateUser;
+use crate::fs::file::File;
+use crate::fs::filetree::NodeSummary;
+use crate::fs::group::changelog::FileTreeUpdate;
+use crate::fs::group::download::BlockSource;
+use crate::fs::group::invitation::Invitation;
+use crate::fs::group::membership::SignedMembershipOp;
+use crate::fs::hash::Hash;
+use crate::message::{ErrorMessage, Message};
+use crate::stream::{Conversation, EncryptedStream, Multiplexer, MULTIPLEX};
+use crate::user::{PrivateUser, PublicKey};
 use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpStream, ToSocketAddrs};
+use uuid::Uuid;
 
-pub struct Client {
-    stream: EncryptedStream<TcpStream>,
+/// The largest answer we accept ahead of a block or file tree, which follow as a byte stream
+const ANSWER_LIMIT: usize = 1024;
+
+/// The largest file tree we accept from someone
+const FILETREE_LIMIT: usize = 64 * 1024 * 1024;
+
+/// The largest membership log we accept from someone
+const MEMBERSHIP_LIMIT: usize = 16 * 1024 * 1024;
+
+pub struct Client<T: AsyncReadExt + AsyncWriteExt + Unpin = TcpStream> {
+    connection: Connection<T>,
+}
+
+enum Connection<T: AsyncReadExt + AsyncWriteExt + Unpin> {
+    /// One request at a time, for peers which can't multiplex
+    Plain(Box<EncryptedStream<T>>),
+    /// Every request on its own channel
+    Multiplexed(Multiplexer),
 }
 
 impl Client {
@@ -14,18 +41,185 @@ impl Client {
             .await
             .context("failed to create tcp connection")?;
 
-        Ok(Self {
-            stream: EncryptedStream::initiator(tcpstream, &user)
+        Ok(Self::from_stream(
+            EncryptedStream::initiator(tcpstream, &user)
                 .await
                 .context("failed to initiate secure tunnel")?,
-        })
+        ))
     }
 
-    pub async fn send(&mut self, msg: Message) -> Result<()> {
-        self.stream.send_message(msg).await
+    /// Connects to whoever has the key `expected`. Fails if someone else answers at `addr`.
+    pub async fn connect_to(
+        addr: impl ToSocketAddrs,
+        user: &PrivateUser,
+        expected: &PublicKey,
+    ) -> Result<Self> {
+        let tcpstream = TcpStream::connect(addr)
+            .await
+            .context("failed to create tcp connection")?;
+
+        Ok(Self::from_stream(
+            EncryptedStream::initiator_expecting(tcpstream, user, expected)
+                .await
+                .context("failed to initiate secure tunnel")?,
+        ))
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync + 'static> Client<T> {
+    /// Creates a client from an already established secure tunnel
+    pub fn from_stream(stream: EncryptedStream<T>) -> Self {
+        let connection = if stream.supports(MULTIPLEX) {
+            Connection::Multiplexed(Multiplexer::new(stream))
+        } else {
+            Connection::Plain(Box::new(stream))
+        };
+
+        Self { connection }
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> Client<T> {
+    /// Another client on the same connection, whose requests don't wait for ours. Only possible
+    /// when the connection is multiplexed.
+    pub fn share(&self) -> Option<Self> {
+        match &self.connection {
+            Connection::Plain(_) => None,
+            Connection::Multiplexed(multiplexer) => Some(Self {
+                connection: Connection::Multiplexed(multiplexer.clone()),
+            }),
+        }
+    }
+
+    /// Sends a message, and returns where the answer to it comes in
+    pub async fn send(&mut self, msg: Message) -> Result<Box<dyn Conversation + '_>> {
+        let mut conversation: Box<dyn Conversation> = match &mut self.connection {
+            Connection::Plain(stream) => Box::new(stream.as_mut()),
+            Connection::Multiplexed(multiplexer) => Box::new(multiplexer.open().await?),
+        };
+
+        conversation.send_message(msg).await?;
+        Ok(conversation)
     }
 
-    pub async fn recv(&mut self, limit: usize) -> Result<Message> {
-        self.stream.recv_message(limit).await
+    /// Asks the other side for all files they have in a group
+    pub async fn request_filetree(&mut self, groupuuid: Uuid) -> Result<FileTreeUpdate> {
+        let conversation = self.send(Message::FileTreeRequest { groupuuid }).await?;
+        recv_filetree(conversation).await
+    }
+
+    /// Asks the other side what changed in the files they have in a group after version `since`
+    pub async fn request_changes(&mut self, groupuuid: Uuid, since: u64) -> Result<FileTreeUpdate> {
+        let conversation = self
+            .send(Message::FileTreeChangesRequest { groupuuid, since })
+            .await?;
+        recv_filetree(conversation).await
+    }
+
+    /// Asks the other side what is in a folder of their file tree, unless it has hash `known`.
+    /// Also returns the version of their tree.
+    pub async fn request_summary(
+        &mut self,
+        groupuuid: Uuid,
+        path: PathBuf,
+        known: Hash,
+    ) -> Result<(u64, NodeSummary)> {
+        let mut conversation = self
+            .send(Message::FileTreeNodeRequest {
+                groupuuid,
+                path,
+                known,
+            })
+            .await?;
+
+        match conversation.recv_message(FILETREE_LIMIT).await? {
+            Message::FileTreeNode { version, summary } => Ok((version, summary)),
+            message => Err(anyhow::anyhow!(
+                "unexpected response to file tree node request: {:?}",
+                message
+            )),
+        }
+    }
+
+    /// Asks the other side to let us into a group. Returns the membership log of the group if
+    /// they did.
+    pub async fn redeem_invitation(
+        &mut self,
+        invitation: Invitation,
+    ) -> Result<Vec<SignedMembershipOp>> {
+        let conversation = self.send(Message::InvitationRedeem(invitation)).await?;
+        recv_membership(conversation).await
+    }
+
+    /// Asks the other side for the membership log of a group
+    pub async fn request_membership(&mut self, groupuuid: Uuid) -> Result<Vec<SignedMembershipOp>> {
+        let conversation = self
+            .send(Message::MembershipLogRequest { groupuuid })
+            .await?;
+        recv_membership(conversation).await
+    }
+}
+
+async fn recv_membership(
+    mut conversation: Box<dyn Conversation + '_>,
+) -> Result<Vec<SignedMembershipOp>> {
+    match conversation.recv_message(MEMBERSHIP_LIMIT).await? {
+        Message::MembershipLog(ops) => Ok(ops),
+        Message::Error(ErrorMessage::InvalidInvitation) => {
+            Err(anyhow::anyhow!("invitation was rejected"))
+        }
+        message => Err(anyhow::anyhow!(
+            "unexpected response to membership log request: {:?}",
+            message
+        )),
+    }
+}
+
+async fn recv_filetree(mut conversation: Box<dyn Conversation + '_>) -> Result<FileTreeUpdate> {
+    match conversation.recv_message(ANSWER_LIMIT).await? {
+        Message::FileTree => {
+            let serialized = conversation
+                .recv_bytes(FILETREE_LIMIT)
+                .await
+                .context("failed to receive file tree")?;
+            bincode::deserialize(&serialized).context("failed to deserialize file tree")
+        }
+        message => Err(anyhow::anyhow!(
+            "unexpected response to file tree request: {:?}",
+            message
+        )),
+    }
+}
+
+#[async_trait]
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> BlockSource for Client<T> {
+    async fn get_block(
+        &mut self,
+        groupuuid: Uuid,
+        file: &File,
+        index: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut conversation = self
+            .send(Message::FileBlockRequest {
+                groupuuid,
+                filehash: file.hash.clone(),
+                index,
+            })
+            .await?;
+
+        match conversation.recv_message(ANSWER_LIMIT).await? {
+            Message::FileBlock => {
+                let block = conversation
+                    .recv_bytes(file.block_size as usize)
+                    .await
+                    .context("failed to receive block")?;
+                Ok(Some(block))
+            }
+            Message::Error(ErrorMessage::FileNotFound) => Ok(None),
+            message => Err(anyhow::anyhow!(
+                "unexpected response to block request: {:?}",
+                message
+            )),
+        }
     }
 }
@@ -1,20 +1,41 @@
 use crate::dspfs::builder::DspfsBuilder;
 use crate::dspfs::client::Client;
 use crate::dspfs::server::{Server, ServerHandle};
-use crate::fs::group::StoredGroup;
+use crate::fs::group::invitation::Invitation;
+use crate::fs::group::watcher::GroupWatcher;
+use crate::fs::group::{Group, StoredGroup};
 use crate::global_store::{SharedStore, Store};
 use crate::user::{PrivateUser, PublicUser};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::*;
 use std::collections::HashMap;
 use std::fs;
 use std::mem;
 use std::path::Path;
+use tokio::select;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::time::{interval, timeout, Duration, Instant};
+use uuid::Uuid;
 
 pub mod builder;
 pub mod client;
 pub mod server;
 
+/// How often we check whether other members have files we want.
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often we go through all files in all groups, in case the watcher missed something.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long we try to reach someone to redeem an invitation with
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many users who came online can wait for the syncer, before we forget about them
+const ONLINE_QUEUE: usize = 64;
+
+/// How many files are indexed between progress messages
+const PROGRESS_INTERVAL: usize = 1000;
+
 pub struct Dspfs<S: Store + 'static> {
     pub(self) store: SharedStore<S>,
     pub(self) me: PrivateUser,
@@ -22,6 +43,8 @@ pub struct Dspfs<S: Store + 'static> {
 
     clients: HashMap<PublicUser, Client>,
     serverhandle: Option<ServerHandle>,
+    syncer: Option<Sender<()>>,
+    watchers: HashMap<Uuid, GroupWatcher>,
 }
 
 impl<S: Store> Dspfs<S> {
@@ -32,7 +55,28 @@ impl<S: Store> Dspfs<S> {
     pub async fn start(&mut self) {
         if self.server.is_some() {
             if let Some(server) = mem::replace(&mut self.server, None) {
-                server.start().await;
+                let (syncer, online) = start_syncer(self.store.clone());
+                let serverhandle = server.start(Some(online)).await;
+
+                // Everyone we connect to learns where our server is from the handshake
+                self.me.set_last_addr(serverhandle.addr);
+                let advertised = self
+                    .store
+                    .write()
+                    .await
+                    .set_self_user(self.me.public_user().clone());
+                if let Err(e) = advertised {
+                    error!("Couldn't save the address of our server: {:?}", e);
+                }
+
+                self.serverhandle = Some(serverhandle);
+                self.syncer = Some(syncer);
+
+                let groups = self.store.read().await.get_groups();
+                match groups {
+                    Ok(groups) => groups.into_iter().for_each(|g| self.watch_group(g)),
+                    Err(e) => error!("Couldn't get groups to watch: {:?}", e),
+                }
             }
         } else {
             warn!("Dspfs was already started, ignoring start request");
@@ -47,6 +91,10 @@ impl<S: Store> Dspfs<S> {
 
                 serverhandle.stop().await?;
             }
+            if let Some(mut syncer) = self.syncer.take() {
+                syncer.send(()).await.context("failed to stop syncer")?;
+            }
+            self.watchers.clear();
         } else {
             warn!("Dspfs was already stopped, ignoring stop request");
         }
@@ -58,25 +106,236 @@ impl<S: Store> Dspfs<S> {
         // 1. create or find folder (mkdir -p)
         // a)
         // 2. create .dspfs folder inside of that folder
-        // 2.1: build file tree
-        // 2.2: schedule index
+        // 2.1: build file tree and schedule index
 
         // b)
         // 2. Import the existing .dspfs folder
 
-        let group = StoredGroup::new(&path);
+        let mut group = StoredGroup::new(&path);
 
         if group.dspfs_folder().exists() {
-            // Existing folder
-            todo!()
+            // Existing folder, moved here or left behind by an earlier installation
+            group = StoredGroup::import(&path)?;
+
+            let mut store = self.store.write().await;
+            if store.get_group(group.uuid)?.is_some() {
+                return Err(anyhow::anyhow!("group {} already exists", group.uuid));
+            }
+            store.add_group(group.clone())?;
         } else {
             // New folder
+            group.users.push(self.me.public_user().clone());
             fs::create_dir_all(group.dspfs_folder())?;
-            self.store.write().await.add_group(group)?;
+            self.store.write().await.add_group(group.clone())?;
+
+            // We're the founder, so we start the membership log
+            group
+                .clone()
+                .reload(self.store.clone())?
+                .start_membership_log()
+                .await?;
+        }
+
+        self.attach_group(group)
+    }
+
+    /// Joins a group someone invited us to (see [Invitation]), and attaches it to the folder at
+    /// `path`. The invitation is redeemed with the inviter, at the first of its hints where we can
+    /// reach them.
+    pub async fn join_group(&mut self, invitation: &str, path: impl AsRef<Path>) -> Result<()> {
+        let invitation: Invitation = invitation.parse()?;
+        invitation.verify()?;
+
+        let mut group = StoredGroup::new(&path);
+        if group.dspfs_folder().exists() {
+            return Err(anyhow::anyhow!("folder already belongs to a group"));
+        }
+        if self
+            .store
+            .read()
+            .await
+            .get_group(invitation.groupuuid())?
+            .is_some()
+        {
+            return Err(anyhow::anyhow!("already a member of this group"));
+        }
+
+        let mut joined = None;
+        for &addr in invitation.hints() {
+            let redeemed = async {
+                let mut client = Client::connect_to(addr, &self.me, invitation.inviter()).await?;
+                client.redeem_invitation(invitation.clone()).await
+            };
+
+            match timeout(CONNECT_TIMEOUT, redeemed).await {
+                Ok(Ok(ops)) => {
+                    joined = Some((addr, ops));
+                    break;
+                }
+                Ok(Err(e)) => warn!("Couldn't redeem invitation at {}: {:?}", addr, e),
+                Err(_) => warn!("Redeeming invitation at {} timed out", addr),
+            }
+        }
+        let (addr, ops) = joined.context("couldn't reach anyone to redeem the invitation with")?;
+
+        group.uuid = invitation.groupuuid();
+        fs::create_dir_all(group.dspfs_folder())?;
+        self.store.write().await.add_group(group.clone())?;
+
+        let mut group = group.reload(self.store.clone())?;
+        group.merge_membership(ops).await?;
+
+        // That's where we found them
+        for member in &mut group.users {
+            if member.get_public_key() == invitation.inviter() {
+                member.set_last_addr(addr);
+            }
+        }
+        group.save().await?;
+
+        self.attach_group(group.stored_group)
+    }
+
+    /// Indexes a group which was just added, and watches it if dspfs is running
+    fn attach_group(&mut self, group: StoredGroup) -> Result<()> {
+        tokio::spawn(index_group(group.clone().reload(self.store.clone())?));
+
+        if self.serverhandle.is_some() {
+            self.watch_group(group);
         }
 
         Ok(())
     }
+
+    /// Keeps the file tree of a group up to date with its folder while dspfs is running
+    fn watch_group(&mut self, group: StoredGroup) {
+        let uuid = group.uuid;
+        match group.reload(self.store.clone()).and_then(|g| g.watch()) {
+            Ok(watcher) => {
+                self.watchers.insert(uuid, watcher);
+            }
+            Err(e) => error!("Couldn't watch group {}: {:?}", uuid, e),
+        }
+    }
+}
+
+/// Periodically syncs and rescans all groups in the background, until something is sent on the
+/// first returned channel. All groups are indexed right away. Users sent on the second channel
+/// just came online, and the downloads they can help with are resumed. All downloads happen in
+/// this one task, so no file is downloaded twice at the same time.
+fn start_syncer<S: Store + 'static>(store: SharedStore<S>) -> (Sender<()>, Sender<PublicUser>) {
+    let (tx, mut rx) = channel(1);
+    let (online_tx, mut online) = channel(ONLINE_QUEUE);
+
+    tokio::spawn(async move {
+        let mut rescan = interval(RESCAN_INTERVAL);
+        let mut ticker = interval(SYNC_INTERVAL);
+        // When we last resumed downloads for someone. Connecting to them to download makes them
+        // resume their downloads from us, so without this we would keep connecting to each other.
+        let mut resumed: HashMap<PublicUser, Instant> = HashMap::new();
+        loop {
+            select! {
+                _ = rx.recv() => return,
+                Some(user) = online.recv() => {
+                    if resumed.get(&user).is_some_and(|at| at.elapsed() < SYNC_INTERVAL) {
+                        continue;
+                    }
+                    resumed.retain(|_, at| at.elapsed() < SYNC_INTERVAL);
+                    resumed.insert(user.clone(), Instant::now());
+
+                    if let Err(e) = resume_downloads(store.clone(), user).await {
+                        log::error!("resuming downloads failed; error = {:?}", e);
+                    }
+                }
+                _ = rescan.tick() => {
+                    if let Err(e) = index_groups(store.clone()).await {
+                        log::error!("indexing groups failed; error = {:?}", e);
+                    }
+                }
+                _ = ticker.tick() => {
+                    if let Err(e) = sync_groups(store.clone()).await {
+                        log::error!("syncing groups failed; error = {:?}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    (tx, online_tx)
+}
+
+/// Attempts the pending downloads in all groups which `user` can help us with. Used when
+/// someone comes online.
+async fn resume_downloads<S: Store + 'static>(
+    store: SharedStore<S>,
+    user: PublicUser,
+) -> Result<()> {
+    let groups = store.read().await.get_groups()?;
+
+    for group in groups.into_iter().filter(|g| g.users.contains(&user)) {
+        group.reload(store.clone())?.resume_downloads(&user).await?;
+    }
+
+    Ok(())
+}
+
+/// Indexes every group, see [index_group]
+async fn index_groups<S: Store + 'static>(store: SharedStore<S>) -> Result<()> {
+    let groups = store.read().await.get_groups()?;
+
+    for group in groups {
+        index_group(group.reload(store.clone())?).await;
+    }
+
+    Ok(())
+}
+
+/// Goes through all files of a group, to find out what changed while we weren't looking.
+async fn index_group<S: Store + 'static>(mut group: Group<S>) {
+    let uuid = group.uuid;
+    info!("Indexing group {}", uuid);
+
+    let result = group
+        .index("", |progress| {
+            if progress.indexed > 0 && progress.indexed % PROGRESS_INTERVAL == 0 {
+                info!(
+                    "Indexed {}/{} files of group {}",
+                    progress.indexed, progress.found, uuid
+                );
+            }
+        })
+        .await;
+
+    match result {
+        Ok(progress) => info!(
+            "Indexed group {}: {} files, {} new or changed, {} removed",
+            uuid, progress.found, progress.changed, progress.removed
+        ),
+        Err(e) => error!("indexing group {} failed; error = {:?}", uuid, e),
+    }
+}
+
+/// Asks the members of every group which files they have, and syncs the group with that.
+/// See [Group::sync](crate::fs::group::Group::sync)
+async fn sync_groups<S: Store + 'static>(store: SharedStore<S>) -> Result<()> {
+    let groups = store.read().await.get_groups()?;
+
+    for group in groups {
+        let uuid = group.uuid;
+        let mut group = group.reload(store.clone())?;
+        if let Err(e) = group.fetch_filetrees().await {
+            log::error!(
+                "fetching file trees of group {} failed; error = {:?}",
+                uuid,
+                e
+            );
+        }
+        if let Err(e) = group.sync().await {
+            log::error!("syncing group {} failed; error = {:?}", uuid, e);
+        }
+    }
+
+    Ok(())
 }
 
 //
@@ -1,7 +1,9 @@
+use crate::fs::group::changelog::FileTreeUpdate;
+use crate::fs::group::Group;
 use crate::global_store::{SharedStore, Store};
 use crate::message::{ErrorMessage, Message};
-use crate::stream::EncryptedStream;
-use crate::user::PrivateUser;
+use crate::stream::{Conversation, EncryptedStream, Multiplexer, MULTIPLEX};
+use crate::user::{PrivateUser, PublicUser};
 use anyhow::{Context, Result};
 use std::net::SocketAddr;
 use std::ops::Deref;
@@ -9,6 +11,7 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, ToSocketAddrs};
 use tokio::select;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use uuid::Uuid;
 
 pub struct Server<S: Store + 'static> {
     listener: TcpListener,
@@ -50,7 +53,8 @@ impl<S: Store + 'static> Server<S> {
 
     // Starts listening for requests
     // contains a loop checking for errors
-    pub async fn start(mut self) -> ServerHandle {
+    // Everyone who connects is sent on `online`, so the downloads they can help with are resumed
+    pub async fn start(mut self, online: Option<Sender<PublicUser>>) -> ServerHandle {
         let (tx, mut rx) = channel(2);
 
         let addr = self.addr;
@@ -58,7 +62,7 @@ impl<S: Store + 'static> Server<S> {
         log::info!("Starting server");
         // Outer loop for catching errors
         tokio::spawn(async move {
-            while let Err(e) = self.internal_start(&mut rx).await {
+            while let Err(e) = self.internal_start(&mut rx, &online).await {
                 log::error!("an error occurred; error = {:?}", e);
             }
         });
@@ -70,7 +74,11 @@ impl<S: Store + 'static> Server<S> {
     }
 
     // Inner loop for receiving messages and calling on [process]
-    async fn internal_start(&mut self, stopper: &mut Receiver<()>) -> Result<()> {
+    async fn internal_start(
+        &mut self,
+        stopper: &mut Receiver<()>,
+        online: &Option<Sender<PublicUser>>,
+    ) -> Result<()> {
         log::info!("Now accepting requests");
 
         loop {
@@ -83,10 +91,11 @@ impl<S: Store + 'static> Server<S> {
                     // Normal message
                     let (stream, addr) = accepted.context("failed to accept connection")?;
                     let local_store = self.store.clone();
+                    let online = online.clone();
 
                     // process the message
                     tokio::spawn(async move {
-                        if let Err(e) = handle_connection(local_store, stream, addr).await {
+                        if let Err(e) = handle_connection(local_store, stream, addr, online).await {
                             log::error!("an error occurred; error = {:?}", e);
                         }
                     });
@@ -96,11 +105,72 @@ impl<S: Store + 'static> Server<S> {
     }
 }
 
+/// What a peer has to be before we handle a message from them
+enum Access {
+    /// Anyone, as the message carries its own proof (like an invitation)
+    Anyone,
+    /// A member of at least one of our groups
+    Known,
+    /// A member of the group with this uuid
+    Member(Uuid),
+}
+
+impl Access {
+    fn of(message: &Message) -> Self {
+        match message {
+            Message::InvitationRedeem(_) => Access::Anyone,
+            Message::FileBlockRequest { groupuuid, .. }
+            | Message::FileTreeRequest { groupuuid }
+            | Message::FileTreeChangesRequest { groupuuid, .. }
+            | Message::FileTreeNodeRequest { groupuuid, .. }
+            | Message::MembershipLogRequest { groupuuid } => Access::Member(*groupuuid),
+            _ => Access::Known,
+        }
+    }
+}
+
+/// Whether a peer may send us a message
+enum Authorization<S: Store> {
+    Granted,
+    /// Granted, and this is the group the message is about
+    Group(Group<S>),
+    Denied,
+}
+
+/// Checks whether `peer` is allowed to send us `message`. Groups which don't exist and groups the
+/// peer isn't a member of look the same to them, so nobody can find out which groups we have.
+async fn authorize<S: Store + 'static>(
+    store: &SharedStore<S>,
+    peer: &PublicUser,
+    message: &Message,
+) -> Result<Authorization<S>> {
+    let authorization = match Access::of(message) {
+        Access::Anyone => Authorization::Granted,
+        Access::Known => {
+            let groups = store.read().await.get_groups()?;
+            if groups.iter().any(|g| g.users.contains(peer)) {
+                Authorization::Granted
+            } else {
+                Authorization::Denied
+            }
+        }
+        Access::Member(groupuuid) => match store.read().await.get_group(groupuuid)? {
+            Some(group) if group.users.contains(peer) => {
+                Authorization::Group(group.reload(store.clone())?)
+            }
+            _ => Authorization::Denied,
+        },
+    };
+
+    Ok(authorization)
+}
+
 // Actually process the incoming requests
-async fn handle_connection<S: Store>(
+async fn handle_connection<S: Store + 'static>(
     store: SharedStore<S>,
-    stream: impl AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync,
-    _addr: SocketAddr,
+    stream: impl AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync + 'static,
+    addr: SocketAddr,
+    online: Option<Sender<PublicUser>>,
 ) -> Result<()> {
     let guard = store.read().await;
     // FIXME
@@ -109,52 +179,190 @@ async fn handle_connection<S: Store>(
     let mut es = EncryptedStream::receiver(stream, user)
         .await
         .context("Couldn't establish secure connection")?;
+    drop(guard);
+
+    // They tell us the port their server listens on, the ip address is the one they connect from
+    let mut peer = es.other_user.clone();
+    if let Some(advertised) = peer.get_last_addr() {
+        let addr = SocketAddr::new(addr.ip(), advertised.port());
+        peer.set_last_addr(addr);
+        remember_addr(&store, &peer, addr).await?;
+    }
 
+    // They're online, so they might have files we're waiting for. When the syncer is busy it
+    // gets to them on its next sync anyway.
+    if let Some(mut online) = online {
+        let _ = online.try_send(peer.clone());
+    }
+
+    if !es.supports(MULTIPLEX) {
+        return handle_conversation(&store, &peer, &mut es).await;
+    }
+
+    // Every channel is a conversation of its own, so a slow one doesn't hold up the others
+    let multiplexer = Multiplexer::new(es);
+    while let Some(channel) = multiplexer.accept().await {
+        let store = store.clone();
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_conversation(&store, &peer, channel).await {
+                log::warn!("Conversation with {:?} failed: {:?}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Remembers where to find `peer` in all groups they are a member of
+async fn remember_addr<S: Store + 'static>(
+    store: &SharedStore<S>,
+    peer: &PublicUser,
+    addr: SocketAddr,
+) -> Result<()> {
+    let mut store = store.write().await;
+
+    for mut group in store.get_groups()? {
+        let mut changed = false;
+        for member in group.users.iter_mut().filter(|u| *u == peer) {
+            if member.get_last_addr() != Some(addr) {
+                member.set_last_addr(addr);
+                changed = true;
+            }
+        }
+
+        if changed {
+            store.update_group(&group)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers the requests `peer` sends us, until they are done
+async fn handle_conversation<S: Store + 'static>(
+    store: &SharedStore<S>,
+    peer: &PublicUser,
+    mut conversation: impl Conversation,
+) -> Result<()> {
     // Check type of message
     // FIXME: Change limit
-    while let Ok(message) = es.recv_message(4096).await {
+    while let Ok(message) = conversation.recv_message(4096).await {
         log::info!("{:?}", message);
-        match message {
-            Message::Init { .. } => {
-                // drop connection
-                return Err(anyhow::anyhow!("Connection reinitialized by client"));
+        match authorize(store, peer, &message).await? {
+            Authorization::Granted => {
+                handle_message(store, peer, &mut conversation, message).await?
             }
-            Message::String(s) => {
-                log::info!("{}", s);
+            Authorization::Group(group) => {
+                handle_group_message(group, &mut conversation, message).await?
             }
-            Message::FileBlockRequest {
-                groupuuid,
-                filehash,
-                index,
-            } => {
-                // File Request:
-                // get group
-                let group =
-                    store.read().await.get_group(groupuuid)?.ok_or_else(|| {
-                        anyhow::anyhow!("Group with uuid {} not found", groupuuid)
-                    })?;
-
-                // verify user is actually in that group
-                if !group.users.contains(&es.other_user) {
-                    return Err(anyhow::anyhow!("Client not in group"));
-                }
+            Authorization::Denied => {
+                log::warn!("{:?} is not allowed to send {:?}", peer, message);
+                conversation
+                    .send_message(Message::Error(ErrorMessage::NotAuthorized))
+                    .await?;
+            }
+        }
+    }
 
-                let group = group.reload(store.clone())?;
+    Ok(())
+}
 
-                // send file
-                let block = if let Some(s) = group.get_block_contents(filehash, index).await? {
-                    s
-                } else {
-                    es.send_message(Message::Error(ErrorMessage::FileNotFound))
+/// Handles a message which isn't about a specific group
+async fn handle_message<S: Store + 'static>(
+    store: &SharedStore<S>,
+    peer: &PublicUser,
+    es: &mut impl Conversation,
+    message: Message,
+) -> Result<()> {
+    match message {
+        Message::Init { .. } | Message::HandshakeConfirm(_) | Message::Rekey => {
+            // drop connection
+            return Err(anyhow::anyhow!("Connection reinitialized by client"));
+        }
+        Message::String(s) => {
+            log::info!("{}", s);
+        }
+        Message::InvitationRedeem(invitation) => {
+            let group = store.read().await.get_group(invitation.groupuuid())?;
+
+            let redeemed = match group {
+                Some(group) => {
+                    let mut group = group.reload(store.clone())?;
+                    group
+                        .redeem_invitation(&invitation, peer.clone())
+                        .await
+                        .map(|_| group)
+                }
+                None => Err(anyhow::anyhow!(
+                    "Group with uuid {} not found",
+                    invitation.groupuuid()
+                )),
+            };
+
+            match redeemed {
+                Ok(group) => {
+                    let ops = group.membership_log().await?.ops().cloned().collect();
+                    es.send_message(Message::MembershipLog(ops)).await?
+                }
+                Err(e) => {
+                    log::warn!("Rejected invitation of {:?}: {:?}", peer, e);
+                    es.send_message(Message::Error(ErrorMessage::InvalidInvitation))
                         .await?;
-                    es.close();
-                    return Ok(());
-                };
+                }
+            }
+        }
+        message => log::error!("Received invalid message: {:?}", message),
+    }
+
+    Ok(())
+}
+
+/// Sends a file tree (or changes) as raw bytes after announcing it
+async fn send_filetree(es: &mut impl Conversation, update: &FileTreeUpdate) -> Result<()> {
+    let serialized = bincode::serialize(update).context("failed to serialize file tree")?;
 
-                es.send_message(Message::FileBlock(block)).await?;
+    es.send_message(Message::FileTree).await?;
+    es.send_bytes(&mut serialized.as_slice()).await
+}
+
+/// Handles a message about a group the other side is a member of
+async fn handle_group_message<S: Store + 'static>(
+    group: Group<S>,
+    es: &mut impl Conversation,
+    message: Message,
+) -> Result<()> {
+    match message {
+        Message::FileBlockRequest {
+            filehash, index, ..
+        } => match group.open_block(filehash, index).await? {
+            Some(mut block) => {
+                es.send_message(Message::FileBlock).await?;
+                es.send_bytes(&mut block).await?;
             }
-            message => log::error!("Received invalid message: {:?}", message),
+            None => {
+                es.send_message(Message::Error(ErrorMessage::FileNotFound))
+                    .await?
+            }
+        },
+        Message::FileTreeRequest { .. } => {
+            let tree = group.get_own_filetree().await?;
+            send_filetree(es, &tree).await?;
+        }
+        Message::FileTreeChangesRequest { since, .. } => {
+            let changes = group.get_own_changes(since).await?;
+            send_filetree(es, &changes).await?;
+        }
+        Message::FileTreeNodeRequest { path, known, .. } => {
+            let (version, summary) = group.summarize_own(path, &known).await?;
+            es.send_message(Message::FileTreeNode { version, summary })
+                .await?;
         }
+        Message::MembershipLogRequest { .. } => {
+            let ops = group.membership_log().await?.ops().cloned().collect();
+            es.send_message(Message::MembershipLog(ops)).await?;
+        }
+        message => log::error!("Received invalid message: {:?}", message),
     }
 
     Ok(())
@@ -165,17 +373,24 @@ pub mod tests {
     use crate::dspfs::client::Client;
     use crate::dspfs::server::{handle_connection, Server};
     use crate::fs::file::File;
-    use crate::fs::group::StoredGroup;
+    use crate::fs::filetree::FileTree;
+    use crate::fs::group::changelog::FileTreeUpdate;
+    use crate::fs::group::invitation::Invitation;
+    use crate::fs::group::membership::MembershipLog;
+    use crate::fs::group::{Group, StoredGroup};
     use crate::global_store::inmemory::InMemoryStore;
-    use crate::global_store::Store;
+    use crate::global_store::{SharedStore, Store};
     use crate::init;
-    use crate::message::Message;
-    use crate::stream::EncryptedStream;
-    use crate::user::PrivateUser;
+    use crate::message::{ErrorMessage, Message};
+    use crate::stream::{EncryptedStream, Multiplexer};
+    use crate::user::{PrivateUser, PublicUser};
     use std::io::Write;
     use std::ops::Deref;
-    use tempfile::tempdir;
+    use tempfile::{tempdir, TempDir};
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc::channel;
     use tokio::time::{delay_for, Duration};
+    use uuid::Uuid;
 
     #[tokio::test]
     pub async fn test_simple_stream() {
@@ -192,7 +407,7 @@ pub mod tests {
         let server = Server::new("0.0.0.0:8123", store1)
             .await
             .unwrap()
-            .start()
+            .start(None)
             .await;
 
         delay_for(Duration::from_secs_f64(0.5)).await;
@@ -253,27 +468,464 @@ pub mod tests {
         let es = EncryptedStream::initiator(tx, &us);
 
         tokio::spawn(async move {
-            handle_connection(store1.clone(), rx, "127.0.0.1:8000".parse().unwrap())
+            handle_connection(store1.clone(), rx, "127.0.0.1:8000".parse().unwrap(), None)
                 .await
                 .unwrap();
         });
 
-        let mut es = es.await.unwrap();
+        let multiplexer = Multiplexer::new(es.await.unwrap());
+        let mut channel = multiplexer.open().await.unwrap();
 
-        es.send_message(Message::FileBlockRequest {
-            groupuuid: guuid,
-            filehash: fhash,
-            index: 0,
-        })
-        .await
-        .unwrap();
+        channel
+            .send_message(Message::FileBlockRequest {
+                groupuuid: guuid,
+                filehash: fhash,
+                index: 0,
+            })
+            .await
+            .unwrap();
+
+        let msg = channel.recv_message(1024).await.unwrap();
+        assert!(matches!(msg, Message::FileBlock));
+
+        let bytes = channel.recv_bytes(1024).await.unwrap();
+        assert_eq!(bytes, b"Hello World!\n");
+    }
+
+    /// The same group on two computers, each with a member of it on them
+    struct TwoMembers {
+        store1: SharedStore<InMemoryStore>,
+        u1: PublicUser,
+        u2: PrivateUser,
+        dir1: TempDir,
+        dir2: TempDir,
+        group1: Group<InMemoryStore>,
+        group2: Group<InMemoryStore>,
+    }
+
+    async fn two_members() -> TwoMembers {
+        let store1 = InMemoryStore::test_store("test1").unwrap();
+        let store2 = InMemoryStore::test_store("test2").unwrap();
+
+        let u1 = store1.read().await.get_self_user().unwrap().unwrap();
+        let u2 = PrivateUser::load_from_store(store2.read().await.deref().deref()).unwrap();
+
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+
+        let mut group1 = StoredGroup::new(dir1.path());
+        group1.users = vec![u1.clone(), u2.public_user().clone()];
+        let mut group2 = group1.clone();
+        group2.location = dir2.path().to_path_buf();
+
+        TwoMembers {
+            group1: add_group(&store1, group1).await,
+            group2: add_group(&store2, group2).await,
+            store1,
+            u1,
+            u2,
+            dir1,
+            dir2,
+        }
+    }
+
+    /// Adds a group to the computer with `store`
+    async fn add_group(
+        store: &SharedStore<InMemoryStore>,
+        group: StoredGroup,
+    ) -> Group<InMemoryStore> {
+        std::fs::create_dir_all(group.dspfs_folder()).unwrap();
+        store.write().await.add_group(group.clone()).unwrap();
+        group.reload(store.clone()).unwrap()
+    }
 
-        let msg = es.recv_message(1024).await.unwrap();
+    /// Connects `user` to the server of the computer with `store`
+    async fn connect(store: SharedStore<InMemoryStore>, user: &PrivateUser) -> Client<UnixStream> {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        tokio::spawn(async move {
+            let _ = handle_connection(store, rx, "127.0.0.1:8000".parse().unwrap(), None).await;
+        });
+        Client::from_stream(EncryptedStream::initiator(tx, user).await.unwrap())
+    }
+
+    #[tokio::test]
+    pub async fn test_download() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            dir1,
+            dir2,
+            mut group1,
+            mut group2,
+        } = two_members().await;
+
+        // Large enough to consist of multiple blocks
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 13) as u8).collect();
+        std::fs::write(dir1.path().join("test"), &data).unwrap();
+
+        let mut file = File::new(dir1.path().join("test")).await.unwrap();
+        file.path = "test".into();
+        group1.add_file(&u1, file.clone()).await.unwrap();
+
+        let client = connect(store1, &u2).await;
+        let report = group2
+            .download_from(&file, vec![(u1, Box::new(client))])
+            .await
+            .unwrap();
+        assert!(report.complete);
+
+        assert!(std::fs::read(dir2.path().join("test")).unwrap() == data);
+        assert!(group2.get_local_file(file.hash).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    pub async fn test_download_not_found() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            mut group2,
+            ..
+        } = two_members().await;
+        let client = connect(store1, &u2).await;
+
+        // The first user doesn't have this file
+        let file = File::new_empty("test".into());
+        let report = group2
+            .download_from(&file, vec![(u1.clone(), Box::new(client))])
+            .await
+            .unwrap();
+
+        assert!(!report.complete);
+        assert!(report.not_found.contains(&u1));
+    }
+
+    #[tokio::test]
+    pub async fn test_filetree_exchange() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            dir1,
+            mut group1,
+            mut group2,
+            ..
+        } = two_members().await;
+
+        let file = File::new_empty("yeet/test".into());
+        group1.add_file(&u1, file.clone()).await.unwrap();
+
+        let mut client = connect(store1, &u2).await;
+        let tree = client.request_filetree(group2.uuid).await.unwrap();
+        group2.apply_update(&u1, tree).await.unwrap();
+
+        let tree = group2.get_filetree(&u1).await.unwrap().unwrap();
+        assert!(tree.find("yeet/test").is_some());
+        assert!(tree.iter().all(|(_, f)| f.is_owned_by(&u1)));
+
+        // After that, only what changed is sent
+        std::fs::write(dir1.path().join("yote"), b"yote").unwrap();
+        let mut other = File::new(dir1.path().join("yote")).await.unwrap();
+        other.path = "yote".into();
+        group1.add_file(&u1, other).await.unwrap();
+
+        let since = group2.get_version(&u1).await.unwrap();
+        let update = client.request_changes(group2.uuid, since).await.unwrap();
+        match &update {
+            FileTreeUpdate::Changes { version, changes } => {
+                assert_eq!(*version, 2);
+                assert_eq!(changes.len(), 1);
+            }
+            _ => panic!("expected only the changes"),
+        }
+        group2.apply_update(&u1, update).await.unwrap();
+
+        let tree = group2.get_filetree(&u1).await.unwrap().unwrap();
+        assert!(tree.find("yote").is_some());
+
+        // We don't let anyone overwrite what we have
+        assert!(group2
+            .set_filetree(u2.public_user(), FileTree::new(), 0)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_learn_address() {
+        let TwoMembers {
+            store1,
+            mut u2,
+            group1,
+            ..
+        } = two_members().await;
+
+        // They only tell us their port, the rest is where they connect from
+        u2.set_last_addr("0.0.0.0:4321".parse().unwrap());
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let server = store1.clone();
+        tokio::spawn(async move {
+            let _ = handle_connection(server, rx, "10.0.0.2:50000".parse().unwrap(), None).await;
+        });
+        let mut client = Client::from_stream(EncryptedStream::initiator(tx, &u2).await.unwrap());
+        client.request_membership(group1.uuid).await.unwrap();
+
+        let group = store1.read().await.get_group(group1.uuid).unwrap().unwrap();
+        let member = group.users.iter().find(|&u| u == u2.public_user()).unwrap();
+        assert_eq!(
+            member.get_last_addr(),
+            Some("10.0.0.2:4321".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_resume_from_owner() {
+        let store = InMemoryStore::test_store("test1").unwrap();
+        let me = store.read().await.get_self_user().unwrap().unwrap();
+        let (inviter, _) = PrivateUser::new("inviter").unwrap();
+        let (mut owner, _) = PrivateUser::new("owner").unwrap();
+
+        // We joined through the inviter, so they are the only one we know where to find
+        let dir = tempdir().unwrap();
+        let mut group = StoredGroup::new(dir.path());
+        let mut found = inviter.public_user().clone();
+        found.set_last_addr("10.0.0.1:8123".parse().unwrap());
+        group.users = vec![me, found, owner.public_user().clone()];
+        let mut group = add_group(&store, group).await;
+
+        // Only the owner has the file we want
+        let mut file = File::new_empty("test".into());
+        file.add_user(owner.public_user().clone());
+        group.queue_download(file).await.unwrap();
+
+        // Once they connect, we know where to resume the download from
+        owner.set_last_addr("0.0.0.0:4321".parse().unwrap());
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let (online_tx, mut online) = channel(1);
+        let server = store.clone();
+        tokio::spawn(async move {
+            let addr = "10.0.0.3:50000".parse().unwrap();
+            let _ = handle_connection(server, rx, addr, Some(online_tx)).await;
+        });
+        let _client = Client::from_stream(EncryptedStream::initiator(tx, &owner).await.unwrap());
+
+        let peer = online.recv().await.unwrap();
+        assert_eq!(&peer, owner.public_user());
+
+        let group = store
+            .read()
+            .await
+            .get_group(group.uuid)
+            .unwrap()
+            .unwrap()
+            .reload(store.clone())
+            .unwrap();
+        let queued = group.list_downloads().await.unwrap();
+        assert!(queued[0].file.is_owned_by(&peer));
+        let owners = group.owners(&queued[0].file);
+        assert_eq!(owners, vec![peer.clone()]);
+        assert_eq!(
+            owners[0].get_last_addr(),
+            Some("10.0.0.3:4321".parse().unwrap())
+        );
+    }
 
-        if let Message::FileBlock(bytes) = msg {
-            assert_eq!(bytes, b"Hello World!\n")
-        } else {
-            panic!();
+    #[tokio::test]
+    pub async fn test_shared_connection() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            mut group1,
+            group2,
+            ..
+        } = two_members().await;
+
+        let file = File::new_empty("yeet/test".into());
+        group1.add_file(&u1, file.clone()).await.unwrap();
+
+        // An answer nobody reads yet doesn't hold up other requests on the same connection
+        let mut client = connect(store1, &u2).await;
+        let mut other = client.share().unwrap();
+        let mut stalled = other
+            .send(Message::FileTreeRequest {
+                groupuuid: group2.uuid,
+            })
+            .await
+            .unwrap();
+
+        let tree = client.request_filetree(group2.uuid).await.unwrap();
+        assert!(matches!(tree, FileTreeUpdate::Full { .. }));
+
+        let msg = stalled.recv_message(1024).await.unwrap();
+        assert!(matches!(msg, Message::FileTree));
+        assert!(!stalled.recv_bytes(1024 * 1024).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_filetree_reconcile() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            mut group1,
+            mut group2,
+            ..
+        } = two_members().await;
+
+        let file = File::new_empty("yeet/test".into());
+        group1.add_file(&u1, file.clone()).await.unwrap();
+
+        // Our copy is at the right version, but somehow has the wrong files
+        let mut stale = FileTree::new();
+        stale
+            .insert("yote/test", File::new_empty("yote/test".into()))
+            .unwrap();
+        group2.set_filetree(&u1, stale, 1).await.unwrap();
+
+        let mut client = connect(store1, &u2).await;
+        group2.fetch_filetree(&u1, &mut client).await.unwrap();
+
+        let ours = group2.get_filetree(&u1).await.unwrap().unwrap();
+        let theirs = group1.get_filetree(&u1).await.unwrap().unwrap();
+        assert_eq!(ours.hash(), theirs.hash());
+        assert!(ours.find("yeet/test").is_some());
+        assert!(ours.find("yote/test").is_none());
+        assert_eq!(group2.get_version(&u1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_invitation() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            dir1,
+            ..
+        } = two_members().await;
+        let (stranger, _) = PrivateUser::new("stranger").unwrap();
+
+        // A group the second user isn't in yet
+        let mut group1 = StoredGroup::new(dir1.path().join("new"));
+        group1.users = vec![u1.clone()];
+        let guuid = group1.uuid;
+        let mut group1 = add_group(&store1, group1).await;
+        group1.start_membership_log().await.unwrap();
+
+        let invitation = group1
+            .invite(Duration::from_secs(60), vec![])
+            .await
+            .unwrap();
+        let forged = Invitation::new(guuid, &stranger, Duration::from_secs(60), vec![]).unwrap();
+
+        // Only members can invite people
+        let mut client = connect(store1.clone(), &u2).await;
+        assert!(client.redeem_invitation(forged).await.is_err());
+        assert!(client.request_membership(guuid).await.is_err());
+
+        let mut client = connect(store1.clone(), &u2).await;
+        let decoded: Invitation = invitation.to_string().parse().unwrap();
+        let ops = client.redeem_invitation(decoded).await.unwrap();
+        let members = MembershipLog::new(ops).unwrap().members();
+        assert_eq!(members, vec![u1.clone(), u2.public_user().clone()]);
+
+        let group1 = store1.read().await.get_group(guuid).unwrap().unwrap();
+        assert!(group1.users.contains(u2.public_user()));
+
+        // Someone else can't get in with the same invitation
+        let mut other = connect(store1.clone(), &stranger).await;
+        assert!(other.redeem_invitation(invitation).await.is_err());
+        let group1 = store1.read().await.get_group(guuid).unwrap().unwrap();
+        assert!(!group1.users.contains(stranger.public_user()));
+
+        // Now we can ask for the membership log, and get the same members out of it
+        let ops = client.request_membership(guuid).await.unwrap();
+        assert_eq!(ops.len(), 2);
+        assert!(ops.iter().all(|op| op.verify().is_ok()));
+    }
+
+    #[tokio::test]
+    pub async fn test_remove_member() {
+        let TwoMembers {
+            store1,
+            u1,
+            u2,
+            mut group1,
+            ..
+        } = two_members().await;
+        let guuid = group1.uuid;
+
+        // Only the first user is in the membership log, until they add the second
+        group1.users = vec![u1.clone()];
+        group1.start_membership_log().await.unwrap();
+        group1.add_member(u2.public_user().clone()).await.unwrap();
+
+        // A file both of them have
+        let file = File::new_empty("yeet".into());
+        group1.add_file(&u1, file.clone()).await.unwrap();
+        group1
+            .add_file(u2.public_user(), file.clone())
+            .await
+            .unwrap();
+
+        group1.remove_member(u2.public_user()).await.unwrap();
+        assert_eq!(group1.users, vec![u1.clone()]);
+        assert!(group1
+            .get_filetree(u2.public_user())
+            .await
+            .unwrap()
+            .is_none());
+        let ours = group1.get_filetree(&u1).await.unwrap().unwrap();
+        let (_, ours) = ours.iter().next().unwrap();
+        assert!(!ours.is_owned_by(u2.public_user()));
+
+        // They don't get anything from us anymore
+        let mut client = connect(store1, &u2).await;
+        assert!(client.request_filetree(guuid).await.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_not_authorized() {
+        let store1 = InMemoryStore::test_store("test1").unwrap();
+        let u1 = store1.read().await.get_self_user().unwrap().unwrap();
+        let (stranger, _) = PrivateUser::new("stranger").unwrap();
+
+        let dir1 = tempdir().unwrap();
+        let mut group1 = StoredGroup::new(dir1.path());
+        group1.users = vec![u1.clone()];
+        std::fs::create_dir_all(group1.dspfs_folder()).unwrap();
+        let guuid = group1.uuid;
+        store1.write().await.add_group(group1).unwrap();
+
+        let (tx, rx) = tokio::net::UnixStream::pair().unwrap();
+        tokio::spawn(async move {
+            handle_connection(store1, rx, "127.0.0.1:8000".parse().unwrap(), None)
+                .await
+                .unwrap();
+        });
+        let es = EncryptedStream::initiator(tx, &stranger).await.unwrap();
+        let multiplexer = Multiplexer::new(es);
+        let mut es = multiplexer.open().await.unwrap();
+
+        // Groups we have and groups we don't have look the same to them
+        for groupuuid in [guuid, Uuid::new_v4()] {
+            es.send_message(Message::FileTreeRequest { groupuuid })
+                .await
+                .unwrap();
+            let response = es.recv_message(1024).await.unwrap();
+            assert!(matches!(
+                response,
+                Message::Error(ErrorMessage::NotAuthorized)
+            ));
         }
+
+        es.send_message(Message::String("yeet".into()))
+            .await
+            .unwrap();
+        let response = es.recv_message(1024).await.unwrap();
+        assert!(matches!(
+            response,
+            Message::Error(ErrorMessage::NotAuthorized)
+        ));
     }
 }
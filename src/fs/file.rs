@@ -3,6 +3,8 @@ use crate::user::PublicUser;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use tokio::fs::File as tFile;
 use tokio::io::AsyncReadExt;
@@ -31,8 +33,35 @@ pub struct File {
     /// Only when we ask this user for the file and it turns out they don't have it anymore,
     /// do we remove him from this set.
     users: HashSet<PublicUser>,
-    // TODO:
-    // modtime
+
+    /// What the filesystem told us about the file when we hashed it.
+    /// None for files we only know of through others.
+    pub(crate) metadata: Option<FileMetadata>,
+}
+
+/// When none of these change, the contents of a file didn't either, and it doesn't need to be
+/// hashed again.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+    pub inode: u64,
+}
+
+impl From<&Metadata> for FileMetadata {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            ctime: metadata.ctime(),
+            ctime_nsec: metadata.ctime_nsec(),
+            inode: metadata.ino(),
+        }
+    }
 }
 
 // All operations on files are immutable and return a new file.
@@ -51,6 +80,7 @@ impl File {
             block_size: block_size(0),
             blockhashes: vec![block_hash],
             users: HashSet::new(),
+            metadata: None,
         }
     }
 
@@ -66,7 +96,10 @@ impl File {
         // 2.1 determine block size
         let file_size = metadata.len();
         if file_size == 0 {
-            return Ok(Self::new_empty(path));
+            return Ok(Self {
+                metadata: Some((&metadata).into()),
+                ..Self::new_empty(path)
+            });
         }
 
         // 3-4
@@ -81,6 +114,7 @@ impl File {
             block_size,
             blockhashes: block_hashes,
             users: Default::default(),
+            metadata: Some((&metadata).into()),
         })
     }
 
@@ -129,11 +163,13 @@ impl File {
         let mut file = tFile::open(&self.path)
             .await
             .context("opening file failed")?;
+        let metadata = file.metadata().await.context("Couldn't access metadata")?;
         // 2. Call hash_file
         let (file_hash, block_hashes) = Self::hash_file(&mut file, self.block_size).await?;
         // 3. save new info
         self.hash = file_hash;
         self.blockhashes = block_hashes;
+        self.metadata = Some((&metadata).into());
 
         Ok(())
     }
@@ -146,6 +182,15 @@ impl File {
         self.users.len()
     }
 
+    /// Iterates over all users who are likely to have this file.
+    pub fn users(&self) -> impl Iterator<Item = &PublicUser> {
+        self.users.iter()
+    }
+
+    pub fn add_user(&mut self, user: PublicUser) -> bool {
+        self.users.insert(user)
+    }
+
     /// Returns true if two file structs refer to the same file.
     /// This is the case when the file hashes ar equal.
     pub fn equals(&self, other: &File) -> bool {
@@ -163,6 +208,23 @@ impl File {
     pub fn get_block_hash(&self, index: u64) -> Option<&Hash> {
         self.blockhashes.get(index as usize)
     }
+
+    /// Returns true if the file on disk most likely still is what we hashed, judging by its
+    /// metadata. Always false for files we haven't hashed ourselves.
+    pub fn is_unchanged(&self, metadata: &Metadata) -> bool {
+        self.metadata == Some(metadata.into())
+    }
+
+    /// Returns true if `block` is exactly the block at `index` in this file.
+    pub fn verify_block(&self, index: u64, block: &[u8]) -> bool {
+        self.get_block_hash(index)
+            .map(|hash| *hash == Hash::hash_block(self.hashing_algorithm.clone(), block))
+            .unwrap_or(false)
+    }
+
+    pub fn num_blocks(&self) -> u64 {
+        self.blockhashes.len() as u64
+    }
 }
 
 /// Based on syncthing's [BEP](https://docs.syncthing.net/specs/bep-v1.html#blocksize)
@@ -1,7 +1,9 @@
 use crate::fs::file::File;
+use crate::fs::hash::Hash;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::borrow::BorrowMut;
+use std::ffi::OsStr;
 use std::iter;
 use std::path::{Component, Components, Path, PathBuf};
 
@@ -34,12 +36,30 @@ pub fn normalize_path(path: &Path) -> PathBuf {
     ret
 }
 
+/// Returns true if `path` stays inside the folder it is relative to: it is not empty, not
+/// absolute and doesn't contain `..` (or `.`).
+pub fn is_safe_path(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Returns true if `name` is a single path component, so it can be the name of a file or folder
+fn is_valid_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(c)), None) if c == OsStr::new(name)
+    )
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum FileTree {
     // A directory
     Node {
         name: String,
         children: Vec<FileTree>,
+        /// Summarizes everything below this directory, see [FileTree::hash]
+        hash: Hash,
     },
     // A file
     Leaf {
@@ -48,11 +68,88 @@ pub enum FileTree {
     },
 }
 
+/// What someone sends back when asked about a directory in their file tree,
+/// see [FileTree::summarize].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NodeSummary {
+    /// The directory has the hash that was asked about
+    Unchanged,
+    /// There is no directory at that path
+    Missing,
+    Children(Vec<ChildSummary>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChildSummary {
+    Node { name: String, hash: Hash },
+    Leaf { name: String, file: File },
+}
+
 impl FileTree {
     pub fn new() -> Self {
+        Self::node(String::new(), Vec::new())
+    }
+
+    /// Creates a directory, and computes its hash
+    pub fn node(name: impl Into<String>, children: Vec<FileTree>) -> Self {
         FileTree::Node {
-            name: Default::default(),
-            children: Default::default(),
+            name: name.into(),
+            hash: Self::hash_children(&children),
+            children,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            FileTree::Node { name, .. } | FileTree::Leaf { name, .. } => name,
+        }
+    }
+
+    /// The hash of a file is the hash of its contents. The hash of a directory is computed
+    /// from the names and hashes of everything in it, so two trees with the same root hash
+    /// contain the same files at the same paths.
+    pub fn hash(&self) -> &Hash {
+        match self {
+            FileTree::Node { hash, .. } => hash,
+            FileTree::Leaf { file, .. } => &file.hash,
+        }
+    }
+
+    fn hash_children(children: &[FileTree]) -> Hash {
+        let mut sorted: Vec<&FileTree> = children.iter().collect();
+        sorted.sort_by(|a, b| a.name().cmp(b.name()));
+
+        let mut hasher = blake3::Hasher::new();
+        for child in sorted {
+            hasher.update(&[matches!(child, FileTree::Leaf { .. }) as u8]);
+            hasher.update(child.name().as_bytes());
+            hasher.update(&[0]);
+            hasher.update(child.hash().bytes());
+        }
+
+        Hash::new(hasher.finalize().as_bytes().to_vec())
+    }
+
+    /// Recomputes the hashes of all directories in the tree. Only needed after changing the
+    /// hashes of files through [FileTree::iter_mut], or for trees we got from someone else.
+    pub fn update_hashes(&mut self) {
+        if let FileTree::Node { children, hash, .. } = self {
+            for child in children.iter_mut() {
+                child.update_hashes();
+            }
+            *hash = Self::hash_children(children);
+        }
+    }
+
+    /// Recomputes the hashes of the directories on a path of child indices, from the bottom up
+    fn update_path_hashes(&mut self, indices: &[usize]) {
+        if let FileTree::Node { children, hash, .. } = self {
+            if let Some((&first, rest)) = indices.split_first() {
+                if let Some(child) = children.get_mut(first) {
+                    child.update_path_hashes(rest);
+                }
+            }
+            *hash = Self::hash_children(children);
         }
     }
 
@@ -65,6 +162,8 @@ impl FileTree {
         }
     }
 
+    /// Iterates over all files mutably. When changing the hash of a file,
+    /// call [FileTree::update_hashes] afterwards.
     pub fn iter_mut<'a>(
         &'a mut self,
     ) -> Box<dyn Iterator<Item = (&'a mut String, &'a mut File)> + 'a> {
@@ -83,7 +182,7 @@ impl FileTree {
         mut path: Vec<usize>,
     ) -> Result<Vec<usize>> {
         Ok(match &root {
-            FileTree::Node { name: _, children } => match components.next() {
+            FileTree::Node { children, .. } => match components.next() {
                 None => path,
                 Some(Component::Normal(part)) => {
                     let part = part.to_string_lossy().into_owned();
@@ -109,21 +208,22 @@ impl FileTree {
         })
     }
 
-    /// Will return the closest matching node of the filetree
-    fn traverse_tree(&self, path: impl AsRef<Path>) -> Result<(&FileTree, usize)> {
-        let path = Self::traverse_tree_helper(
-            &self,
+    /// Returns the child indices of the closest matching node of the filetree
+    fn traverse_indices(&self, path: impl AsRef<Path>) -> Result<Vec<usize>> {
+        Self::traverse_tree_helper(
+            self,
             normalize_path(path.as_ref()).components().borrow_mut(),
             Vec::new(),
-        )?;
-
-        let len = path.len();
+        )
+    }
 
+    /// Follows a path of child indices down the tree
+    fn node_at_mut(&mut self, indices: &[usize]) -> &mut FileTree {
         let mut curr = self;
-        for i in path {
+        for &i in indices {
             match curr {
-                FileTree::Node { name: _, children } => {
-                    curr = &children[i];
+                FileTree::Node { children, .. } => {
+                    curr = &mut children[i];
                 }
                 _ => {
                     unreachable!("This should exist, or otherwise the helper function doesn't work")
@@ -131,24 +231,20 @@ impl FileTree {
             }
         }
 
-        Ok((curr, len))
+        curr
     }
 
-    /// The same as [traverse_tree] but it using and returning a mutable [FileTree]
-    fn traverse_tree_mut(&mut self, path: impl AsRef<Path>) -> Result<(&mut FileTree, usize)> {
-        let path = Self::traverse_tree_helper(
-            &self,
-            normalize_path(path.as_ref()).components().borrow_mut(),
-            Vec::new(),
-        )?;
+    /// Will return the closest matching node of the filetree
+    fn traverse_tree(&self, path: impl AsRef<Path>) -> Result<(&FileTree, usize)> {
+        let path = self.traverse_indices(path)?;
 
         let len = path.len();
 
         let mut curr = self;
         for i in path {
             match curr {
-                FileTree::Node { name: _, children } => {
-                    curr = &mut children[i];
+                FileTree::Node { children, .. } => {
+                    curr = &children[i];
                 }
                 _ => {
                     unreachable!("This should exist, or otherwise the helper function doesn't work")
@@ -159,6 +255,14 @@ impl FileTree {
         Ok((curr, len))
     }
 
+    /// The same as [traverse_tree] but it using and returning a mutable [FileTree]
+    fn traverse_tree_mut(&mut self, path: impl AsRef<Path>) -> Result<(&mut FileTree, usize)> {
+        let path = self.traverse_indices(path)?;
+        let len = path.len();
+
+        Ok((self.node_at_mut(&path), len))
+    }
+
     pub fn find(&self, path: impl AsRef<Path>) -> Option<&FileTree> {
         let path = normalize_path(path.as_ref());
 
@@ -172,6 +276,8 @@ impl FileTree {
         }
     }
 
+    /// Finds a node in the tree. Changing the hashes of files in the node through this
+    /// reference requires calling [FileTree::update_hashes] afterwards.
     pub fn find_mut(&mut self, path: impl AsRef<Path>) -> Option<&mut FileTree> {
         let path = normalize_path(path.as_ref());
 
@@ -190,10 +296,15 @@ impl FileTree {
         let path = normalize_path(path.as_ref());
         let filename = path.file_name()?.to_string_lossy().into_owned();
 
-        let (node, len) = if let Some(path) = path.parent() {
-            self.traverse_tree_mut(path).ok()? // TODO: Error?
+        let indices = if let Some(path) = path.parent() {
+            self.traverse_indices(path).ok()? // TODO: Error?
+        } else {
+            Vec::new()
+        };
+        let len = if path.parent().is_some() {
+            indices.len()
         } else {
-            (self, 1)
+            1
         };
 
         // Did we traverse the entire path
@@ -201,8 +312,8 @@ impl FileTree {
             return None;
         };
 
-        match node {
-            FileTree::Node { name: _, children } => {
+        let removed = match self.node_at_mut(&indices) {
+            FileTree::Node { children, .. } => {
                 let mut found: Option<usize> = None;
                 for (index, child) in children.iter().enumerate() {
                     match child {
@@ -211,7 +322,7 @@ impl FileTree {
                                 found = Some(index);
                             }
                         }
-                        FileTree::Node { name, children: _ } => {
+                        FileTree::Node { name, .. } => {
                             if recursive && name == &filename {
                                 found = Some(index);
                             }
@@ -222,26 +333,31 @@ impl FileTree {
                 found.map(|index| children.remove(index))
             }
             _ => None,
+        };
+
+        if removed.is_some() {
+            self.update_path_hashes(&indices);
         }
+        removed
     }
 
     /// Inserts a file into the filetree, with the `path` being a sequence of folders
     /// from te root of the filetree.
     ///
     /// ```
-    /// let mut filetree = FileTree::Node {
-    ///     name: "".into(),
-    ///     children: vec![
-    ///         FileTree::Node {
-    ///             name: "test".into(),
-    ///             children: vec![
+    /// let mut filetree = FileTree::node(
+    ///     "",
+    ///     vec![
+    ///         FileTree::node(
+    ///             "test",
+    ///             vec![
     ///                 FileTree::Leaf(Default::default()),
     ///                 // 1. INSERT HERE
     ///             ],
-    ///         },
+    ///         ),
     ///         // 2. INSERT HERE
     ///     ],
-    /// };
+    /// );
     ///
     /// // In the `test` subfolder
     /// filetree.insert("test/file.txt", Default::default())
@@ -250,58 +366,227 @@ impl FileTree {
     ///
     /// ```
     ///
+    /// A file which is already in the tree at `path` is replaced.
+    ///
     /// The `path` argument must be relative (may not start with a `/`),
     /// may not contain `..` (go up directories) or `.` (current dir), and may not contain
     /// windows drive letters (how the fuck did you manage that, dspfs doesn't even run on windows)
     pub fn insert(&mut self, path: impl AsRef<Path>, file: File) -> Result<()> {
         let path = normalize_path(path.as_ref());
 
-        let (mut node, len) = if let Some(path) = path.parent() {
-            self.traverse_tree_mut(path)
-                .context("finding closest filetree node went wrong")?
-        } else {
-            (self, 1)
-        };
-
         let filename = path.file_name()
             .context("Somehow no filename was found in that path (shouldn't happen) (if it does happen you know either Victor or Jonathan did an oopsie woopsie) (aka you're fucked)")?
             .to_string_lossy()
             .into_owned();
 
-        if let Some(path) = path.parent() {
-            for c in path.components().skip(len) {
-                let folder = FileTree::Node {
-                    children: Vec::new(),
-                    name: c.as_os_str().to_string_lossy().into_owned(),
-                };
-                if let FileTree::Node { name: _, children } = node {
-                    children.push(folder);
-                    node = children.last_mut().unwrap();
-                }
-            }
-        }
+        let mut indices = self.create_folders(path.parent().unwrap_or_else(|| Path::new("")))?;
 
-        if let FileTree::Node { name: _, children } = node {
+        if let FileTree::Node { children, .. } = self.node_at_mut(&indices) {
+            let existing = children.iter().position(|c| c.name() == filename);
             let leaf = FileTree::Leaf {
                 name: filename,
                 file,
             };
 
-            children.push(leaf);
+            match existing.map(|i| (i, &children[i])) {
+                // A new version of a file replaces the old one
+                Some((i, FileTree::Leaf { .. })) => {
+                    children[i] = leaf;
+                    indices.push(i);
+                }
+                Some((_, FileTree::Node { .. })) => {
+                    return Err(anyhow::anyhow!(
+                        "path pointed to a folder, can't replace it with a file."
+                    ));
+                }
+                None => {
+                    children.push(leaf);
+                    indices.push(children.len() - 1);
+                }
+            }
         } else {
             return Err(anyhow::anyhow!(
                 "path pointed to file, can't insert a file in a non directory."
             ));
         }
 
+        self.update_path_hashes(&indices);
+
+        Ok(())
+    }
+
+    /// Creates a (possibly empty) folder in the filetree, and all folders above it.
+    /// Does nothing if the folder already exists.
+    pub fn insert_folder(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let indices = self.create_folders(normalize_path(path.as_ref()))?;
+
+        if let FileTree::Leaf { .. } = self.node_at_mut(&indices) {
+            return Err(anyhow::anyhow!(
+                "path pointed to file, can't insert a folder in a non directory."
+            ));
+        }
+
+        self.update_path_hashes(&indices);
+
         Ok(())
     }
+
+    /// Makes sure all folders on `path` exist, and returns the child indices of the deepest one.
+    /// Does not update the hashes of the folders.
+    fn create_folders(&mut self, path: impl AsRef<Path>) -> Result<Vec<usize>> {
+        let path = normalize_path(path.as_ref());
+        let mut indices = self
+            .traverse_indices(&path)
+            .context("finding closest filetree node went wrong")?;
+
+        for c in path.components().skip(indices.len()) {
+            let folder = FileTree::new_folder(c.as_os_str().to_string_lossy().into_owned());
+
+            if let FileTree::Node { children, .. } = self.node_at_mut(&indices) {
+                children.push(folder);
+                indices.push(children.len() - 1);
+            }
+        }
+
+        Ok(indices)
+    }
+
+    fn new_folder(name: String) -> Self {
+        Self::node(name, Vec::new())
+    }
+
+    /// Errors unless every name in the tree is a valid name, and every file has the path it is
+    /// at in the tree. Trees received from others are checked with this before we use them.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            FileTree::Node { children, .. } => children
+                .iter()
+                .try_for_each(|child| child.check_at(Path::new(""))),
+            FileTree::Leaf { .. } => Err(anyhow::anyhow!("the root of a tree must be a folder")),
+        }
+    }
+
+    fn check_at(&self, parent: &Path) -> Result<()> {
+        if !is_valid_name(self.name()) {
+            return Err(anyhow::anyhow!("invalid name {:?}", self.name()));
+        }
+        let path = parent.join(self.name());
+
+        match self {
+            FileTree::Node { children, .. } => {
+                children.iter().try_for_each(|child| child.check_at(&path))
+            }
+            FileTree::Leaf { file, .. } if file.path != path => Err(anyhow::anyhow!(
+                "file at {:?} has path {:?}",
+                path,
+                file.path
+            )),
+            FileTree::Leaf { .. } => Ok(()),
+        }
+    }
+
+    /// Describes the directory at `path`, unless its hash is `known` already. Used to find out
+    /// where two trees differ without sending the entire tree.
+    pub fn summarize(&self, path: impl AsRef<Path>, known: &Hash) -> NodeSummary {
+        match self.find(path) {
+            Some(FileTree::Node { hash, .. }) if hash == known => NodeSummary::Unchanged,
+            Some(FileTree::Node { children, .. }) => NodeSummary::Children(
+                children
+                    .iter()
+                    .map(|child| match child {
+                        FileTree::Node { name, hash, .. } => ChildSummary::Node {
+                            name: name.clone(),
+                            hash: hash.clone(),
+                        },
+                        FileTree::Leaf { name, file } => ChildSummary::Leaf {
+                            name: name.clone(),
+                            file: file.clone(),
+                        },
+                    })
+                    .collect(),
+            ),
+            _ => NodeSummary::Missing,
+        }
+    }
+
+    /// Makes the directory at `path` look like `children`, which is what someone else has there.
+    /// Returns the paths of the directories in it which differ, and need to be summarized next.
+    /// Errors if a child has an invalid name, or a file in it has a different path.
+    pub fn apply_summary(
+        &mut self,
+        path: impl AsRef<Path>,
+        children: Vec<ChildSummary>,
+    ) -> Result<Vec<PathBuf>> {
+        let path = normalize_path(path.as_ref());
+        self.insert_folder(&path)?;
+
+        let mut differing = Vec::new();
+        let mut names = Vec::new();
+
+        for child in children {
+            let name = match &child {
+                ChildSummary::Node { name, .. } | ChildSummary::Leaf { name, .. } => name,
+            };
+            if !is_valid_name(name) || names.contains(name) {
+                return Err(anyhow::anyhow!("invalid name {:?} in summary", name));
+            }
+
+            match child {
+                ChildSummary::Leaf { name, file } => {
+                    let child_path = path.join(&name);
+                    if file.path != child_path {
+                        return Err(anyhow::anyhow!(
+                            "file at {:?} has path {:?}",
+                            child_path,
+                            file.path
+                        ));
+                    }
+                    match self.find(&child_path) {
+                        Some(FileTree::Leaf { file: ours, .. }) if ours.hash == file.hash => (),
+                        _ => {
+                            self.delete(&child_path, true);
+                            self.insert(&child_path, file)?;
+                        }
+                    }
+                    names.push(name);
+                }
+                ChildSummary::Node { name, hash } => {
+                    let child_path = path.join(&name);
+                    match self.find(&child_path) {
+                        Some(FileTree::Node { hash: ours, .. }) if *ours == hash => (),
+                        Some(FileTree::Node { .. }) => differing.push(child_path),
+                        _ => {
+                            self.delete(&child_path, true);
+                            self.insert_folder(&child_path)?;
+                            differing.push(child_path);
+                        }
+                    }
+                    names.push(name);
+                }
+            }
+        }
+
+        // Whatever they don't have there anymore
+        let gone: Vec<String> = match self.find(&path) {
+            Some(FileTree::Node { children, .. }) => children
+                .iter()
+                .map(|c| c.name().to_string())
+                .filter(|n| !names.contains(n))
+                .collect(),
+            _ => Vec::new(),
+        };
+        for name in gone {
+            self.delete(path.join(name), true);
+        }
+
+        Ok(differing)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::fs::filetree::FileTree::{Leaf, Node};
+    use crate::fs::filetree::FileTree::Leaf;
 
     #[test]
     pub fn test_insert() {
@@ -313,16 +598,16 @@ mod tests {
 
         assert_eq!(
             f,
-            Node {
-                name: "".into(),
-                children: vec![Node {
-                    name: "yeet".into(),
-                    children: vec![Leaf {
+            FileTree::node(
+                "",
+                vec![FileTree::node(
+                    "yeet",
+                    vec![Leaf {
                         name: "yeet.txt".into(),
                         file
-                    },],
-                },],
-            }
+                    },]
+                ),]
+            )
         );
     }
 
@@ -363,16 +648,7 @@ mod tests {
             unreachable!()
         }
 
-        assert_eq!(
-            f,
-            Node {
-                name: "".into(),
-                children: vec![Node {
-                    name: "yeet".into(),
-                    children: vec![],
-                },],
-            }
-        );
+        assert_eq!(f, FileTree::node("", vec![FileTree::node("yeet", vec![]),]));
     }
 
     #[test]
@@ -384,13 +660,7 @@ mod tests {
         f.insert("yeet/yeet.txt", file).unwrap();
         f.delete("yeet", true).unwrap();
 
-        assert_eq!(
-            f,
-            Node {
-                name: "".into(),
-                children: vec![]
-            }
-        );
+        assert_eq!(f, FileTree::node("", vec![]));
     }
 
     #[test]
@@ -453,16 +723,16 @@ mod tests {
     pub fn test_iter_simple() {
         let file = File::new_empty("yeet.txt".into());
 
-        let t = Node {
-            name: "".into(),
-            children: vec![Node {
-                name: "yeet".into(),
-                children: vec![Leaf {
+        let t = FileTree::node(
+            "",
+            vec![FileTree::node(
+                "yeet",
+                vec![Leaf {
                     name: "yeet.txt".into(),
                     file: file.clone(),
                 }],
-            }],
-        };
+            )],
+        );
 
         assert_eq!(
             vec![&file],
@@ -480,19 +750,19 @@ mod tests {
 
         assert_eq!(
             f,
-            Node {
-                name: "".into(),
-                children: vec![Node {
-                    name: "yeet".into(),
-                    children: vec![Node {
-                        name: "yeet".into(),
-                        children: vec![Leaf {
+            FileTree::node(
+                "",
+                vec![FileTree::node(
+                    "yeet",
+                    vec![FileTree::node(
+                        "yeet",
+                        vec![Leaf {
                             name: "yeet.txt".into(),
                             file
-                        }],
-                    },],
-                },],
-            }
+                        }]
+                    ),]
+                ),]
+            )
         );
     }
 
@@ -507,28 +777,28 @@ mod tests {
 
         assert_eq!(
             f,
-            Node {
-                name: "".into(),
-                children: vec![Node {
-                    name: "yeet".into(),
-                    children: vec![
-                        Node {
-                            name: "yote".into(),
-                            children: vec![FileTree::Leaf {
+            FileTree::node(
+                "",
+                vec![FileTree::node(
+                    "yeet",
+                    vec![
+                        FileTree::node(
+                            "yote",
+                            vec![FileTree::Leaf {
                                 name: "yeet.txt".into(),
                                 file: file.clone()
-                            }],
-                        },
-                        Node {
-                            name: "yeet".into(),
-                            children: vec![FileTree::Leaf {
+                            }]
+                        ),
+                        FileTree::node(
+                            "yeet",
+                            vec![FileTree::Leaf {
                                 name: "yeet.txt".into(),
                                 file
-                            }],
-                        },
-                    ],
-                },],
-            }
+                            }]
+                        ),
+                    ]
+                ),]
+            )
         );
     }
 
@@ -549,9 +819,9 @@ mod tests {
         // This will insert a file in yeet/yeet.txt
         assert!(f.insert("yeet/./yeet.txt", file.clone()).is_ok());
 
-        let expected = Node {
-            name: "".to_string(),
-            children: vec![
+        let expected = FileTree::node(
+            "",
+            vec![
                 Leaf {
                     name: "yeet.txt".to_string(),
                     file: file.clone(),
@@ -564,15 +834,15 @@ mod tests {
                     name: "yeet3.txt".to_string(),
                     file: file.clone(),
                 },
-                Node {
-                    name: "yeet".to_string(),
-                    children: vec![Leaf {
+                FileTree::node(
+                    "yeet",
+                    vec![Leaf {
                         name: "yeet.txt".to_string(),
                         file,
                     }],
-                },
+                ),
             ],
-        };
+        );
 
         assert_eq!(expected, f);
     }
@@ -586,4 +856,135 @@ mod tests {
         };
         assert!(f.insert("some path", file).is_err());
     }
+
+    fn file(contents: &[u8]) -> File {
+        let mut file = File::new_empty("None".into());
+        file.hash = Hash::new(contents.to_vec());
+        file
+    }
+
+    fn file_at(path: &str, contents: &[u8]) -> File {
+        let mut file = file(contents);
+        file.path = path.into();
+        file
+    }
+
+    #[test]
+    pub fn test_hash_order_independent() {
+        let mut a = FileTree::new();
+        a.insert("yeet/yeet.txt", file(b"1")).unwrap();
+        a.insert("yote.txt", file(b"2")).unwrap();
+
+        let mut b = FileTree::new();
+        b.insert("yote.txt", file(b"2")).unwrap();
+        b.insert("yeet/yeet.txt", file(b"1")).unwrap();
+        assert_eq!(a.hash(), b.hash());
+
+        b.insert("yeet/yeet.txt", file(b"3")).unwrap();
+        assert_ne!(a.hash(), b.hash());
+        assert_eq!(b.iter().count(), 2);
+
+        b.delete("yeet/yeet.txt", false).unwrap();
+        a.delete("yeet/yeet.txt", false).unwrap();
+        assert_eq!(a.hash(), b.hash());
+    }
+
+    #[test]
+    pub fn test_safe_paths() {
+        assert!(is_safe_path(Path::new("yeet/yeet.txt")));
+        assert!(!is_safe_path(Path::new("")));
+        assert!(!is_safe_path(Path::new("/yeet.txt")));
+        assert!(!is_safe_path(Path::new("yeet/../../yeet.txt")));
+        assert!(!is_safe_path(Path::new("./yeet.txt")));
+
+        let mut tree = FileTree::new();
+        let mut yeet = file(b"1");
+        yeet.path = "yeet/yeet.txt".into();
+        tree.insert("yeet/yeet.txt", yeet.clone()).unwrap();
+        assert!(tree.check().is_ok());
+
+        // A file somewhere else than where it is in the tree
+        tree.insert("yote.txt", yeet).unwrap();
+        assert!(tree.check().is_err());
+
+        let dotdot = FileTree::node("", vec![FileTree::node("..", Vec::new())]);
+        assert!(dotdot.check().is_err());
+    }
+
+    #[test]
+    pub fn test_summary_invalid_names() {
+        let mut tree = FileTree::new();
+        for name in &["..", ".", "", "yeet/yote", "/yeet"] {
+            let node = ChildSummary::Node {
+                name: name.to_string(),
+                hash: Hash::new(Vec::new()),
+            };
+            assert!(tree.apply_summary("", vec![node]).is_err());
+        }
+
+        let mut yeet = file(b"1");
+        yeet.path = "/etc/yeet".into();
+        let leaf = ChildSummary::Leaf {
+            name: "yeet".into(),
+            file: yeet,
+        };
+        assert!(tree.apply_summary("", vec![leaf]).is_err());
+        assert_eq!(tree, FileTree::new());
+    }
+
+    #[test]
+    pub fn test_summary_reconcile() {
+        let mut theirs = FileTree::new();
+        theirs
+            .insert("same/yeet.txt", file_at("same/yeet.txt", b"1"))
+            .unwrap();
+        theirs
+            .insert("changed/yeet.txt", file_at("changed/yeet.txt", b"2"))
+            .unwrap();
+        theirs
+            .insert(
+                "changed/deeper/yote.txt",
+                file_at("changed/deeper/yote.txt", b"3"),
+            )
+            .unwrap();
+        theirs
+            .insert("new/yeet.txt", file_at("new/yeet.txt", b"4"))
+            .unwrap();
+        theirs.insert_folder("empty").unwrap();
+
+        let mut ours = FileTree::new();
+        ours.insert("same/yeet.txt", file_at("same/yeet.txt", b"1"))
+            .unwrap();
+        ours.insert("changed/yeet.txt", file_at("changed/yeet.txt", b"5"))
+            .unwrap();
+        ours.insert(
+            "changed/deeper/yote.txt",
+            file_at("changed/deeper/yote.txt", b"3"),
+        )
+        .unwrap();
+        ours.insert("gone/yeet.txt", file_at("gone/yeet.txt", b"6"))
+            .unwrap();
+
+        let mut requests = 0;
+        let mut pending = vec![PathBuf::new()];
+        while let Some(path) = pending.pop() {
+            requests += 1;
+            let known = ours.find(&path).unwrap().hash().clone();
+            match theirs.summarize(&path, &known) {
+                NodeSummary::Children(children) => {
+                    pending.extend(ours.apply_summary(&path, children).unwrap())
+                }
+                summary => assert_eq!(summary, NodeSummary::Unchanged),
+            }
+        }
+
+        assert_eq!(ours.hash(), theirs.hash());
+        // The root, the changed folder, and the new folders, but not what stayed the same
+        assert_eq!(requests, 4);
+        assert_eq!(theirs.summarize("", theirs.hash()), NodeSummary::Unchanged);
+        assert_eq!(
+            theirs.summarize("nope", theirs.hash()),
+            NodeSummary::Missing
+        );
+    }
 }
@@ -0,0 +1,64 @@
+use crate::fs::file::File;
+use crate::fs::filetree::FileTree;
+use serde::{Deserialize, Serialize};
+
+/// How many changes we remember per member. Anyone who is further behind than this gets sent the
+/// entire file tree instead.
+pub const MAX_CHANGES: u64 = 10_000;
+
+/// A single change to someone's file tree. Every change increments the version of the tree by one.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Change {
+    Add(File),
+    Update { old: File, new: File },
+    Delete(File),
+}
+
+impl Change {
+    /// The files this change is about
+    pub fn files(&self) -> Vec<&File> {
+        match self {
+            Change::Add(file) | Change::Delete(file) => vec![file],
+            Change::Update { old, new } => vec![old, new],
+        }
+    }
+
+    pub fn files_mut(&mut self) -> Vec<&mut File> {
+        match self {
+            Change::Add(file) | Change::Delete(file) => vec![file],
+            Change::Update { old, new } => vec![old, new],
+        }
+    }
+}
+
+/// Which part of the change log of a member we still have. The changes from `base` up to
+/// (but not including) `version` are stored, the ones before were compacted away.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ChangeLogInfo {
+    pub base: u64,
+    pub version: u64,
+}
+
+impl ChangeLogInfo {
+    /// A change log of which we know nothing before `version`
+    pub fn starting_at(version: u64) -> Self {
+        Self {
+            base: version,
+            version,
+        }
+    }
+
+    /// Returns true if we still have all changes after `version`
+    pub fn has_changes_since(&self, version: u64) -> bool {
+        self.base <= version && version <= self.version
+    }
+}
+
+/// What a member sends us when we ask what changed in their file tree.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum FileTreeUpdate {
+    /// Everything that happened since the version we asked for, up to `version`
+    Changes { version: u64, changes: Vec<Change> },
+    /// The changes we asked for are not available anymore, so this is the entire tree
+    Full { version: u64, tree: FileTree },
+}
@@ -0,0 +1,743 @@
+use crate::fs::file::File;
+use crate::fs::group::partial::PartialFile;
+use crate::user::PublicUser;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{delay_for, timeout, Duration};
+use uuid::Uuid;
+
+/// How long we wait for a single block before we consider a peer too slow to be useful.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a worker without work waits before asking the scheduler again.
+const IDLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long we avoid a peer after the first time they sent us a corrupt block. This doubles with
+/// every time it happens again, up to [MAX_PENALTY].
+const PENALTY: Duration = Duration::from_secs(10 * 60);
+
+const MAX_PENALTY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Anything we can ask for blocks of a file. Usually this is a [Client](crate::dspfs::client::Client)
+/// connected to another member of the group.
+#[async_trait]
+pub trait BlockSource: Send {
+    /// Requests block `index` of `file`. Returns `None` when the other side doesn't have the file.
+    async fn get_block(
+        &mut self,
+        groupuuid: Uuid,
+        file: &File,
+        index: u64,
+    ) -> Result<Option<Vec<u8>>>;
+}
+
+/// What happened while trying to download a file.
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// True when the file was fully downloaded
+    pub complete: bool,
+    /// Users which told us they don't have the file
+    pub not_found: HashSet<PublicUser>,
+    /// True when the file wasn't put in place, because we have a different file at its path
+    pub conflict: bool,
+    /// Users which sent us corrupt blocks
+    pub corrupt: HashSet<PublicUser>,
+}
+
+/// Remembers that a peer sent us corrupt blocks, so later downloads ask others first.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Penalty {
+    /// How many downloads this peer sent corrupt blocks in
+    pub strikes: u32,
+    /// Until when this peer is only asked for blocks when nobody else is left
+    pub until: SystemTime,
+}
+
+impl Penalty {
+    pub fn new() -> Self {
+        Self {
+            strikes: 0,
+            until: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Records another strike, and avoids the peer for longer than the last time
+    pub fn strike(&mut self) {
+        self.strikes += 1;
+        let penalty = PENALTY * 2u32.saturating_pow(self.strikes - 1);
+        self.until = SystemTime::now() + penalty.min(MAX_PENALTY);
+    }
+
+    pub fn is_active(&self) -> bool {
+        SystemTime::now() < self.until
+    }
+}
+
+impl Default for Penalty {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum DownloadState {
+    /// Waiting for someone who has the file to come online
+    Pending,
+    /// Everyone who should have had the file told us they don't have it
+    Failed,
+    /// We have a different file at its path, which we won't overwrite
+    Conflict,
+}
+
+/// A download in the persistent download queue of a group.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct QueuedDownload {
+    pub file: File,
+    pub state: DownloadState,
+    /// Users which told us they don't have the file (anymore)
+    pub not_found: HashSet<PublicUser>,
+}
+
+impl QueuedDownload {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            state: DownloadState::Pending,
+            not_found: HashSet::new(),
+        }
+    }
+
+    /// Records the result of an unsuccessful download attempt. Once every owner of the file
+    /// (except for `me`) told us they don't have it, the download has failed.
+    pub fn record_attempt(&mut self, report: DownloadReport, me: &PublicUser) {
+        self.not_found.extend(report.not_found);
+
+        let not_found = &self.not_found;
+        if self
+            .file
+            .users()
+            .filter(|&u| u != me)
+            .all(|u| not_found.contains(u))
+        {
+            self.state = DownloadState::Failed;
+        }
+    }
+}
+
+enum Work {
+    Block(u64),
+    Wait,
+    Stop,
+}
+
+#[derive(Default)]
+struct PeerState {
+    /// Number of blocks this peer delivered
+    served: u64,
+    /// Number of blocks this peer sent which didn't match their hash
+    corrupt: u64,
+    /// Set when this peer told us it doesn't have the file
+    not_found: bool,
+    /// Set when this peer failed us and shouldn't be asked for any more blocks
+    dropped: bool,
+    /// Set when this peer sent us corrupt blocks before, see [Penalty]
+    penalised: bool,
+}
+
+/// Hands out block indices to the peers we download from. Peers pull work whenever they are done
+/// with their previous block, so fast peers automatically end up serving most of the file.
+/// Penalised peers only get work once all other peers are gone.
+struct Scheduler {
+    pending: VecDeque<u64>,
+    /// Blocks currently requested, and from whom
+    in_flight: HashMap<u64, HashSet<PublicUser>>,
+    peers: HashMap<PublicUser, PeerState>,
+}
+
+impl Scheduler {
+    fn new(
+        blocks: impl Iterator<Item = u64>,
+        peers: impl Iterator<Item = PublicUser>,
+        penalised: &HashSet<PublicUser>,
+    ) -> Self {
+        Self {
+            pending: blocks.collect(),
+            in_flight: HashMap::new(),
+            peers: peers
+                .map(|p| {
+                    let state = PeerState {
+                        penalised: penalised.contains(&p),
+                        ..Default::default()
+                    };
+                    (p, state)
+                })
+                .collect(),
+        }
+    }
+
+    fn next(&mut self, peer: &PublicUser) -> Work {
+        let penalised = match self.peers.get(peer) {
+            Some(p) if !p.dropped => p.penalised,
+            _ => return Work::Stop,
+        };
+
+        if penalised && self.peers.values().any(|p| !p.dropped && !p.penalised) {
+            return if self.pending.is_empty() && self.in_flight.is_empty() {
+                Work::Stop
+            } else {
+                Work::Wait
+            };
+        }
+
+        if let Some(index) = self.pending.pop_front() {
+            self.in_flight
+                .entry(index)
+                .or_default()
+                .insert(peer.clone());
+            return Work::Block(index);
+        }
+
+        // Nothing left to hand out, but other peers may still be working on the last blocks.
+        // Request one of those as well so a single slow peer can't stall the end of the download.
+        if let Some((&index, requested_by)) = self
+            .in_flight
+            .iter_mut()
+            .find(|(_, requested_by)| !requested_by.contains(peer))
+        {
+            requested_by.insert(peer.clone());
+            return Work::Block(index);
+        }
+
+        if self.in_flight.is_empty() {
+            Work::Stop
+        } else {
+            Work::Wait
+        }
+    }
+
+    /// Marks a block as received. Returns false if another peer already delivered it.
+    fn completed(&mut self, peer: &PublicUser, index: u64) -> bool {
+        if let Some(p) = self.peers.get_mut(peer) {
+            p.served += 1;
+        }
+
+        self.in_flight.remove(&index).is_some()
+    }
+
+    /// Puts a block back in the queue (unless someone else is still fetching it)
+    /// and stops asking this peer for blocks.
+    fn failed(&mut self, peer: &PublicUser, index: u64) {
+        if let Some(requested_by) = self.in_flight.get_mut(&index) {
+            requested_by.remove(peer);
+            if requested_by.is_empty() {
+                self.in_flight.remove(&index);
+                self.pending.push_front(index);
+            }
+        }
+
+        if let Some(p) = self.peers.get_mut(peer) {
+            p.dropped = true;
+        }
+    }
+
+    /// Called when a peer sent us a block that doesn't match its hash. The block is requested again
+    /// from someone else and since we can't trust this peer anymore, it is dropped from the download.
+    fn corrupt(&mut self, peer: &PublicUser, index: u64) {
+        if let Some(p) = self.peers.get_mut(peer) {
+            p.corrupt += 1;
+        }
+
+        self.failed(peer, index);
+    }
+
+    /// Called when a peer doesn't have the file we're trying to download.
+    fn not_found(&mut self, peer: &PublicUser, index: u64) {
+        if let Some(p) = self.peers.get_mut(peer) {
+            p.not_found = true;
+        }
+
+        self.failed(peer, index);
+    }
+
+    /// Stops all workers, used when all blocks have been written.
+    fn finish(&mut self) {
+        self.pending.clear();
+        self.in_flight.clear();
+
+        for (peer, state) in &self.peers {
+            log::debug!(
+                "{:?} served {} blocks ({} corrupt)",
+                peer,
+                state.served,
+                state.corrupt
+            );
+        }
+    }
+}
+
+struct Worker {
+    peer: PublicUser,
+    source: Box<dyn BlockSource>,
+    groupuuid: Uuid,
+    file: Arc<File>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    blocks: mpsc::Sender<(u64, Vec<u8>)>,
+}
+
+impl Worker {
+    async fn run(mut self) {
+        loop {
+            let next = self.scheduler.lock().await.next(&self.peer);
+            let index = match next {
+                Work::Block(index) => index,
+                Work::Wait => {
+                    delay_for(IDLE_INTERVAL).await;
+                    continue;
+                }
+                Work::Stop => break,
+            };
+
+            let response = timeout(
+                BLOCK_TIMEOUT,
+                self.source.get_block(self.groupuuid, &self.file, index),
+            )
+            .await;
+
+            match response {
+                Ok(Ok(Some(block))) if !self.file.verify_block(index, &block) => {
+                    log::warn!(
+                        "{:?} sent a corrupt block {} of {:?}",
+                        self.peer,
+                        index,
+                        self.file.path
+                    );
+                    self.scheduler.lock().await.corrupt(&self.peer, index);
+                }
+                Ok(Ok(Some(block))) => {
+                    let first = self.scheduler.lock().await.completed(&self.peer, index);
+                    if first && self.blocks.send((index, block)).await.is_err() {
+                        // The download was aborted
+                        break;
+                    }
+                }
+                Ok(Ok(None)) => {
+                    log::info!("{:?} doesn't have the requested file", self.peer);
+                    self.scheduler.lock().await.not_found(&self.peer, index);
+                }
+                Ok(Err(e)) => {
+                    log::warn!("requesting block from {:?} failed: {:?}", self.peer, e);
+                    self.scheduler.lock().await.failed(&self.peer, index);
+                }
+                Err(_) => {
+                    log::warn!("{:?} was too slow sending block {}", self.peer, index);
+                    self.scheduler.lock().await.failed(&self.peer, index);
+                }
+            }
+        }
+    }
+}
+
+/// Downloads all blocks of `file` which are still missing from `partial` in parallel from all
+/// `sources`, and writes them to `partial`. Every block is checked against its hash before it is
+/// written. Peers which fail, are too slow or send corrupt blocks are dropped, and their blocks
+/// are requested from the others. Peers in `penalised` are only asked when nobody else is left.
+///
+/// Running out of peers before all blocks are in is not an error, but results in an incomplete
+/// [DownloadReport]. Errors are reserved for problems on our side, like failing to write the file.
+pub async fn download_blocks(
+    groupuuid: Uuid,
+    file: &File,
+    partial: &mut PartialFile,
+    sources: Vec<(PublicUser, Box<dyn BlockSource>)>,
+    penalised: &HashSet<PublicUser>,
+) -> Result<DownloadReport> {
+    let mut remaining = partial.missing_blocks().count();
+    if remaining == 0 {
+        return Ok(DownloadReport {
+            complete: true,
+            ..Default::default()
+        });
+    }
+
+    if sources.is_empty() {
+        return Err(anyhow::anyhow!("no users to download the file from"));
+    }
+
+    let file = Arc::new(file.clone());
+    let scheduler = Arc::new(Mutex::new(Scheduler::new(
+        partial.missing_blocks(),
+        sources.iter().map(|(peer, _)| peer.clone()),
+        penalised,
+    )));
+    let (tx, mut rx) = mpsc::channel(2 * sources.len());
+
+    for (peer, source) in sources {
+        let worker = Worker {
+            peer,
+            source,
+            groupuuid,
+            file: file.clone(),
+            scheduler: scheduler.clone(),
+            blocks: tx.clone(),
+        };
+        tokio::spawn(worker.run());
+    }
+    // Only the workers hold a sender now, so the channel closes once they all gave up
+    drop(tx);
+
+    while remaining > 0 {
+        let (index, block) = if let Some(block) = rx.recv().await {
+            block
+        } else {
+            log::info!(
+                "no users left to download {:?} from, {} blocks missing",
+                file.path,
+                remaining
+            );
+            break;
+        };
+
+        partial.write_block(index, &block).await?;
+
+        remaining -= 1;
+    }
+    let mut scheduler = scheduler.lock().await;
+    scheduler.finish();
+
+    Ok(DownloadReport {
+        complete: remaining == 0,
+        not_found: scheduler
+            .peers
+            .iter()
+            .filter(|(_, state)| state.not_found)
+            .map(|(peer, _)| peer.clone())
+            .collect(),
+        corrupt: scheduler
+            .peers
+            .iter()
+            .filter(|(_, state)| state.corrupt > 0)
+            .map(|(peer, _)| peer.clone())
+            .collect(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::PrivateUser;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::tempdir;
+
+    /// Serves blocks straight from a file on disk
+    struct DiskSource {
+        data: Vec<u8>,
+        served: Arc<AtomicUsize>,
+        delay: Duration,
+        broken: bool,
+        corrupt: bool,
+    }
+
+    #[async_trait]
+    impl BlockSource for DiskSource {
+        async fn get_block(
+            &mut self,
+            _groupuuid: Uuid,
+            file: &File,
+            index: u64,
+        ) -> Result<Option<Vec<u8>>> {
+            delay_for(self.delay).await;
+            if self.broken {
+                return Err(anyhow::anyhow!("connection reset"));
+            }
+
+            self.served.fetch_add(1, Ordering::SeqCst);
+            let start = (index * file.block_size) as usize;
+            let end = (start + file.block_size as usize).min(self.data.len());
+            let mut block = self.data[start..end].to_vec();
+            if self.corrupt {
+                block[0] ^= 0xff;
+            }
+
+            Ok(Some(block))
+        }
+    }
+
+    fn peer(name: &str) -> PublicUser {
+        PrivateUser::new(name).unwrap().0.public_user().clone()
+    }
+
+    async fn test_file(dir: &Path) -> (File, Vec<u8>) {
+        // A bit over three blocks of 128 KiB
+        let data: Vec<u8> = (0..400_000u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("source");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&data)
+            .unwrap();
+
+        (File::new(path).await.unwrap(), data)
+    }
+
+    fn source(data: &[u8], delay: Duration, broken: bool) -> (Box<DiskSource>, Arc<AtomicUsize>) {
+        let served = Arc::new(AtomicUsize::new(0));
+        let source = DiskSource {
+            data: data.to_vec(),
+            served: served.clone(),
+            delay,
+            broken,
+            corrupt: false,
+        };
+        (Box::new(source), served)
+    }
+
+    fn corrupt_source(data: &[u8]) -> (Box<DiskSource>, Arc<AtomicUsize>) {
+        let (mut source, served) = source(data, Duration::from_millis(0), false);
+        source.corrupt = true;
+        (source, served)
+    }
+
+    #[tokio::test]
+    async fn test_download_parallel() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+
+        let (s1, served1) = source(&data, Duration::from_millis(10), false);
+        let (s2, served2) = source(&data, Duration::from_millis(10), false);
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(peer("a"), s1), (peer("b"), s2)],
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.complete);
+
+        let target = dir.path().join("target");
+        partial.finish(&target).await.unwrap();
+        assert!(std::fs::read(target).unwrap() == data);
+        assert!(served1.load(Ordering::SeqCst) > 0);
+        assert!(served2.load(Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_download_failing_peer() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+
+        let (good, _) = source(&data, Duration::from_millis(10), false);
+        let (bad, _) = source(&data, Duration::from_millis(0), true);
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(peer("bad"), bad), (peer("good"), good)],
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.complete);
+
+        let target = dir.path().join("target");
+        partial.finish(&target).await.unwrap();
+        assert!(std::fs::read(target).unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn test_download_all_peers_fail() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+
+        let (bad, _) = source(&data, Duration::from_millis(0), true);
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(peer("bad"), bad)],
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(!report.complete);
+        assert!(report.not_found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_corrupt_peer() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+
+        let (good, _) = source(&data, Duration::from_millis(10), false);
+        let (corrupt, served) = corrupt_source(&data);
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(peer("corrupt"), corrupt), (peer("good"), good)],
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.complete);
+
+        let target = dir.path().join("target");
+        partial.finish(&target).await.unwrap();
+        assert!(std::fs::read(target).unwrap() == data);
+        // Never ask a peer which sent a corrupt block for more
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_only_corrupt_peers() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+
+        let (corrupt, _) = corrupt_source(&data);
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(peer("corrupt"), corrupt)],
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(!report.complete);
+    }
+
+    #[tokio::test]
+    async fn test_download_penalised_peer() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+
+        let (good, _) = source(&data, Duration::from_millis(10), false);
+        let (penalised, served) = source(&data, Duration::from_millis(0), false);
+        let suspect = peer("suspect");
+        let only = vec![suspect.clone()].into_iter().collect();
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(suspect.clone(), penalised), (peer("good"), good)],
+            &only,
+        )
+        .await
+        .unwrap();
+        assert!(report.complete);
+        assert!(report.corrupt.is_empty());
+        assert_eq!(served.load(Ordering::SeqCst), 0);
+
+        // But they are better than nobody
+        let (penalised, served) = source(&data, Duration::from_millis(0), false);
+        PartialFile::remove(dir.path(), &file).await.unwrap();
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(suspect, penalised)],
+            &only,
+        )
+        .await
+        .unwrap();
+        assert!(report.complete);
+        assert!(served.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn test_penalty() {
+        let mut penalty = Penalty::new();
+        assert!(!penalty.is_active());
+
+        penalty.strike();
+        assert!(penalty.is_active());
+        let first = penalty.until;
+
+        penalty.strike();
+        assert!(penalty.until > first + PENALTY / 2);
+        assert_eq!(penalty.strikes, 2);
+
+        // Even the worst peers get another chance eventually
+        penalty.strikes = 100;
+        penalty.strike();
+        assert!(penalty.until <= SystemTime::now() + MAX_PENALTY);
+    }
+
+    #[tokio::test]
+    async fn test_queued_download_fails_when_nobody_has_it() {
+        let dir = tempdir().unwrap();
+        let (mut file, _) = test_file(dir.path()).await;
+
+        let me = peer("me");
+        let (a, b) = (peer("a"), peer("b"));
+        file.add_user(me.clone());
+        file.add_user(a.clone());
+        file.add_user(b.clone());
+
+        let mut queued = QueuedDownload::new(file);
+
+        // Someone else may still have it
+        let mut report = DownloadReport::default();
+        report.not_found.insert(a);
+        queued.record_attempt(report, &me);
+        assert_eq!(queued.state, DownloadState::Pending);
+
+        let mut report = DownloadReport::default();
+        report.not_found.insert(b);
+        queued.record_attempt(report, &me);
+        assert_eq!(queued.state, DownloadState::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes() {
+        let dir = tempdir().unwrap();
+        let (file, data) = test_file(dir.path()).await;
+        let bs = file.block_size as usize;
+
+        // We already got the first two blocks before
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        partial.write_block(0, &data[..bs]).await.unwrap();
+        partial.write_block(1, &data[bs..2 * bs]).await.unwrap();
+        drop(partial);
+
+        let (s, served) = source(&data, Duration::from_millis(0), false);
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        let report = download_blocks(
+            Uuid::new_v4(),
+            &file,
+            &mut partial,
+            vec![(peer("a"), s)],
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert!(report.complete);
+        assert_eq!(served.load(Ordering::SeqCst) as u64, file.num_blocks() - 2);
+
+        let target = dir.path().join("target");
+        partial.finish(&target).await.unwrap();
+        assert!(std::fs::read(target).unwrap() == data);
+    }
+}
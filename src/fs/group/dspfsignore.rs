@@ -0,0 +1,131 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the files listing what shouldn't be shared
+pub const IGNORE_FILE: &str = ".dspfsignore";
+
+/// The `.dspfsignore` files of a group, which use gitignore syntax. An ignore file applies to
+/// everything in the folder it is in, and rules in deeper folders take precedence over the ones
+/// above them. Ignore files are read when they are first needed.
+pub struct DspfsIgnore {
+    root: PathBuf,
+    files: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl DspfsIgnore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Returns true if `path` (relative to the group root) is ignored, or is in an ignored folder.
+    /// The `.dspfs` folder is always ignored.
+    pub fn is_ignored(&mut self, path: impl AsRef<Path>, is_dir: bool) -> bool {
+        let path = path.as_ref();
+        if path.starts_with(".dspfs") {
+            return true;
+        }
+
+        // Everything in an ignored folder is ignored as well
+        let mut ancestors: Vec<&Path> = path
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect();
+        ancestors.reverse();
+
+        let last = ancestors.len().saturating_sub(1);
+        ancestors
+            .into_iter()
+            .enumerate()
+            .any(|(i, p)| self.matches(p, i < last || is_dir))
+    }
+
+    /// Whether the closest ignore file with a rule for `path` ignores it
+    fn matches(&mut self, path: &Path, is_dir: bool) -> bool {
+        let absolute = self.root.join(path);
+
+        for folder in path.ancestors().skip(1) {
+            if let Some(gitignore) = self.load(folder) {
+                match gitignore.matched(&absolute, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => (),
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Reads the ignore file in `folder`, if there is one
+    fn load(&mut self, folder: &Path) -> Option<&Gitignore> {
+        let root = &self.root;
+
+        self.files
+            .entry(folder.to_path_buf())
+            .or_insert_with(|| {
+                let folder = root.join(folder);
+                let file = folder.join(IGNORE_FILE);
+                if !file.is_file() {
+                    return None;
+                }
+
+                let mut builder = GitignoreBuilder::new(&folder);
+                if let Some(e) = builder.add(&file) {
+                    log::warn!("Problem in {:?}: {:?}", file, e);
+                }
+                builder
+                    .build()
+                    .map_err(|e| log::warn!("Couldn't read {:?}: {:?}", file, e))
+                    .ok()
+            })
+            .as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ignore_folder() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "node_modules/\n*.swp\n").unwrap();
+
+        let mut ignore = DspfsIgnore::new(dir.path());
+        assert!(ignore.is_ignored("node_modules", true));
+        assert!(ignore.is_ignored("yeet/node_modules/yeet.js", false));
+        assert!(ignore.is_ignored("yeet/.yeet.txt.swp", false));
+        assert!(ignore.is_ignored(".dspfs/heed.mdb", false));
+        assert!(!ignore.is_ignored("yeet/yeet.txt", false));
+        // Only folders are matched by a trailing slash
+        assert!(!ignore.is_ignored("node_modules", false));
+    }
+
+    #[test]
+    fn test_nested_ignore() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("yeet")).unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "*.log\n").unwrap();
+        fs::write(
+            dir.path().join("yeet").join(IGNORE_FILE),
+            "!keep.log\nbuild\n",
+        )
+        .unwrap();
+
+        let mut ignore = DspfsIgnore::new(dir.path());
+        assert!(ignore.is_ignored("yote.log", false));
+        assert!(ignore.is_ignored("yeet/yote.log", false));
+        assert!(!ignore.is_ignored("yeet/keep.log", false));
+        assert!(ignore.is_ignored("yeet/build/out", false));
+        // The nested file only applies to its own folder
+        assert!(!ignore.is_ignored("build/out", false));
+        assert!(ignore.is_ignored("keep.log", false));
+    }
+}
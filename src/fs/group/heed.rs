@@ -1,21 +1,47 @@
 use crate::fs::file::File;
 use crate::fs::filetree::FileTree;
+use crate::fs::group::changelog::{Change, ChangeLogInfo, MAX_CHANGES};
+use crate::fs::group::download::{Penalty, QueuedDownload};
+use crate::fs::group::membership::SignedMembershipOp;
 use crate::fs::group::store::GroupStore;
+use crate::fs::group::StoredGroup;
 use crate::fs::hash::Hash;
-use crate::user::PublicUser;
+use crate::user::{PublicKey, PublicUser};
 use anyhow::{Context, Result};
-use heed::types::SerdeBincode;
-use heed::{Database, Env, EnvOpenOptions};
+use heed::types::{SerdeBincode, Str, Unit};
+use heed::{Database, Env, EnvOpenOptions, RwTxn};
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fs;
 use std::path::Path;
+use uuid::Uuid;
 
+/// Everything about a user is stored under their public key, as their name can change
 pub struct HeedGroupStore {
     env: Env,
-    filetrees: Database<SerdeBincode<PublicUser>, SerdeBincode<FileTree>>,
+    filetrees: Database<SerdeBincode<PublicKey>, SerdeBincode<FileTree>>,
     files: Database<SerdeBincode<Hash>, SerdeBincode<File>>,
+    downloads: Database<SerdeBincode<Hash>, SerdeBincode<QueuedDownload>>,
+    changelogs: Database<SerdeBincode<PublicKey>, SerdeBincode<ChangeLogInfo>>,
+    changes: Database<SerdeBincode<(PublicKey, u64)>, SerdeBincode<Change>>,
+    meta: Database<Str, SerdeBincode<StoredGroup>>,
+    membership: Database<SerdeBincode<Hash>, SerdeBincode<SignedMembershipOp>>,
+    format: Database<Str, SerdeBincode<u32>>,
+    invitations: Database<SerdeBincode<Uuid>, Unit>,
+    shared: Database<SerdeBincode<PublicKey>, SerdeBincode<(u64, FileTree)>>,
+    penalties: Database<SerdeBincode<PublicKey>, SerdeBincode<Penalty>>,
 }
 
+/// Key under which the group itself is stored in the meta database
+const GROUP_KEY: &str = "group";
+
+/// Key under which the format of the store is stored in the format database
+const VERSION_KEY: &str = "version";
+
+/// The format of what is in the store. Stores from before it was recorded only contain what
+/// indexing and syncing find out again, so they are emptied when they are opened.
+const STORE_VERSION: u32 = 1;
+
 impl HeedGroupStore {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
         if path.as_ref().extension() != Some(OsStr::new("mdb")) {
@@ -24,81 +50,715 @@ impl HeedGroupStore {
 
         fs::create_dir_all(&path)?;
         let mut opts = EnvOpenOptions::new();
-        opts.max_dbs(2);
+        opts.max_dbs(11);
         let env = opts.open(&path)?;
 
         let filetrees = env.create_database(Some("filetrees"))?;
         let files = env.create_database(Some("files"))?;
+        let downloads = env.create_database(Some("downloads"))?;
+        let changelogs = env.create_database(Some("changelogs"))?;
+        let changes = env.create_database(Some("changes"))?;
+        let meta = env.create_database(Some("meta"))?;
+        let membership = env.create_database(Some("membership"))?;
+        let format = env.create_database(Some("format"))?;
+        let invitations = env.create_database(Some("invitations"))?;
+        let shared = env.create_database(Some("shared"))?;
+        let penalties = env.create_database(Some("penalties"))?;
 
-        Ok(Self {
+        let store = Self {
             env,
             filetrees,
             files,
-        })
+            downloads,
+            changelogs,
+            changes,
+            meta,
+            membership,
+            format,
+            invitations,
+            shared,
+            penalties,
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// Brings a store written by an older version of dspfs up to date, see [STORE_VERSION]
+    fn migrate(&self) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        match self
+            .format
+            .get(&wtxn, VERSION_KEY)
+            .context("Error accessing the db")?
+        {
+            Some(STORE_VERSION) => return Ok(()),
+            Some(version) => {
+                return Err(anyhow::anyhow!(
+                    "group store was written by a newer version of dspfs (format {}, we know {})",
+                    version,
+                    STORE_VERSION
+                ))
+            }
+            None => (),
+        }
+
+        self.filetrees.clear(&mut wtxn)?;
+        self.files.clear(&mut wtxn)?;
+        self.downloads.clear(&mut wtxn)?;
+        self.changelogs.clear(&mut wtxn)?;
+        self.changes.clear(&mut wtxn)?;
+        self.shared.clear(&mut wtxn)?;
+
+        self.format.put(&mut wtxn, VERSION_KEY, &STORE_VERSION)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// Adds a file to the tree of a user, and returns it with everyone who has it.
+    fn insert_file(
+        &self,
+        wtxn: &mut RwTxn,
+        user: &PublicUser,
+        tree: &mut FileTree,
+        mut file: File,
+    ) -> Result<File> {
+        // Keep track of everyone we already knew had this file
+        file.add_user(user.clone());
+        if let Some(existing) = self
+            .files
+            .get(wtxn, &file.hash)
+            .context("Error accessing the db")?
+        {
+            file.merge_users(&existing);
+        }
+
+        self.files.put(wtxn, &file.hash, &file)?;
+
+        // The user doesn't have the file this one replaces anymore
+        if let Some(FileTree::Leaf { file: old, .. }) = tree.find(&file.path) {
+            if old.hash != file.hash {
+                let old = old.hash.clone();
+                self.remove_user_from_file(wtxn, user, &old)?;
+            }
+        }
+
+        tree.insert(file.path.clone(), file.clone())?;
+
+        Ok(file)
+    }
+
+    /// Removes a file from the tree of a user. Returns false if it wasn't in their tree.
+    fn remove_file(
+        &self,
+        wtxn: &mut RwTxn,
+        user: &PublicUser,
+        tree: &mut FileTree,
+        file: &File,
+    ) -> Result<bool> {
+        let removed = tree.delete(&file.path, false).is_some();
+        self.remove_user_from_file(wtxn, user, &file.hash)?;
+
+        Ok(removed)
+    }
+
+    /// Applies a change to the tree of a user, and adds it to their change log.
+    fn apply_change(
+        &self,
+        wtxn: &mut RwTxn,
+        user: &PublicUser,
+        tree: &mut FileTree,
+        change: Change,
+    ) -> Result<()> {
+        let logged = match change {
+            Change::Add(file) => Some(Change::Add(self.insert_file(wtxn, user, tree, file)?)),
+            Change::Update { old, new } => {
+                self.remove_file(wtxn, user, tree, &old)?;
+                let new = self.insert_file(wtxn, user, tree, new)?;
+                Some(Change::Update { old, new })
+            }
+            Change::Delete(file) => {
+                if self.remove_file(wtxn, user, tree, &file)? {
+                    Some(Change::Delete(file))
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(change) = logged {
+            self.log_change(wtxn, user, &change)?;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets that `user` has the file with this hash
+    fn remove_user_from_file(
+        &self,
+        wtxn: &mut RwTxn,
+        user: &PublicUser,
+        hash: &Hash,
+    ) -> Result<()> {
+        if let Some(mut file) = self
+            .files
+            .get(wtxn, hash)
+            .context("Error accessing the db")?
+        {
+            file.remove_user(user);
+            if file.num_owning_users() > 0 {
+                self.files.put(wtxn, hash, &file)?;
+            } else {
+                self.files.delete(wtxn, hash)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends a change to the change log of a user, forgetting the oldest change if the log
+    /// gets too long.
+    fn log_change(&self, wtxn: &mut RwTxn, user: &PublicUser, change: &Change) -> Result<()> {
+        let mut info = self
+            .changelogs
+            .get(wtxn, user.get_public_key())
+            .context("Error accessing the db")?
+            .unwrap_or_default();
+
+        self.changes
+            .put(wtxn, &(user.get_public_key().clone(), info.version), change)
+            .context("error saving to the db")?;
+        info.version += 1;
+
+        if info.version - info.base > MAX_CHANGES {
+            self.changes
+                .delete(wtxn, &(user.get_public_key().clone(), info.base))?;
+            info.base += 1;
+        }
+
+        self.changelogs
+            .put(wtxn, user.get_public_key(), &info)
+            .context("error saving to the db")
     }
 }
 
 impl GroupStore for HeedGroupStore {
     fn add_file(&mut self, user: &PublicUser, file: File) -> Result<()> {
+        self.apply_changes(user, vec![Change::Add(file)])
+    }
+
+    fn get_file(&self, hash: Hash) -> Result<Option<File>> {
+        let rtxn = self.env.read_txn()?;
+
+        Ok(self
+            .files
+            .get(&rtxn, &hash)
+            .context("error getting hash from db")?)
+    }
+
+    fn get_filetree(&self, user: &PublicUser) -> Result<Option<FileTree>> {
+        let rtxn = self.env.read_txn()?;
+
+        self.filetrees
+            .get(&rtxn, user.get_public_key())
+            .context("error getting filetree from db")
+    }
+
+    fn set_filetree(
+        &mut self,
+        user: &PublicUser,
+        mut filetree: FileTree,
+        version: u64,
+    ) -> Result<()> {
         let mut wtxn = self.env.write_txn()?;
 
-        self.files.put(&mut wtxn, &file.hash, &file)?;
+        let mut hashes = HashSet::new();
+        for (_, file) in filetree.iter_mut() {
+            file.add_user(user.clone());
+            if let Some(existing) = self
+                .files
+                .get(&wtxn, &file.hash)
+                .context("Error accessing the db")?
+            {
+                file.merge_users(&existing);
+            }
 
-        let mut tree = self
+            self.files.put(&mut wtxn, &file.hash, file)?;
+            hashes.insert(file.hash.clone());
+        }
+        // Don't trust the hashes of folders we got from someone else
+        filetree.update_hashes();
+
+        // The user doesn't have the files which aren't in their new tree anymore
+        if let Some(old) = self
             .filetrees
-            .get(&wtxn, user)
+            .get(&wtxn, user.get_public_key())
             .context("Error accessing the db")?
-            .unwrap_or_else(FileTree::new);
-
-        tree.insert(file.path.clone(), file)?;
+        {
+            for (_, file) in old.iter().filter(|(_, f)| !hashes.contains(&f.hash)) {
+                self.remove_user_from_file(&mut wtxn, user, &file.hash)?;
+            }
+        }
 
         self.filetrees
-            .put(&mut wtxn, user, &tree)
+            .put(&mut wtxn, user.get_public_key(), &filetree)
+            .context("error saving to the db")?;
+        self.shared.delete(&mut wtxn, user.get_public_key())?;
+
+        // The changes leading up to this tree are unknown
+        if let Some(info) = self
+            .changelogs
+            .get(&wtxn, user.get_public_key())
+            .context("Error accessing the db")?
+        {
+            for v in info.base..info.version {
+                self.changes
+                    .delete(&mut wtxn, &(user.get_public_key().clone(), v))?;
+            }
+        }
+        self.changelogs
+            .put(
+                &mut wtxn,
+                user.get_public_key(),
+                &ChangeLogInfo::starting_at(version),
+            )
             .context("error saving to the db")?;
 
         wtxn.commit()?;
+        Ok(())
+    }
+
+    fn get_filetrees(&self) -> Result<Vec<(PublicKey, FileTree)>> {
+        let rtxn = self.env.read_txn()?;
+
+        let res = self.filetrees.iter(&rtxn)?.filter_map(|i| i.ok()).collect();
+
+        Ok(res)
+    }
+
+    fn get_shared_tree(&self, user: &PublicUser) -> Result<Option<(u64, FileTree)>> {
+        let rtxn = self.env.read_txn()?;
+
+        self.shared
+            .get(&rtxn, user.get_public_key())
+            .context("error getting shared filetree from db")
+    }
+
+    fn set_shared_tree(&mut self, user: &PublicUser, version: u64, tree: &FileTree) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
 
+        self.shared
+            .put(&mut wtxn, user.get_public_key(), &(version, tree.clone()))
+            .context("error saving to the db")?;
+
+        wtxn.commit()?;
         Ok(())
     }
 
-    fn get_file(&self, hash: Hash) -> Result<Option<File>> {
+    fn get_version(&self, user: &PublicUser) -> Result<u64> {
         let rtxn = self.env.read_txn()?;
 
         Ok(self
-            .files
-            .get(&rtxn, &hash)
-            .context("error getting hash from db")?)
+            .changelogs
+            .get(&rtxn, user.get_public_key())
+            .context("error getting change log from db")?
+            .unwrap_or_default()
+            .version)
+    }
+
+    fn get_changes(&self, user: &PublicUser, since: u64) -> Result<Option<Vec<Change>>> {
+        let rtxn = self.env.read_txn()?;
+
+        let info = self
+            .changelogs
+            .get(&rtxn, user.get_public_key())
+            .context("error getting change log from db")?
+            .unwrap_or_default();
+        if !info.has_changes_since(since) {
+            return Ok(None);
+        }
+
+        (since..info.version)
+            .map(|v| {
+                self.changes
+                    .get(&rtxn, &(user.get_public_key().clone(), v))
+                    .context("error getting change from db")?
+                    .context("change log is missing a change")
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    fn update_file(&mut self, user: &PublicUser, old: &File, new: File) -> Result<()> {
+        self.apply_changes(
+            user,
+            vec![Change::Update {
+                old: old.clone(),
+                new,
+            }],
+        )
+    }
+
+    fn update_metadata(&mut self, user: &PublicUser, files: Vec<File>) -> Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        let mut wtxn = self.env.write_txn()?;
+
+        let mut tree = self
+            .filetrees
+            .get(&wtxn, user.get_public_key())
+            .context("Error accessing the db")?
+            .unwrap_or_else(FileTree::new);
+
+        for file in files {
+            match tree.find_mut(&file.path) {
+                Some(FileTree::Leaf { file: ours, .. }) if ours.hash == file.hash => {
+                    ours.metadata = file.metadata
+                }
+                _ => return Err(anyhow::anyhow!("{:?} isn't in the file tree", file.path)),
+            }
+        }
+
+        self.filetrees
+            .put(&mut wtxn, user.get_public_key(), &tree)
+            .context("error saving to the db")?;
+
+        wtxn.commit()?;
+        Ok(())
     }
 
     fn delete_file(&mut self, user: &PublicUser, file: &File) -> Result<()> {
+        self.apply_changes(user, vec![Change::Delete(file.clone())])
+    }
+
+    fn apply_changes(&mut self, user: &PublicUser, changes: Vec<Change>) -> Result<()> {
         let mut wtxn = self.env.write_txn()?;
 
-        if let Some(mut tree) = self
+        let mut tree = self
             .filetrees
-            .get(&wtxn, user)
+            .get(&wtxn, user.get_public_key())
             .context("Error accessing the db")?
-        {
-            tree.delete(&file.path, false);
+            .unwrap_or_else(FileTree::new);
 
-            self.filetrees
-                .put(&mut wtxn, user, &tree)
-                .context("error saving to the db")?;
+        for change in changes {
+            self.apply_change(&mut wtxn, user, &mut tree, change)?;
         }
 
-        if let Some(mut file) = self
-            .files
-            .get(&wtxn, &file.hash)
+        self.filetrees
+            .put(&mut wtxn, user.get_public_key(), &tree)
+            .context("error saving to the db")?;
+        self.shared.delete(&mut wtxn, user.get_public_key())?;
+
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn remove_user(&mut self, user: &PublicUser) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        if let Some(tree) = self
+            .filetrees
+            .get(&wtxn, user.get_public_key())
             .context("Error accessing the db")?
         {
-            file.remove_user(user);
-            if file.num_owning_users() > 0 {
-                self.files.put(&mut wtxn, &file.hash, &file)?;
-            } else {
-                self.files.delete(&mut wtxn, &file.hash)?;
+            for (_, file) in tree.iter() {
+                self.remove_user_from_file(&mut wtxn, user, &file.hash)?;
             }
+            self.filetrees.delete(&mut wtxn, user.get_public_key())?;
+            self.shared.delete(&mut wtxn, user.get_public_key())?;
+        }
+
+        if let Some(info) = self
+            .changelogs
+            .get(&wtxn, user.get_public_key())
+            .context("Error accessing the db")?
+        {
+            for v in info.base..info.version {
+                self.changes
+                    .delete(&mut wtxn, &(user.get_public_key().clone(), v))?;
+            }
+            self.changelogs.delete(&mut wtxn, user.get_public_key())?;
+        }
+
+        // The files in the trees of others and in the download queue still say they have them
+        let trees = self
+            .filetrees
+            .iter(&wtxn)?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error accessing the db")?;
+        for (owner, mut tree) in trees {
+            let mut changed = false;
+            for (_, file) in tree.iter_mut() {
+                changed |= file.remove_user(user);
+            }
+            if changed {
+                self.filetrees.put(&mut wtxn, &owner, &tree)?;
+                self.shared.delete(&mut wtxn, &owner)?;
+            }
+        }
+
+        let downloads = self
+            .downloads
+            .iter(&wtxn)?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error accessing the db")?;
+        for (hash, mut download) in downloads {
+            if download.file.remove_user(user) {
+                self.downloads.put(&mut wtxn, &hash, &download)?;
+            }
+        }
+
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn queue_download(&mut self, download: QueuedDownload) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        self.downloads
+            .put(&mut wtxn, &download.file.hash, &download)
+            .context("error saving to the db")?;
+
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn get_download(&self, hash: &Hash) -> Result<Option<QueuedDownload>> {
+        let rtxn = self.env.read_txn()?;
+
+        self.downloads
+            .get(&rtxn, hash)
+            .context("error getting download from db")
+    }
+
+    fn get_downloads(&self) -> Result<Vec<QueuedDownload>> {
+        let rtxn = self.env.read_txn()?;
+
+        let res = self
+            .downloads
+            .iter(&rtxn)?
+            .filter_map(|i| i.ok().map(|(_hash, download)| download))
+            .collect();
+
+        Ok(res)
+    }
+
+    fn remove_download(&mut self, hash: &Hash) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        self.downloads.delete(&mut wtxn, hash)?;
+
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn get_penalty(&self, user: &PublicUser) -> Result<Penalty> {
+        let rtxn = self.env.read_txn()?;
+
+        Ok(self
+            .penalties
+            .get(&rtxn, user.get_public_key())
+            .context("error getting penalty from db")?
+            .unwrap_or_default())
+    }
+
+    fn set_penalty(&mut self, user: &PublicUser, penalty: &Penalty) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        self.penalties
+            .put(&mut wtxn, user.get_public_key(), penalty)
+            .context("error saving to the db")?;
+
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn set_group(&mut self, group: &StoredGroup) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        self.meta
+            .put(&mut wtxn, GROUP_KEY, group)
+            .context("error saving to the db")?;
+
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn get_group(&self) -> Result<Option<StoredGroup>> {
+        let rtxn = self.env.read_txn()?;
+
+        self.meta
+            .get(&rtxn, GROUP_KEY)
+            .context("error getting group from db")
+    }
+
+    fn add_membership_ops(&mut self, ops: Vec<SignedMembershipOp>) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        for op in ops {
+            self.membership
+                .put(&mut wtxn, &op.id()?, &op)
+                .context("error saving to the db")?;
         }
 
         wtxn.commit()?;
         Ok(())
     }
+
+    fn get_membership_ops(&self) -> Result<Vec<SignedMembershipOp>> {
+        let rtxn = self.env.read_txn()?;
+
+        let res = self
+            .membership
+            .iter(&rtxn)?
+            .filter_map(|i| i.ok().map(|(_id, op)| op))
+            .collect();
+
+        Ok(res)
+    }
+
+    fn redeem_invitation(&mut self, id: Uuid) -> Result<bool> {
+        let mut wtxn = self.env.write_txn()?;
+
+        if self
+            .invitations
+            .get(&wtxn, &id)
+            .context("Error accessing the db")?
+            .is_some()
+        {
+            return Ok(false);
+        }
+        self.invitations
+            .put(&mut wtxn, &id, &())
+            .context("error saving to the db")?;
+
+        wtxn.commit()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::group::download::DownloadState;
+    use crate::user::PrivateUser;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_download_queue_persists() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("heed.mdb");
+
+        let file = File::new_empty("test".into());
+        let hash = file.hash.clone();
+
+        let mut store = HeedGroupStore::new(&path).unwrap();
+        store.queue_download(QueuedDownload::new(file)).unwrap();
+        drop(store);
+
+        let mut store = HeedGroupStore::new(&path).unwrap();
+        let downloads = store.get_downloads().unwrap();
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(downloads[0].state, DownloadState::Pending);
+        assert!(store.get_download(&hash).unwrap().is_some());
+
+        store.remove_download(&hash).unwrap();
+        assert!(store.get_downloads().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_filetree() {
+        let dir = tempdir().unwrap();
+        let mut store = HeedGroupStore::new(dir.path().join("heed.mdb")).unwrap();
+
+        let (u1, _) = PrivateUser::new("u1").unwrap();
+        let (u2, _) = PrivateUser::new("u2").unwrap();
+        let u1 = u1.public_user().clone();
+        let u2 = u2.public_user().clone();
+
+        let shared = File::new_empty("shared".into());
+        let mut gone = File::new_empty("gone".into());
+        gone.hash = Hash::new(vec![1, 2, 3]);
+        store.add_file(&u1, shared.clone()).unwrap();
+        store.add_file(&u2, gone.clone()).unwrap();
+
+        let mut tree = FileTree::new();
+        tree.insert("shared", shared.clone()).unwrap();
+        store.set_filetree(&u2, tree, 7).unwrap();
+        assert_eq!(store.get_version(&u2).unwrap(), 7);
+
+        // Both have the shared file now, and nobody has the one u2 threw away
+        let file = store.get_file(shared.hash.clone()).unwrap().unwrap();
+        assert!(file.is_owned_by(&u1) && file.is_owned_by(&u2));
+        assert!(store.get_file(gone.hash).unwrap().is_none());
+
+        let tree = store.get_filetree(&u2).unwrap().unwrap();
+        assert_eq!(tree.iter().count(), 1);
+        assert!(tree.iter().all(|(_, f)| f.is_owned_by(&u1)));
+    }
+
+    #[test]
+    fn test_change_log() {
+        let dir = tempdir().unwrap();
+        let mut store = HeedGroupStore::new(dir.path().join("heed.mdb")).unwrap();
+
+        let (user, _) = PrivateUser::new("user").unwrap();
+        let user = user.public_user().clone();
+
+        let old = File::new_empty("old".into());
+        let mut new = old.clone();
+        new.path = "new".into();
+
+        store.add_file(&user, old.clone()).unwrap();
+        store.update_file(&user, &old, new.clone()).unwrap();
+        store.delete_file(&user, &new).unwrap();
+        // Not in the tree anymore, so nothing changes
+        store.delete_file(&user, &new).unwrap();
+
+        assert_eq!(store.get_version(&user).unwrap(), 3);
+        let changes = store.get_changes(&user, 1).unwrap().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(&changes[0], Change::Update { old: o, .. } if o.path == old.path));
+        assert!(matches!(&changes[1], Change::Delete(f) if f.path == new.path));
+
+        assert!(store.get_changes(&user, 4).unwrap().is_none());
+
+        // A new tree means we don't know what happened before it
+        store.set_filetree(&user, FileTree::new(), 3).unwrap();
+        assert!(store.get_changes(&user, 1).unwrap().is_none());
+        assert_eq!(store.get_changes(&user, 3).unwrap().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_store_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("heed.mdb");
+
+        let (user, _) = PrivateUser::new("user").unwrap();
+        let mut store = HeedGroupStore::new(&path).unwrap();
+        store
+            .add_file(user.public_user(), File::new_empty("test".into()))
+            .unwrap();
+
+        // Stores from before the format was recorded have trees nobody can read anymore
+        let mut wtxn = store.env.write_txn().unwrap();
+        store.format.delete(&mut wtxn, VERSION_KEY).unwrap();
+        wtxn.commit().unwrap();
+        drop(store);
+
+        let store = HeedGroupStore::new(&path).unwrap();
+        assert!(store.get_filetrees().unwrap().is_empty());
+
+        // We don't know what newer versions store
+        let mut wtxn = store.env.write_txn().unwrap();
+        store
+            .format
+            .put(&mut wtxn, VERSION_KEY, &(STORE_VERSION + 1))
+            .unwrap();
+        wtxn.commit().unwrap();
+        drop(store);
+
+        assert!(HeedGroupStore::new(&path).is_err());
+    }
 }
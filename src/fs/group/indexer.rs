@@ -0,0 +1,306 @@
+use crate::fs::file::File;
+use crate::fs::filetree::FileTree;
+use crate::fs::group::changelog::Change;
+use crate::fs::group::dspfsignore::DspfsIgnore;
+use crate::fs::group::store::SharedGroupStore;
+use crate::user::PublicUser;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::{fs, task};
+use walkdir::WalkDir;
+
+/// How many changes are collected before they are saved in one go
+const BATCH_SIZE: usize = 256;
+
+/// How far along indexing a group folder is
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexProgress {
+    /// Files found on disk
+    pub found: usize,
+    /// Files looked at so far
+    pub indexed: usize,
+    /// Files which had to be hashed, because they are new or their metadata changed
+    pub hashed: usize,
+    /// New or changed files
+    pub changed: usize,
+    /// Files which aren't there anymore
+    pub removed: usize,
+}
+
+/// Makes the file tree of `user` match what is on disk at `path` (relative to the group `root`),
+/// which can be a file or a folder. New and changed files are hashed, and files which aren't
+/// there anymore or are ignored are removed. `progress` is called after every file.
+pub async fn index(
+    group_store: &SharedGroupStore,
+    user: &PublicUser,
+    root: &Path,
+    path: &Path,
+    mut progress: impl FnMut(&IndexProgress) + Send,
+) -> Result<IndexProgress> {
+    let mut state = IndexProgress::default();
+
+    // What we knew was there
+    let tree = group_store.read().await.get_filetree(user)?;
+    let mut known: HashMap<PathBuf, File> = tree
+        .as_ref()
+        .and_then(|tree| tree.find(path))
+        .map(|node| {
+            node.iter()
+                .map(|(_, f)| (f.path.clone(), f.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // What is there now
+    let on_disk = walk(root, path).await?;
+    state.found = on_disk.len();
+    progress(&state);
+
+    let mut batch = Vec::new();
+    let mut touched = Vec::new();
+    for relative in on_disk {
+        let old = known.remove(&relative);
+        match hash_change(root, relative.clone(), old).await {
+            Ok(Indexed::Unchanged) => (),
+            Ok(Indexed::Touched(file)) => {
+                state.hashed += 1;
+                touched.push(file);
+            }
+            Ok(Indexed::Changed(change)) => {
+                state.hashed += 1;
+                state.changed += 1;
+                batch.push(change);
+            }
+            // It might have disappeared in the meantime, or we can't read it
+            Err(e) => log::warn!("Couldn't index {:?}: {:?}", relative, e),
+        }
+
+        if batch.len() + touched.len() >= BATCH_SIZE {
+            let mut group_store = group_store.write().await;
+            group_store.apply_changes(user, batch.split_off(0))?;
+            group_store.update_metadata(user, touched.split_off(0))?;
+        }
+
+        state.indexed += 1;
+        progress(&state);
+    }
+
+    state.removed = known.len();
+    batch.extend(known.into_values().map(Change::Delete));
+    let mut group_store = group_store.write().await;
+    group_store.apply_changes(user, batch)?;
+    group_store.update_metadata(user, touched)?;
+    drop(group_store);
+    progress(&state);
+
+    Ok(state)
+}
+
+/// Lists all files at `path` (relative to `root`), skipping the `.dspfs` folder and everything
+/// which is ignored.
+async fn walk(root: &Path, path: &Path) -> Result<Vec<PathBuf>> {
+    let root = root.to_path_buf();
+    let start = root.join(path);
+
+    task::spawn_blocking(move || {
+        let mut ignore = DspfsIgnore::new(&root);
+
+        WalkDir::new(start)
+            .into_iter()
+            .filter_entry(|e| match e.path().strip_prefix(&root) {
+                Ok(relative) => !ignore.is_ignored(relative, e.file_type().is_dir()),
+                Err(_) => false,
+            })
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| e.path().strip_prefix(&root).ok().map(Path::to_path_buf))
+            .collect()
+    })
+    .await
+    .context("walking group folder failed")
+}
+
+/// How a file changed since it was indexed before
+pub(crate) enum Indexed {
+    /// Its metadata didn't change, so it wasn't hashed again
+    Unchanged,
+    /// It was hashed again, but only its metadata changed. This isn't a change others care
+    /// about, we only remember the new metadata to skip hashing it next time.
+    Touched(File),
+    Changed(Change),
+}
+
+/// Hashes the file at `relative`, and returns how it changed compared to `old` (what we knew
+/// about it before). Files of which the metadata didn't change aren't hashed again.
+pub(crate) async fn hash_change(
+    root: &Path,
+    relative: PathBuf,
+    old: Option<File>,
+) -> Result<Indexed> {
+    let absolute = root.join(&relative);
+
+    if let Some(old) = &old {
+        let metadata = fs::metadata(&absolute)
+            .await
+            .context("Couldn't access metadata")?;
+        if old.is_unchanged(&metadata) {
+            return Ok(Indexed::Unchanged);
+        }
+    }
+
+    let mut file = File::new(absolute)
+        .await
+        .context("Creating and indexing new file failed")?;
+    file.path = relative;
+
+    Ok(match old {
+        Some(old) if old.hash == file.hash => Indexed::Touched(file),
+        Some(old) => Indexed::Changed(Change::Update { old, new: file }),
+        None => Indexed::Changed(Change::Add(file)),
+    })
+}
+
+/// Finds the file we know of at `path` in a file tree
+pub(crate) fn known_file(tree: Option<FileTree>, path: &Path) -> Option<File> {
+    match tree?.find(path) {
+        Some(FileTree::Leaf { file, .. }) => Some(file.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::group::heed::HeedGroupStore;
+    use crate::fs::group::store::GroupStore;
+    use crate::user::PrivateUser;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use tokio::sync::RwLock;
+
+    fn test_store(root: &Path) -> (SharedGroupStore, PublicUser) {
+        std::fs::create_dir_all(root.join(".dspfs")).unwrap();
+        let store: Box<dyn GroupStore> =
+            Box::new(HeedGroupStore::new(root.join(".dspfs/heed.mdb")).unwrap());
+        let (user, _) = PrivateUser::new("user").unwrap();
+
+        (Arc::new(RwLock::new(store)), user.public_user().clone())
+    }
+
+    #[tokio::test]
+    async fn test_index() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let (store, user) = test_store(root);
+
+        // More than one batch
+        for i in 0..300 {
+            let folder = root.join(format!("{}", i % 10));
+            std::fs::create_dir_all(&folder).unwrap();
+            std::fs::write(folder.join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+
+        let mut calls = 0;
+        let progress = index(&store, &user, root, Path::new(""), |_| calls += 1)
+            .await
+            .unwrap();
+        assert_eq!(progress.found, 300);
+        assert_eq!(progress.changed, 300);
+        assert!(calls > 300);
+
+        let tree = store.read().await.get_filetree(&user).unwrap().unwrap();
+        assert_eq!(tree.iter().count(), 300);
+        assert!(tree.find("3/123.txt").is_some());
+        assert!(tree.find(".dspfs").is_none());
+
+        std::fs::remove_dir_all(root.join("0")).unwrap();
+        std::fs::write(root.join("1/1.txt"), b"changed").unwrap();
+
+        let progress = index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(progress.found, 270);
+        assert_eq!(progress.changed, 1);
+        assert_eq!(progress.removed, 30);
+
+        let tree = store.read().await.get_filetree(&user).unwrap().unwrap();
+        assert_eq!(tree.iter().count(), 270);
+    }
+
+    #[tokio::test]
+    async fn test_skip_unchanged() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let (store, user) = test_store(root);
+
+        std::fs::write(root.join("yeet.txt"), b"yeet").unwrap();
+        std::fs::write(root.join("yote.txt"), b"yote").unwrap();
+
+        let progress = index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(progress.hashed, 2);
+
+        let progress = index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(progress.hashed, 0);
+        assert_eq!(progress.changed, 0);
+
+        // Writing the file again changes its metadata, but not its contents
+        let version = store.read().await.get_version(&user).unwrap();
+        std::fs::write(root.join("yeet.txt"), b"yeet").unwrap();
+        let progress = index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(progress.hashed, 1);
+        assert_eq!(progress.changed, 0);
+        // Which nobody else needs to hear about
+        assert_eq!(store.read().await.get_version(&user).unwrap(), version);
+
+        let progress = index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(progress.hashed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_index_ignored() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let (store, user) = test_store(root);
+
+        std::fs::create_dir_all(root.join("node_modules/yeet")).unwrap();
+        std::fs::write(root.join("node_modules/yeet/yeet.js"), b"yeet").unwrap();
+        std::fs::write(root.join("yeet.txt"), b"yeet").unwrap();
+        std::fs::write(root.join("yeet.txt.swp"), b"yeet").unwrap();
+
+        index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .read()
+                .await
+                .get_filetree(&user)
+                .unwrap()
+                .unwrap()
+                .iter()
+                .count(),
+            3
+        );
+
+        std::fs::write(root.join(".dspfsignore"), "node_modules\n*.swp\n").unwrap();
+        let progress = index(&store, &user, root, Path::new(""), |_| ())
+            .await
+            .unwrap();
+        assert_eq!(progress.removed, 2);
+
+        let tree = store.read().await.get_filetree(&user).unwrap().unwrap();
+        assert!(tree.find("yeet.txt").is_some());
+        assert!(tree.find(".dspfsignore").is_some());
+        assert!(tree.find("node_modules/yeet/yeet.js").is_none());
+        assert!(tree.find("yeet.txt.swp").is_none());
+    }
+}
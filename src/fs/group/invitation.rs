@@ -0,0 +1,165 @@
+use crate::user::{PrivateUser, PublicKey};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// What an invitation says. This is the part which is signed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+struct InvitationContent {
+    /// Every invitation can only be redeemed once, the inviter remembers which ids were
+    id: Uuid,
+    groupuuid: Uuid,
+    inviter: PublicKey,
+    /// Seconds since the unix epoch after which the invitation can't be used anymore
+    expires: u64,
+    /// Where the invitee can find the inviter to redeem the invitation with
+    hints: Vec<SocketAddr>,
+}
+
+/// An invitation for someone to join a group, made and signed by an admin of that group.
+/// The invitee redeems it with the inviter, who then adds them to the group. It can be redeemed
+/// only once.
+///
+/// Invitations can be passed around as a compact string, see [Invitation::encode].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Invitation {
+    content: InvitationContent,
+    signature: Vec<u8>,
+}
+
+impl Invitation {
+    /// Invites someone to the group with uuid `groupuuid` on behalf of `inviter`. The invitation
+    /// can be redeemed for `valid_for`, with the inviter at the addresses in `hints`.
+    pub fn new(
+        groupuuid: Uuid,
+        inviter: &PrivateUser,
+        valid_for: Duration,
+        hints: Vec<SocketAddr>,
+    ) -> Result<Self> {
+        let content = InvitationContent {
+            id: Uuid::new_v4(),
+            groupuuid,
+            inviter: inviter.get_public_key().clone(),
+            expires: (now()? + valid_for).as_secs(),
+            hints,
+        };
+
+        let bytes = bincode::serialize(&content).context("failed to serialize invitation")?;
+        let signature = inviter.get_keypair().sign(&bytes).as_ref().to_vec();
+
+        Ok(Self { content, signature })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.content.id
+    }
+
+    pub fn groupuuid(&self) -> Uuid {
+        self.content.groupuuid
+    }
+
+    pub fn inviter(&self) -> &PublicKey {
+        &self.content.inviter
+    }
+
+    pub fn hints(&self) -> &[SocketAddr] {
+        &self.content.hints
+    }
+
+    /// Checks that the invitation was signed by the inviter, and that it didn't expire.
+    /// This doesn't check whether the inviter is actually a member of the group.
+    pub fn verify(&self) -> Result<()> {
+        let bytes = bincode::serialize(&self.content).context("failed to serialize invitation")?;
+        self.content
+            .inviter
+            .ring()
+            .verify(&bytes, &self.signature)
+            .map_err(|_| anyhow::anyhow!("invitation has an invalid signature"))?;
+
+        if now()?.as_secs() > self.content.expires {
+            return Err(anyhow::anyhow!("invitation expired"));
+        }
+
+        Ok(())
+    }
+
+    /// Turns the invitation into a string which is easy to copy around
+    pub fn encode(&self) -> Result<String> {
+        let bytes = bincode::serialize(self).context("failed to serialize invitation")?;
+        Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Reads an invitation made by [Invitation::encode]
+    pub fn decode(s: &str) -> Result<Self> {
+        let bytes = base64::decode_config(s.trim(), base64::URL_SAFE_NO_PAD)
+            .context("invitation is not valid base64")?;
+        bincode::deserialize(&bytes).context("invalid invitation")
+    }
+}
+
+impl fmt::Display for Invitation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = self.encode().map_err(|_| fmt::Error)?;
+        f.write_str(&encoded)
+    }
+}
+
+impl FromStr for Invitation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::decode(s)
+    }
+}
+
+fn now() -> Result<Duration> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time is before the unix epoch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invitation_roundtrip() {
+        let (inviter, _) = PrivateUser::new("inviter").unwrap();
+        let hints = vec!["127.0.0.1:8123".parse().unwrap()];
+        let invitation =
+            Invitation::new(Uuid::new_v4(), &inviter, Duration::from_secs(60), hints).unwrap();
+
+        let encoded = invitation.to_string();
+        let decoded: Invitation = encoded.parse().unwrap();
+        assert_eq!(decoded, invitation);
+        assert!(decoded.verify().is_ok());
+        assert_eq!(decoded.inviter(), inviter.get_public_key());
+    }
+
+    #[test]
+    fn test_invitation_tampered() {
+        let (inviter, _) = PrivateUser::new("inviter").unwrap();
+        let mut invitation =
+            Invitation::new(Uuid::new_v4(), &inviter, Duration::from_secs(60), vec![]).unwrap();
+
+        invitation.content.groupuuid = Uuid::new_v4();
+        assert!(invitation.verify().is_err());
+    }
+
+    #[test]
+    fn test_invitation_expired() {
+        let (inviter, _) = PrivateUser::new("inviter").unwrap();
+        let mut invitation =
+            Invitation::new(Uuid::new_v4(), &inviter, Duration::from_secs(0), vec![]).unwrap();
+        invitation.content.expires -= 1;
+
+        // Signed again, so only the expiry is wrong
+        let bytes = bincode::serialize(&invitation.content).unwrap();
+        invitation.signature = inviter.get_keypair().sign(&bytes).as_ref().to_vec();
+        assert!(invitation.verify().is_err());
+    }
+}
@@ -0,0 +1,671 @@
+use crate::fs::hash::Hash;
+use crate::user::{PrivateUser, PublicKey, PublicUser};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// What a member of a group is allowed to do
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+pub enum Role {
+    /// Can also invite and remove members, and change roles
+    Admin,
+    /// Shares files with the rest of the group
+    #[default]
+    Member,
+    /// Can only download. Whatever they change is never taken over by others.
+    ReadOnly,
+}
+
+/// A change to who is in a group. Only admins can add and remove members and change roles,
+/// but anyone can rename or remove themselves.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum MembershipChange {
+    /// Adds someone with the default role
+    Add(PublicUser),
+    Remove(PublicKey),
+    Rename {
+        key: PublicKey,
+        username: String,
+    },
+    SetRole {
+        key: PublicKey,
+        role: Role,
+    },
+}
+
+/// A change to who is in a group, made by one of its members
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MembershipOp {
+    pub groupuuid: Uuid,
+    /// The ids of the latest ops the author knew of, which this op comes after. The log starts
+    /// with a single op without parents, in which the founder of the group adds themselves as
+    /// admin.
+    pub parents: Vec<Hash>,
+    pub author: PublicKey,
+    pub change: MembershipChange,
+}
+
+/// A [MembershipOp], signed by its author
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SignedMembershipOp {
+    op: MembershipOp,
+    signature: Vec<u8>,
+}
+
+impl SignedMembershipOp {
+    pub fn new(
+        groupuuid: Uuid,
+        parents: Vec<Hash>,
+        change: MembershipChange,
+        author: &PrivateUser,
+    ) -> Result<Self> {
+        let op = MembershipOp {
+            groupuuid,
+            parents,
+            author: author.get_public_key().clone(),
+            change,
+        };
+
+        let bytes = bincode::serialize(&op).context("failed to serialize membership op")?;
+        let signature = author.get_keypair().sign(&bytes).as_ref().to_vec();
+
+        Ok(Self { op, signature })
+    }
+
+    pub fn op(&self) -> &MembershipOp {
+        &self.op
+    }
+
+    /// Identifies this op
+    pub fn id(&self) -> Result<Hash> {
+        let bytes = bincode::serialize(self).context("failed to serialize membership op")?;
+        Ok(Hash::new(blake3::hash(&bytes).as_bytes().to_vec()))
+    }
+
+    /// Checks that the op was signed by its author
+    pub fn verify(&self) -> Result<()> {
+        let bytes = bincode::serialize(&self.op).context("failed to serialize membership op")?;
+        self.op
+            .author
+            .ring()
+            .verify(&bytes, &self.signature)
+            .map_err(|_| anyhow::anyhow!("membership op has an invalid signature"))
+    }
+}
+
+/// Every membership change made in a group. Each op names the ops it comes after, so the log is
+/// ordered by what its authors knew when they made their changes. Replaying the log in that order
+/// gives the members of the group and their roles, where changes made by someone who wasn't
+/// allowed to make them at that point are skipped. Removing someone or taking away their role
+/// also undoes what they did without knowing about it, so nobody can get around it by making
+/// changes which claim to come from before. When admins remove each other without knowing about
+/// it, whoever became admin first wins.
+/// Everyone who has the same ops therefore agrees on who is in the group.
+#[derive(Debug, Default)]
+pub struct MembershipLog {
+    /// Every op comes after its parents, and ops which don't come after each other are ordered
+    /// by id
+    ops: Vec<(Hash, SignedMembershipOp)>,
+    /// For every op, the indices of all ops it comes after
+    ancestors: Vec<HashSet<usize>>,
+}
+
+impl MembershipLog {
+    /// Creates the log from ops in any order. Ops of which the parents are missing are left out.
+    pub fn new(ops: Vec<SignedMembershipOp>) -> Result<Self> {
+        let ops = ops
+            .into_iter()
+            .map(|op| Ok((op.id()?, op)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self::ordered(ops))
+    }
+
+    /// Orders ops so every op comes after its parents. When several ops could come next, the
+    /// one with the lowest id goes first.
+    fn ordered(ops: Vec<(Hash, SignedMembershipOp)>) -> Self {
+        let mut waiting: HashMap<Hash, SignedMembershipOp> = ops.into_iter().collect();
+        let mut log = Self::default();
+        let mut indices: HashMap<Hash, usize> = HashMap::new();
+
+        loop {
+            let next = waiting
+                .iter()
+                .filter(|(_, op)| op.op.parents.iter().all(|p| indices.contains_key(p)))
+                .map(|(id, _)| id)
+                .min_by(|a, b| a.bytes().cmp(b.bytes()))
+                .cloned();
+            let id = match next {
+                Some(id) => id,
+                None => break,
+            };
+            let op = waiting.remove(&id).expect("op was just found");
+
+            let mut ancestors = HashSet::new();
+            for parent in &op.op.parents {
+                let parent = indices[parent];
+                ancestors.insert(parent);
+                ancestors.extend(&log.ancestors[parent]);
+            }
+
+            indices.insert(id.clone(), log.ops.len());
+            log.ops.push((id, op));
+            log.ancestors.push(ancestors);
+        }
+
+        log
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> impl Iterator<Item = &SignedMembershipOp> {
+        self.ops.iter().map(|(_, op)| op)
+    }
+
+    pub fn contains(&self, id: &Hash) -> bool {
+        self.ops.iter().any(|(i, _)| i == id)
+    }
+
+    /// The ids of the latest ops, which an op made now comes after
+    pub fn heads(&self) -> Vec<Hash> {
+        let mut parents = HashSet::new();
+        for (_, op) in &self.ops {
+            parents.extend(op.op.parents.iter());
+        }
+
+        self.ops
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !parents.contains(id))
+            .cloned()
+            .collect()
+    }
+
+    /// The indices of all ops in the log which `op` comes after
+    fn ancestors_of(&self, op: &SignedMembershipOp) -> Result<HashSet<usize>> {
+        let mut ancestors = HashSet::new();
+        for parent in &op.op.parents {
+            let index = self
+                .ops
+                .iter()
+                .position(|(id, _)| id == parent)
+                .context("membership op comes after ops we don't have")?;
+            ancestors.insert(index);
+            ancestors.extend(&self.ancestors[index]);
+        }
+
+        Ok(ancestors)
+    }
+
+    /// Checks an op someone sent us before it is added to the log: it must be signed by its
+    /// author, be about the group with uuid `groupuuid`, and can't start the log over.
+    /// Otherwise, we need to have its parents, it has to come after all earlier ops of its author,
+    /// and its author has to be a member at that point.
+    pub fn check(&self, groupuuid: Uuid, op: &SignedMembershipOp) -> Result<()> {
+        op.verify()?;
+
+        if op.op.groupuuid != groupuuid {
+            return Err(anyhow::anyhow!("membership op is for another group"));
+        }
+
+        if op.op.parents.is_empty() {
+            match &op.op.change {
+                MembershipChange::Add(user) if *user.get_public_key() == op.op.author => (),
+                _ => return Err(anyhow::anyhow!("log has to start with the founder")),
+            }
+            if let Some((_, genesis)) = self.ops.first() {
+                if genesis != op {
+                    return Err(anyhow::anyhow!("membership log was already started"));
+                }
+            }
+            return Ok(());
+        }
+
+        let ancestors = self.ancestors_of(op)?;
+        let earlier = self
+            .ops
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, o))| o.op.author == op.op.author)
+            .all(|(index, _)| ancestors.contains(&index));
+        if !earlier {
+            return Err(anyhow::anyhow!(
+                "membership op doesn't come after earlier ops of its author"
+            ));
+        }
+
+        let valid = self.valid();
+        let mut members = Vec::new();
+        for index in (0..self.ops.len()).filter(|i| valid[*i] && ancestors.contains(i)) {
+            Self::apply(&mut members, &self.ops[index].1.op);
+        }
+        if !members
+            .iter()
+            .any(|(u, _)| *u.get_public_key() == op.op.author)
+        {
+            return Err(anyhow::anyhow!(
+                "author of membership op isn't a member at that point"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks an op (see [MembershipLog::check]) and adds it to the log. Returns false if it was
+    /// already in the log.
+    pub fn insert(&mut self, groupuuid: Uuid, op: SignedMembershipOp) -> Result<bool> {
+        let id = op.id()?;
+        if self.contains(&id) {
+            return Ok(false);
+        }
+        self.check(groupuuid, &op)?;
+
+        let mut ops = std::mem::take(&mut self.ops);
+        ops.push((id, op));
+        *self = Self::ordered(ops);
+
+        Ok(true)
+    }
+
+    /// Replays the log to find out who is in the group
+    pub fn members(&self) -> Vec<PublicUser> {
+        self.replay().into_iter().map(|(user, _)| user).collect()
+    }
+
+    /// Replays the log to find out who is in the group, and what they are allowed to do
+    pub fn replay(&self) -> Vec<(PublicUser, Role)> {
+        let mut members = Vec::new();
+        for ((_, op), valid) in self.ops.iter().zip(self.valid()) {
+            if valid {
+                Self::apply(&mut members, &op.op);
+            }
+        }
+
+        members
+    }
+
+    /// Finds out which ops count. An op counts if its author was allowed to make it, judging by
+    /// the ops it comes after, and by the removals and role changes made without knowing about
+    /// it. The latter only count when their authors were allowed to make them by the ops they
+    /// come after, and became admin before the author of the op did. Otherwise two admins who
+    /// remove each other would cancel each other out, and anyone who is removed could undo that
+    /// by removing whoever removed them.
+    fn valid(&self) -> Vec<bool> {
+        let allowed = self.allowed_by(|_, _| false);
+        let seniority = self.seniority(&allowed);
+        let rank = |index: usize| {
+            let author = &self.ops[index].1.op.author;
+            seniority
+                .iter()
+                .find(|(key, _)| key == author)
+                .map_or(usize::MAX, |(_, rank)| *rank)
+        };
+
+        self.allowed_by(|index, other| {
+            allowed[other]
+                && !self.ancestors[index].contains(&other)
+                && !self.ancestors[other].contains(&index)
+                && rank(other) <= rank(index)
+        })
+    }
+
+    /// For everyone who was made admin at some point, the index of the first op which did
+    /// that. The founder comes first.
+    fn seniority(&self, allowed: &[bool]) -> Vec<(PublicKey, usize)> {
+        let mut seniority: Vec<(PublicKey, usize)> = Vec::new();
+        for (index, (_, op)) in self.ops.iter().enumerate().filter(|(i, _)| allowed[*i]) {
+            let admin = match &op.op.change {
+                MembershipChange::Add(user) if op.op.parents.is_empty() => user.get_public_key(),
+                MembershipChange::SetRole {
+                    key,
+                    role: Role::Admin,
+                } => key,
+                _ => continue,
+            };
+            if !seniority.iter().any(|(key, _)| key == admin) {
+                seniority.push((admin.clone(), index));
+            }
+        }
+
+        seniority
+    }
+
+    /// Replays the ops each op comes after, together with the ops for which `concurrent` returns
+    /// true (of which only the removals and role changes are used), to find out whether the
+    /// author of each op was allowed to make it.
+    fn allowed_by(&self, concurrent: impl Fn(usize, usize) -> bool) -> Vec<bool> {
+        let mut valid: Vec<bool> = Vec::with_capacity(self.ops.len());
+
+        for (index, (_, op)) in self.ops.iter().enumerate() {
+            let mut members = Vec::new();
+            for before in (0..index).filter(|i| valid[*i] && self.ancestors[index].contains(i)) {
+                Self::apply(&mut members, &self.ops[before].1.op);
+            }
+            for other in (0..self.ops.len()).filter(|other| concurrent(index, *other)) {
+                let other = &self.ops[other].1.op;
+                if Self::revokes(other, &op.op.author) {
+                    Self::apply(&mut members, other);
+                }
+            }
+
+            valid.push(Self::apply(&mut members, &op.op));
+        }
+
+        valid
+    }
+
+    /// Whether `op` removes `key`, or makes them something else than admin
+    fn revokes(op: &MembershipOp, key: &PublicKey) -> bool {
+        match &op.change {
+            MembershipChange::Remove(k) => k == key,
+            MembershipChange::SetRole { key: k, role } => k == key && *role != Role::Admin,
+            _ => false,
+        }
+    }
+
+    /// Applies an op to the members of the group, if its author is allowed to make it. Returns
+    /// whether it was allowed.
+    fn apply(members: &mut Vec<(PublicUser, Role)>, op: &MembershipOp) -> bool {
+        if op.parents.is_empty() {
+            // Only the first start of the log counts
+            return match (members.is_empty(), &op.change) {
+                (true, MembershipChange::Add(user)) if *user.get_public_key() == op.author => {
+                    members.push((user.clone(), Role::Admin));
+                    true
+                }
+                _ => false,
+            };
+        }
+
+        let admin = match members
+            .iter()
+            .find(|(u, _)| *u.get_public_key() == op.author)
+        {
+            Some((_, role)) => *role == Role::Admin,
+            None => return false,
+        };
+
+        match &op.change {
+            MembershipChange::Add(user) if admin => {
+                if !members.iter().any(|(u, _)| u == user) {
+                    members.push((user.clone(), Role::default()));
+                }
+            }
+            MembershipChange::Remove(key) if admin || *key == op.author => {
+                members.retain(|(u, _)| u.get_public_key() != key)
+            }
+            MembershipChange::Rename { key, username } if admin || *key == op.author => {
+                if let Some((user, _)) = members.iter_mut().find(|(u, _)| u.get_public_key() == key)
+                {
+                    user.set_username(username);
+                }
+            }
+            MembershipChange::SetRole { key, role } if admin => {
+                if let Some((_, r)) = members.iter_mut().find(|(u, _)| u.get_public_key() == key) {
+                    *r = *role;
+                }
+            }
+            // Not allowed
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(
+        parents: &[&SignedMembershipOp],
+        change: MembershipChange,
+        author: &PrivateUser,
+    ) -> SignedMembershipOp {
+        let parents = parents.iter().map(|op| op.id().unwrap()).collect();
+        SignedMembershipOp::new(Uuid::nil(), parents, change, author).unwrap()
+    }
+
+    fn key(user: &PrivateUser) -> PublicKey {
+        user.get_public_key().clone()
+    }
+
+    #[test]
+    fn test_members() {
+        let (u1, _) = PrivateUser::new("u1").unwrap();
+        let (u2, _) = PrivateUser::new("u2").unwrap();
+        let (u3, _) = PrivateUser::new("u3").unwrap();
+
+        let genesis = signed(&[], MembershipChange::Add(u1.public_user().clone()), &u1);
+        let add = signed(
+            &[&genesis],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u1,
+        );
+        let promote = signed(
+            &[&add],
+            MembershipChange::SetRole {
+                key: key(&u2),
+                role: Role::Admin,
+            },
+            &u1,
+        );
+        let rename = signed(
+            &[&promote],
+            MembershipChange::Rename {
+                key: key(&u2),
+                username: "yeet".into(),
+            },
+            &u2,
+        );
+        let remove = signed(&[&rename], MembershipChange::Remove(key(&u1)), &u2);
+        // u1 isn't a member anymore by now
+        let late = signed(
+            &[&remove],
+            MembershipChange::Add(u3.public_user().clone()),
+            &u1,
+        );
+
+        let ops = vec![remove, genesis, add, promote, rename, late];
+
+        // The order in which ops arrive doesn't matter
+        for ops in [ops.clone(), ops.into_iter().rev().collect()] {
+            let members = MembershipLog::new(ops).unwrap().members();
+            assert_eq!(members, vec![u2.public_user().clone()]);
+            assert_eq!(members[0].get_username(), "yeet");
+        }
+    }
+
+    #[test]
+    fn test_roles() {
+        let (u1, _) = PrivateUser::new("u1").unwrap();
+        let (u2, _) = PrivateUser::new("u2").unwrap();
+        let (u3, _) = PrivateUser::new("u3").unwrap();
+
+        let genesis = signed(&[], MembershipChange::Add(u1.public_user().clone()), &u1);
+        let add = signed(
+            &[&genesis],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u1,
+        );
+        // Members can't add people, or make themselves admin
+        let invite = signed(
+            &[&add],
+            MembershipChange::Add(u3.public_user().clone()),
+            &u2,
+        );
+        let promote = signed(
+            &[&invite],
+            MembershipChange::SetRole {
+                key: key(&u2),
+                role: Role::Admin,
+            },
+            &u2,
+        );
+        let demote = signed(
+            &[&promote],
+            MembershipChange::SetRole {
+                key: key(&u2),
+                role: Role::ReadOnly,
+            },
+            &u1,
+        );
+        // Or remove others
+        let remove = signed(&[&demote], MembershipChange::Remove(key(&u1)), &u2);
+
+        let log = MembershipLog::new(vec![genesis, add, invite, promote, demote, remove]).unwrap();
+        assert_eq!(
+            log.replay(),
+            vec![
+                (u1.public_user().clone(), Role::Admin),
+                (u2.public_user().clone(), Role::ReadOnly)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let (u1, _) = PrivateUser::new("u1").unwrap();
+        let (u2, _) = PrivateUser::new("u2").unwrap();
+
+        let genesis = signed(&[], MembershipChange::Add(u1.public_user().clone()), &u1);
+        let log = MembershipLog::new(vec![genesis.clone()]).unwrap();
+        assert!(log.check(Uuid::nil(), &genesis).is_ok());
+        assert!(log.check(Uuid::new_v4(), &genesis).is_err());
+
+        // Nobody can start the log over
+        let other = signed(&[], MembershipChange::Add(u2.public_user().clone()), &u2);
+        assert!(log.check(Uuid::nil(), &other).is_err());
+
+        // Or make changes without being added
+        let sneaky = signed(
+            &[&genesis],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u2,
+        );
+        assert!(log.check(Uuid::nil(), &sneaky).is_err());
+
+        // We need to know what an op comes after
+        let orphan = signed(
+            &[&other],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u1,
+        );
+        assert!(log.check(Uuid::nil(), &orphan).is_err());
+
+        let mut forged = signed(
+            &[&genesis],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u1,
+        );
+        assert!(log.check(Uuid::nil(), &forged).is_ok());
+        forged.op.parents.clear();
+        assert!(log.check(Uuid::nil(), &forged).is_err());
+    }
+
+    #[test]
+    fn test_backdating() {
+        let (u1, _) = PrivateUser::new("u1").unwrap();
+        let (u2, _) = PrivateUser::new("u2").unwrap();
+        let (u3, _) = PrivateUser::new("u3").unwrap();
+
+        let genesis = signed(&[], MembershipChange::Add(u1.public_user().clone()), &u1);
+        let add = signed(
+            &[&genesis],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u1,
+        );
+        let promote = signed(
+            &[&add],
+            MembershipChange::SetRole {
+                key: key(&u2),
+                role: Role::Admin,
+            },
+            &u1,
+        );
+        let rename = signed(
+            &[&promote],
+            MembershipChange::Rename {
+                key: key(&u2),
+                username: "yeet".into(),
+            },
+            &u2,
+        );
+        let remove = signed(&[&rename], MembershipChange::Remove(key(&u2)), &u1);
+        let mut log =
+            MembershipLog::new(vec![genesis, add.clone(), promote, rename.clone(), remove])
+                .unwrap();
+        assert_eq!(log.members(), vec![u1.public_user().clone()]);
+
+        // After being removed, u2 claims to have done things while still being an admin
+        let invite = signed(
+            &[&rename],
+            MembershipChange::Add(u3.public_user().clone()),
+            &u2,
+        );
+        let coup = signed(&[&invite], MembershipChange::Remove(key(&u1)), &u2);
+        assert!(log.insert(Uuid::nil(), invite).unwrap());
+        assert!(log.insert(Uuid::nil(), coup).unwrap());
+        assert_eq!(log.members(), vec![u1.public_user().clone()]);
+
+        // Or from before what they did last
+        let earlier = signed(
+            &[&add],
+            MembershipChange::Add(u3.public_user().clone()),
+            &u2,
+        );
+        assert!(log.check(Uuid::nil(), &earlier).is_err());
+    }
+
+    #[test]
+    fn test_removed_member() {
+        let (u1, _) = PrivateUser::new("u1").unwrap();
+        let (u2, _) = PrivateUser::new("u2").unwrap();
+        let (u3, _) = PrivateUser::new("u3").unwrap();
+
+        let genesis = signed(&[], MembershipChange::Add(u1.public_user().clone()), &u1);
+        let add = signed(
+            &[&genesis],
+            MembershipChange::Add(u2.public_user().clone()),
+            &u1,
+        );
+        let promote = signed(
+            &[&add],
+            MembershipChange::SetRole {
+                key: key(&u2),
+                role: Role::Admin,
+            },
+            &u1,
+        );
+        let remove = signed(&[&promote], MembershipChange::Remove(key(&u2)), &u1);
+        let log = MembershipLog::new(vec![genesis.clone(), add, promote, remove.clone()]).unwrap();
+
+        // Knowing they were removed, they can't do anything anymore
+        let invite = signed(
+            &[&remove],
+            MembershipChange::Add(u3.public_user().clone()),
+            &u2,
+        );
+        let rename = signed(
+            &[&remove],
+            MembershipChange::Rename {
+                key: key(&u2),
+                username: "yeet".into(),
+            },
+            &u2,
+        );
+        for op in [&invite, &rename] {
+            assert!(log.check(Uuid::nil(), op).is_err());
+        }
+
+        // Not even when we get their ops some other way
+        let ops: Vec<_> = log.ops().cloned().chain(vec![invite, rename]).collect();
+        assert_eq!(
+            MembershipLog::new(ops).unwrap().replay(),
+            vec![(u1.public_user().clone(), Role::Admin)]
+        );
+    }
+}
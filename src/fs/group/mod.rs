@@ -1,23 +1,49 @@
+pub mod changelog;
+pub mod download;
+pub mod dspfsignore;
 mod heed;
+pub mod indexer;
+pub mod invitation;
+pub mod membership;
+pub mod partial;
+pub mod selection;
 mod store;
+pub mod watcher;
 
+use crate::dspfs::client::Client;
 use crate::fs::file::File;
+use crate::fs::filetree::{is_safe_path, normalize_path, FileTree, NodeSummary};
+use crate::fs::group::changelog::{Change, FileTreeUpdate};
+use crate::fs::group::download::{BlockSource, DownloadReport, DownloadState, QueuedDownload};
+use crate::fs::group::dspfsignore::{DspfsIgnore, IGNORE_FILE};
 use crate::fs::group::heed::HeedGroupStore;
+use crate::fs::group::indexer::{IndexProgress, Indexed};
+use crate::fs::group::invitation::Invitation;
+use crate::fs::group::membership::{MembershipChange, MembershipLog, Role, SignedMembershipOp};
+use crate::fs::group::partial::PartialFile;
+use crate::fs::group::selection::{Selection, SelectionRule};
 use crate::fs::group::store::{GroupStore, SharedGroupStore};
+use crate::fs::group::watcher::GroupWatcher;
 use crate::fs::hash::Hash;
 use crate::global_store::{SharedStore, Store};
-use crate::user::PublicUser;
+use crate::user::{PrivateUser, PublicKey, PublicUser};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::io::SeekFrom;
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, SeekFrom};
+use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Take};
 use tokio::sync::RwLock;
+use tokio::time::{timeout, Duration};
 use uuid::Uuid;
 
+/// How long we try to connect to another member before giving up on them for a download.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// A *StoredGroup* is a reduced version of a [Group], which can safely be stored in a database.
 /// For documentation on what a DSPFS *Group* is, refer to the documentation of [Group].
 /// A stored group can be *reloaded* to allow it to be used as a regular [Group] again. Only regular
@@ -25,11 +51,17 @@ use uuid::Uuid;
 /// database to be available, and these actions are therefore only available on loaded [Group]s.
 ///
 /// All operations supported on *StoredGroup*s are also available on loaded [Group]s.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct StoredGroup {
     pub uuid: Uuid,
     pub users: Vec<PublicUser>,
     pub location: PathBuf,
+    /// Which files of other members we want to have locally
+    pub selection: Selection,
+    /// When enabled, we want every file any member has, regardless of the selection
+    pub autodownload: bool,
+    /// What each member is allowed to do, which follows from the membership log
+    pub roles: HashMap<PublicUser, Role>,
 }
 
 impl StoredGroup {
@@ -41,9 +73,24 @@ impl StoredGroup {
             uuid: Uuid::new_v4(),
             users: Vec::new(),
             location: path.as_ref().to_path_buf(),
+            selection: Selection::default(),
+            autodownload: false,
+            roles: HashMap::new(),
         }
     }
 
+    /// Imports the group of an existing dspfs folder at `path`, for example one which was moved
+    /// here from another computer. The group keeps its uuid, members and selection.
+    pub fn import(path: impl AsRef<Path>) -> Result<Self> {
+        let dspfs_folder = path.as_ref().join(".dspfs");
+        let mut group = open_db(&dspfs_folder)?
+            .get_group()?
+            .context("the .dspfs folder doesn't say which group it belongs to")?;
+
+        group.location = path.as_ref().to_path_buf();
+        Ok(group)
+    }
+
     /// Creates a new full group from a stored group
     /// TODO: cache
     pub fn reload<S: Store>(self, global_store: SharedStore<S>) -> Result<Group<S>> {
@@ -53,6 +100,15 @@ impl StoredGroup {
     pub fn dspfs_folder(&self) -> PathBuf {
         self.location.join(".dspfs")
     }
+
+    /// What a member of this group is allowed to do. None if they aren't a member.
+    pub fn role(&self, user: &PublicUser) -> Option<Role> {
+        if self.users.contains(user) {
+            Some(self.roles.get(user).copied().unwrap_or_default())
+        } else {
+            None
+        }
+    }
 }
 
 /// A Group is a structure which represents a folder on your computer which is shared through DSPFS.
@@ -75,8 +131,7 @@ impl StoredGroup {
 /// Warning: files you add to the dspfs group can be seen by other people in the group. This is the
 /// purpose of dspfs. However, make sure not to put information in a dspfs group you like to
 /// remain secret from other members in the group.
-/// TODO: It will be possible to exclude folders in this directory from being shared.
-#[derive(Clone)]
+/// Files and folders matching a `.dspfsignore` file (in gitignore syntax) are not shared.
 pub struct Group<S> {
     pub stored_group: StoredGroup,
 
@@ -84,60 +139,756 @@ pub struct Group<S> {
     global_store: SharedStore<S>,
 }
 
-impl<S: Store> Group<S> {
-    /// Opens the database in the database folder. Creates it if it didn't exist.
-    fn open_db(db_folder: impl AsRef<Path>) -> Result<Arc<RwLock<Box<dyn GroupStore>>>> {
-        enum DbType {
-            Heed,
+// Derived Clone would require S: Clone, but the stores are shared anyway
+impl<S> Clone for Group<S> {
+    fn clone(&self) -> Self {
+        Self {
+            stored_group: self.stored_group.clone(),
+            group_store: self.group_store.clone(),
+            global_store: self.global_store.clone(),
         }
+    }
+}
 
-        // Somehow detect db type automatically
-        let db_type = DbType::Heed;
-
-        Ok(Arc::new(RwLock::new(Box::new(match db_type {
-            DbType::Heed => HeedGroupStore::new(db_folder.as_ref().join("heed.mdb"))?,
-        }))))
+/// Opens the database in the database folder. Creates it if it didn't exist.
+fn open_db(db_folder: impl AsRef<Path>) -> Result<Box<dyn GroupStore>> {
+    enum DbType {
+        Heed,
     }
 
+    // Somehow detect db type automatically
+    let db_type = DbType::Heed;
+
+    Ok(Box::new(match db_type {
+        DbType::Heed => HeedGroupStore::new(db_folder.as_ref().join("heed.mdb"))?,
+    }))
+}
+
+impl<S: Store> Group<S> {
     fn from_stored(stored_group: StoredGroup, global_store: SharedStore<S>) -> Result<Self> {
+        let mut group_store = open_db(stored_group.dspfs_folder())?;
+
+        // Keep the .dspfs folder importable, also for groups made before it stored the group
+        if group_store.get_group()?.as_ref() != Some(&stored_group) {
+            group_store.set_group(&stored_group)?;
+        }
+
         Ok(Self {
-            group_store: Self::open_db(stored_group.dspfs_folder())?,
+            group_store: Arc::new(RwLock::new(group_store)),
             stored_group,
             global_store,
         })
     }
 
-    // /// Sets the filetree received from a user in the group
-    // async fn set_filetree(&mut self, user: &PublicUser, filetree: FileTree) -> Result<()>{
-    //     for i in filetree.iter() {
-    //         if let Some(f) = self.get_file_by_hash(i.hash)? {
-    //             // merge the sets in these files
-    //             let new = f.merge_users(i);
-    //             self.update_file(&f, new)?;
-    //         } else {
-    //             self.add_remote_file(i.clone())?;
-    //         }
-    //     }
-    //
-    //     self.store.read().await.set_filetree(user, filetree)
-    // }
+    /// Saves changes made to the [StoredGroup] part of this group to the global store, and to
+    /// the `.dspfs` folder of the group
+    pub async fn save(&self) -> Result<()> {
+        self.group_store
+            .write()
+            .await
+            .set_group(&self.stored_group)
+            .context("saving group failed")?;
+
+        self.global_store
+            .write()
+            .await
+            .update_group(&self.stored_group)
+            .context("saving group failed")
+    }
+
+    /// Adds a rule to the selection of files we want to have locally, see [Selection].
+    pub async fn select(&mut self, rule: SelectionRule) -> Result<()> {
+        self.selection.add(rule)?;
+        self.save().await
+    }
+
+    /// Removes a rule from the selection of files we want to have locally, see [Selection].
+    pub async fn unselect(&mut self, rule: &SelectionRule) -> Result<()> {
+        if self.selection.remove(rule) {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Enables or disables mirroring all files of all members of this group locally.
+    pub async fn set_autodownload(&mut self, enabled: bool) -> Result<()> {
+        if self.autodownload != enabled {
+            self.autodownload = enabled;
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Everyone in this group, including us
+    pub async fn members(&self) -> Result<Vec<PublicUser>> {
+        let mut members = self.users.clone();
+        let self_user = self.self_user().await?;
+        if !members.contains(&self_user) {
+            members.push(self_user);
+        }
+        Ok(members)
+    }
+
+    /// Gets the membership log of this group, see [MembershipLog]
+    pub async fn membership_log(&self) -> Result<MembershipLog> {
+        MembershipLog::new(self.group_store.read().await.get_membership_ops()?)
+    }
+
+    /// Starts the membership log of a group we just created, in which we add ourselves as
+    /// admin. Everyone else gets the log from a member when they join, as two logs which were
+    /// started separately never agree.
+    pub async fn start_membership_log(&mut self) -> Result<()> {
+        if !self.membership_log().await?.is_empty() {
+            return Err(anyhow::anyhow!("membership log was already started"));
+        }
+
+        let me = self.private_user().await?;
+        let genesis = SignedMembershipOp::new(
+            self.uuid,
+            Vec::new(),
+            MembershipChange::Add(me.public_user().clone()),
+            &me,
+        )?;
+        self.group_store
+            .write()
+            .await
+            .add_membership_ops(vec![genesis])?;
+
+        self.update_members().await
+    }
+
+    /// Records a membership change made by us, and updates who is in the group
+    async fn change_membership(&mut self, change: MembershipChange) -> Result<()> {
+        let log = self.membership_log().await?;
+        let me = self.private_user().await?;
+
+        let role = log
+            .replay()
+            .into_iter()
+            .find(|(u, _)| u == me.public_user())
+            .map(|(_, role)| role)
+            .context("we are not a member of this group")?;
+        let own = match &change {
+            MembershipChange::Remove(key) | MembershipChange::Rename { key, .. } => {
+                key == me.get_public_key()
+            }
+            _ => false,
+        };
+        if role != Role::Admin && !own {
+            return Err(anyhow::anyhow!("only admins can do that"));
+        }
+
+        let op = SignedMembershipOp::new(self.uuid, log.heads(), change, &me)?;
+        self.group_store
+            .write()
+            .await
+            .add_membership_ops(vec![op])?;
+
+        self.update_members().await
+    }
+
+    /// Adds membership ops another member sent us to our membership log, and updates who is in
+    /// the group. Ops which don't check out are skipped, see [MembershipLog::check].
+    pub async fn merge_membership(&mut self, mut ops: Vec<SignedMembershipOp>) -> Result<()> {
+        let mut log = self.membership_log().await?;
+
+        // Ops can only be added after the ops they come after, which might be further on
+        let mut new = Vec::new();
+        loop {
+            let before = ops.len();
+            ops.retain(|op| match log.insert(self.uuid, op.clone()) {
+                Ok(true) => {
+                    new.push(op.clone());
+                    false
+                }
+                Ok(false) => false,
+                Err(_) => true,
+            });
+            if ops.len() == before {
+                break;
+            }
+        }
+        for op in ops {
+            if let Err(e) = log.check(self.uuid, &op) {
+                log::warn!("Skipping membership op {:?}: {:?}", op, e);
+            }
+        }
+
+        if !new.is_empty() {
+            self.group_store.write().await.add_membership_ops(new)?;
+            self.update_members().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes `users` and `roles` match the membership log. Everything we know about people who
+    /// aren't members anymore, or who became read-only, is removed.
+    async fn update_members(&mut self) -> Result<()> {
+        let replayed = self.membership_log().await?.replay();
+        let roles: HashMap<PublicUser, Role> = replayed.iter().cloned().collect();
+        let mut members: Vec<PublicUser> = replayed.into_iter().map(|(u, _)| u).collect();
+
+        let self_user = self.self_user().await?;
+        for gone in self.users.iter().filter(|&u| {
+            *u != self_user
+                && self.role(u) != Some(Role::ReadOnly)
+                && !matches!(roles.get(u), Some(Role::Admin) | Some(Role::Member))
+        }) {
+            self.group_store.write().await.remove_user(gone)?;
+        }
+
+        // Where to find people is something we keep track of ourselves
+        for member in &mut members {
+            let known = self.users.iter().find(|&u| u == member);
+            if let Some(addr) = known.and_then(PublicUser::get_last_addr) {
+                member.set_last_addr(addr);
+            }
+        }
+
+        let changed = members.len() != self.users.len()
+            || members
+                .iter()
+                .zip(&self.users)
+                .any(|(a, b)| a != b || a.get_username() != b.get_username())
+            || roles != self.roles;
+        if changed {
+            self.users = members;
+            self.roles = roles;
+            self.save().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds someone to this group. Returns false if they were a member already.
+    pub async fn add_member(&mut self, user: PublicUser) -> Result<bool> {
+        if self.users.contains(&user) {
+            return Ok(false);
+        }
+
+        self.change_membership(MembershipChange::Add(user)).await?;
+        Ok(true)
+    }
+
+    /// Removes someone from this group. Once other members hear about it, they won't share any
+    /// files with them anymore.
+    pub async fn remove_member(&mut self, user: &PublicUser) -> Result<()> {
+        if !self.users.contains(user) {
+            return Err(anyhow::anyhow!("not a member of this group"));
+        }
+
+        self.change_membership(MembershipChange::Remove(user.get_public_key().clone()))
+            .await
+    }
+
+    /// Changes what a member is allowed to do. Only admins can do this.
+    pub async fn set_role(&mut self, user: &PublicUser, role: Role) -> Result<()> {
+        if !self.users.contains(user) {
+            return Err(anyhow::anyhow!("not a member of this group"));
+        }
+
+        self.change_membership(MembershipChange::SetRole {
+            key: user.get_public_key().clone(),
+            role,
+        })
+        .await
+    }
+
+    /// Changes the name everyone in the group knows a member by
+    pub async fn rename_member(&mut self, user: &PublicUser, username: &str) -> Result<()> {
+        if !self.users.contains(user) {
+            return Err(anyhow::anyhow!("not a member of this group"));
+        }
+
+        self.change_membership(MembershipChange::Rename {
+            key: user.get_public_key().clone(),
+            username: username.into(),
+        })
+        .await
+    }
+
+    /// Invites someone to this group, see [Invitation]. `hints` are the addresses at which the
+    /// invitee can reach us to redeem it. Only admins can invite people.
+    pub async fn invite(
+        &mut self,
+        valid_for: Duration,
+        hints: Vec<SocketAddr>,
+    ) -> Result<Invitation> {
+        let me = self.private_user().await?;
+        if !self.is_admin(me.get_public_key()).await? {
+            return Err(anyhow::anyhow!("only admins can invite people"));
+        }
+
+        Invitation::new(self.uuid, &me, valid_for, hints)
+    }
+
+    /// Whether the member with this key is an admin, according to the membership log
+    async fn is_admin(&mut self, key: &PublicKey) -> Result<bool> {
+        Ok(self
+            .membership_log()
+            .await?
+            .replay()
+            .iter()
+            .any(|(u, role)| u.get_public_key() == key && *role == Role::Admin))
+    }
+
+    /// Adds `invitee` to this group, if the invitation they sent us is for this group, isn't
+    /// expired, and was made by us while we're an admin. Only admins can let people in, and
+    /// every invitation only lets one person in.
+    pub async fn redeem_invitation(
+        &mut self,
+        invitation: &Invitation,
+        invitee: PublicUser,
+    ) -> Result<()> {
+        invitation.verify()?;
+        if invitation.groupuuid() != self.uuid {
+            return Err(anyhow::anyhow!("invitation is for another group"));
+        }
+
+        // Only the inviter knows whether the invitation was redeemed already
+        let me = self.self_user().await?;
+        if invitation.inviter() != me.get_public_key() {
+            return Err(anyhow::anyhow!("invitation wasn't made by us"));
+        }
+        if !self.is_admin(invitation.inviter()).await? {
+            return Err(anyhow::anyhow!("invitation wasn't made by an admin"));
+        }
+
+        if !self
+            .group_store
+            .write()
+            .await
+            .redeem_invitation(invitation.id())?
+        {
+            return Err(anyhow::anyhow!("invitation was redeemed already"));
+        }
+
+        self.add_member(invitee).await?;
+        Ok(())
+    }
+
+    /// Gets the membership log of another member, and merges it with ours.
+    pub async fn fetch_members<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync>(
+        &mut self,
+        client: &mut Client<T>,
+    ) -> Result<()> {
+        let ops = client.request_membership(self.uuid).await?;
+        self.merge_membership(ops).await
+    }
+
+    /// Gets the file tree of a member of this group, regardless of what we selected.
+    pub async fn get_filetree(&self, user: &PublicUser) -> Result<Option<FileTree>> {
+        self.group_store.read().await.get_filetree(user)
+    }
+
+    /// Queues downloads for all files of other members which are selected (or all of them, in
+    /// autodownload mode), but which we don't have yet, and attempts all pending downloads.
+    pub async fn sync(&mut self) -> Result<()> {
+        let self_user = self.self_user().await?;
+        let matcher = self.selection.matcher()?;
+
+        let filetrees = self.group_store.read().await.get_filetrees()?;
+        for (key, tree) in filetrees {
+            if key == *self_user.get_public_key() {
+                continue;
+            }
+
+            let autodownload = self.autodownload;
+            let files: Vec<File> = tree
+                .iter()
+                .filter(|(_, f)| autodownload || matcher.is_selected(&f.path))
+                .map(|(_, f)| f.clone())
+                .collect();
+
+            for mut file in files {
+                let group_store = self.group_store.read().await;
+                // The file in this tree might not know about everyone who has it
+                if let Some(known) = group_store.get_file(file.hash.clone())? {
+                    file.merge_users(&known);
+                }
+                let wanted = !file.is_owned_by(&self_user)
+                    && group_store.get_download(&file.hash)?.is_none();
+                drop(group_store);
+
+                if wanted {
+                    self.queue_download(file).await?;
+                }
+            }
+        }
+
+        self.process_downloads().await
+    }
+
+    /// Gets the version of the file tree of a member of this group, as far as we know.
+    pub async fn get_version(&self, user: &PublicUser) -> Result<u64> {
+        self.group_store.read().await.get_version(user)
+    }
+
+    /// Gets the files we have in this group and the version of our tree, to send to other members.
+    pub async fn get_own_filetree(&self) -> Result<FileTreeUpdate> {
+        let (version, tree) = self.own_shared_tree().await?;
+        Ok(FileTreeUpdate::Full { version, tree })
+    }
+
+    /// Our file tree as we send it to others (see [shared_tree](Group::shared_tree)), and its
+    /// version. Which files are ignored is only worked out again once our tree changed.
+    async fn own_shared_tree(&self) -> Result<(u64, FileTree)> {
+        let self_user = self.self_user().await?;
+
+        let group_store = self.group_store.read().await;
+        let version = group_store.get_version(&self_user)?;
+        if let Some((cached, tree)) = group_store.get_shared_tree(&self_user)? {
+            if cached == version {
+                return Ok((version, tree));
+            }
+        }
+        drop(group_store);
+
+        let mut group_store = self.group_store.write().await;
+        let version = group_store.get_version(&self_user)?;
+        let tree = self.shared_tree(
+            group_store
+                .get_filetree(&self_user)?
+                .unwrap_or_else(FileTree::new),
+        );
+        group_store.set_shared_tree(&self_user, version, &tree)?;
+
+        Ok((version, tree))
+    }
+
+    /// Gets what changed in the files we have in this group since `since`, to send to other members.
+    /// If we don't know anymore, this is the entire tree.
+    pub async fn get_own_changes(&self, since: u64) -> Result<FileTreeUpdate> {
+        let self_user = self.self_user().await?;
+        let group_store = self.group_store.read().await;
+
+        if let Some(mut changes) = group_store.get_changes(&self_user, since)? {
+            // Leaving changes out would make our versions disagree, so they get the entire tree
+            let mut ignore = DspfsIgnore::new(&self.location);
+            let ignored = changes.iter().any(|change| match change {
+                Change::Add(file) | Change::Update { new: file, .. } => {
+                    ignore.is_ignored(&file.path, false)
+                }
+                Change::Delete(_) => false,
+            });
+
+            if !ignored {
+                // Only we can make sense of the metadata of our files
+                for file in changes.iter_mut().flat_map(Change::files_mut) {
+                    file.metadata = None;
+                }
+                return Ok(FileTreeUpdate::Changes {
+                    version: group_store.get_version(&self_user)?,
+                    changes,
+                });
+            }
+        }
+        drop(group_store);
+
+        self.get_own_filetree().await
+    }
+
+    /// Leaves everything that is ignored out of our own file tree, so other members never see it.
+    /// The metadata of our files is left out as well.
+    fn shared_tree(&self, mut tree: FileTree) -> FileTree {
+        for (_, file) in tree.iter_mut() {
+            file.metadata = None;
+        }
+
+        let mut ignore = DspfsIgnore::new(&self.location);
+        let ignored: Vec<PathBuf> = tree
+            .iter()
+            .map(|(_, f)| f.path.clone())
+            .filter(|path| ignore.is_ignored(path, false))
+            .collect();
+
+        for path in ignored {
+            tree.delete(path, false);
+        }
+        tree
+    }
+
+    /// Errors unless we take over the files of `user`, which read-only members can't share
+    fn check_shares_files(&self, user: &PublicUser) -> Result<()> {
+        match self.role(user) {
+            Some(Role::ReadOnly) => Err(anyhow::anyhow!("{:?} is read-only", user)),
+            Some(_) => Ok(()),
+            None => Err(anyhow::anyhow!("{:?} is not a member", user)),
+        }
+    }
+
+    /// Sets the filetree received from a user in the group
+    pub async fn set_filetree(
+        &mut self,
+        user: &PublicUser,
+        mut filetree: FileTree,
+        version: u64,
+    ) -> Result<()> {
+        if *user == self.self_user().await? {
+            return Err(anyhow::anyhow!("refusing to replace our own file tree"));
+        }
+        self.check_shares_files(user)?;
+        filetree
+            .check()
+            .with_context(|| format!("invalid file tree from {:?}", user))?;
+        for (_, file) in filetree.iter_mut() {
+            file.metadata = None;
+        }
+
+        self.group_store
+            .write()
+            .await
+            .set_filetree(user, filetree, version)
+            .context("saving file tree failed")
+    }
+
+    /// Applies what a user in the group told us changed in their files.
+    pub async fn apply_update(&mut self, user: &PublicUser, update: FileTreeUpdate) -> Result<()> {
+        let (version, changes) = match update {
+            FileTreeUpdate::Full { version, tree } => {
+                return self.set_filetree(user, tree, version).await
+            }
+            FileTreeUpdate::Changes {
+                version,
+                mut changes,
+            } => {
+                for file in changes.iter_mut().flat_map(Change::files_mut) {
+                    file.metadata = None;
+                }
+                (version, changes)
+            }
+        };
+
+        if *user == self.self_user().await? {
+            return Err(anyhow::anyhow!("refusing to change our own file tree"));
+        }
+        self.check_shares_files(user)?;
+        if let Some(file) = changes
+            .iter()
+            .flat_map(Change::files)
+            .find(|file| !is_safe_path(&file.path))
+        {
+            return Err(anyhow::anyhow!(
+                "{:?} changed a file at invalid path {:?}",
+                user,
+                file.path
+            ));
+        }
+
+        let mut group_store = self.group_store.write().await;
+        group_store.apply_changes(user, changes)?;
+
+        // Our copy of their change log should end up at the same version as theirs
+        let ours = group_store.get_version(user)?;
+        if ours != version {
+            return Err(anyhow::anyhow!(
+                "file tree version mismatch after applying changes ({} != {})",
+                ours,
+                version
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Asks all (online) members of this group what changed in the files they have.
+    pub async fn fetch_filetrees(&mut self) -> Result<()> {
+        let me = PrivateUser::load_from_store(self.global_store.read().await.deref().deref())
+            .context("Couldn't load user from global_store")?;
+
+        for user in self.users.clone() {
+            if user == *me.public_user() {
+                continue;
+            }
+
+            let mut client = if let Some(client) = Self::connect(&me, &user).await {
+                client
+            } else {
+                continue;
+            };
+
+            if let Err(e) = self.fetch_members(&mut client).await {
+                log::warn!("Couldn't get the members {:?} knows of: {:?}", user, e);
+            }
+            if self.check_shares_files(&user).is_err() {
+                continue;
+            }
+            if let Err(e) = self.fetch_filetree(&user, &mut client).await {
+                log::warn!("Couldn't get the files of {:?}: {:?}", user, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Brings our copy of the file tree of `user` up to date. When nothing changed this takes a
+    /// single request. Otherwise we ask for the changes since the version we have, and if our
+    /// copy still differs after that, we compare the trees folder by folder.
+    pub async fn fetch_filetree<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync>(
+        &mut self,
+        user: &PublicUser,
+        client: &mut Client<T>,
+    ) -> Result<()> {
+        if let Some(ours) = self.get_filetree(user).await? {
+            let (_, summary) = client
+                .request_summary(self.uuid, PathBuf::new(), ours.hash().clone())
+                .await?;
+            if summary == NodeSummary::Unchanged {
+                return Ok(());
+            }
+        }
+
+        let since = self.get_version(user).await?;
+        let update = client.request_changes(self.uuid, since).await?;
+        if let Err(e) = self.apply_update(user, update).await {
+            log::warn!("Changes of {:?} didn't apply: {:?}", user, e);
+        }
+
+        // Make sure we really ended up with the same tree
+        let ours = self.get_filetree(user).await?.unwrap_or_else(FileTree::new);
+        let (_, summary) = client
+            .request_summary(self.uuid, PathBuf::new(), ours.hash().clone())
+            .await?;
+        if summary != NodeSummary::Unchanged {
+            log::warn!(
+                "Our copy of the files of {:?} differs, comparing trees",
+                user
+            );
+            self.reconcile_filetree(user, client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Walks down the file tree of `user`, only asking about the folders which differ from our copy.
+    async fn reconcile_filetree<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync>(
+        &mut self,
+        user: &PublicUser,
+        client: &mut Client<T>,
+    ) -> Result<()> {
+        let mut tree = self.get_filetree(user).await?.unwrap_or_else(FileTree::new);
+        let mut version = None;
+
+        let mut pending = vec![PathBuf::new()];
+        while let Some(path) = pending.pop() {
+            let known = match tree.find(&path) {
+                Some(node) => node.hash().clone(),
+                None => FileTree::new().hash().clone(),
+            };
+
+            let (v, summary) = client
+                .request_summary(self.uuid, path.clone(), known)
+                .await?;
+            // The version of the tree as a whole is the one from when we asked about the root
+            version.get_or_insert(v);
+
+            match summary {
+                NodeSummary::Unchanged => (),
+                NodeSummary::Missing => {
+                    tree.delete(&path, true);
+                }
+                NodeSummary::Children(children) => {
+                    pending.extend(tree.apply_summary(&path, children)?)
+                }
+            }
+        }
+
+        self.set_filetree(user, tree, version.unwrap_or_default())
+            .await
+    }
+
+    /// Describes a folder in our own file tree, see [FileTree::summarize].
+    /// Also returns the version of our tree.
+    pub async fn summarize_own(
+        &self,
+        path: impl AsRef<Path>,
+        known: &Hash,
+    ) -> Result<(u64, NodeSummary)> {
+        let (version, tree) = self.own_shared_tree().await?;
+        Ok((version, tree.summarize(path, known)))
+    }
 
     /// Adds a file to the group that exists locally on your filesystem.
     /// This function will hash the file, create a [File] struct and insert it.
+    /// `path` is either absolute, or relative to the root of the group.
     pub async fn index_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
-        let self_user = self
-            .global_store
-            .read()
-            .await
-            .get_self_user()
-            .context("Could not get self user due to database error")?
-            .context("Could not get self user")?;
+        let self_user = self.self_user().await?;
+        let relative = self.relative_path(path)?;
 
-        let file = File::new(path.as_ref().to_path_buf())
-            .await
-            .context("Creating and indexing new file failed")?;
+        let old = indexer::known_file(self.get_filetree(&self_user).await?, &relative);
+        match indexer::hash_change(&self.location, relative, old).await? {
+            Indexed::Unchanged => (),
+            Indexed::Touched(file) => self
+                .group_store
+                .write()
+                .await
+                .update_metadata(&self_user, vec![file])
+                .context("saving metadata went wrong")?,
+            Indexed::Changed(change) => self
+                .group_store
+                .write()
+                .await
+                .apply_changes(&self_user, vec![change])
+                .context("adding file to database went wrong")?,
+        }
 
-        self.add_file(&self_user, file).await
+        Ok(())
+    }
+
+    /// Makes our file tree match what is on disk at `path`, which can be a file or a folder.
+    /// New and changed files are (re)indexed, and files that aren't there anymore are removed.
+    /// `path` is either absolute, or relative to the root of the group.
+    pub async fn refresh_path(&mut self, path: impl AsRef<Path>) -> Result<IndexProgress> {
+        self.index(path, |_| ()).await
+    }
+
+    /// The same as [refresh_path](Group::refresh_path), but reports how far along it is.
+    /// Indexing the root of the group (`""`) indexes everything.
+    pub async fn index(
+        &mut self,
+        path: impl AsRef<Path>,
+        progress: impl FnMut(&IndexProgress) + Send,
+    ) -> Result<IndexProgress> {
+        let mut relative = self.relative_path(path)?;
+        if relative.starts_with(".dspfs") {
+            return Ok(IndexProgress::default());
+        }
+        // Changing an ignore file can change what is shared in its entire folder
+        if relative.file_name() == Some(IGNORE_FILE.as_ref()) {
+            relative.pop();
+        }
+        let self_user = self.self_user().await?;
+
+        indexer::index(
+            &self.group_store,
+            &self_user,
+            &self.location,
+            &relative,
+            progress,
+        )
+        .await
+    }
+
+    /// Turns a path in this group into a path relative to the root of the group.
+    fn relative_path(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref();
+
+        let relative = match path.strip_prefix(&self.location) {
+            Ok(relative) => relative,
+            Err(_) if path.is_relative() => path,
+            Err(_) => return Err(anyhow::anyhow!("{:?} is not in this group", path)),
+        };
+
+        Ok(normalize_path(relative))
+    }
+
+    /// Starts watching the folder of this group, see [GroupWatcher].
+    pub fn watch(&self) -> Result<GroupWatcher>
+    where
+        S: 'static,
+    {
+        GroupWatcher::new(self.clone())
     }
 
     /// [add_file] adds a file to the relevant databases.
@@ -150,31 +901,297 @@ impl<S: Store> Group<S> {
             .context("adding file to database went wrong")
     }
 
+    /// Finds a file we have ourselves. Only our own file tree is looked at, as the paths others
+    /// have their files at mean nothing on our filesystem.
     pub async fn get_local_file(&self, hash: Hash) -> Result<Option<File>> {
-        let self_user = self
-            .global_store
+        let self_user = self.self_user().await?;
+
+        Ok(self
+            .get_filetree(&self_user)
+            .await?
+            .and_then(|tree| {
+                tree.iter()
+                    .map(|(_, file)| file)
+                    .find(|file| file.hash == hash)
+                    .cloned()
+            })
+            .filter(|file| is_safe_path(&file.path)))
+    }
+
+    async fn private_user(&self) -> Result<PrivateUser> {
+        PrivateUser::load_from_store(self.global_store.read().await.deref().deref())
+            .context("Couldn't load user from global_store")
+    }
+
+    async fn self_user(&self) -> Result<PublicUser> {
+        self.global_store
             .read()
             .await
             .get_self_user()
             .context("Could not get self user due to database error")?
-            .context("Could not get self user")?;
+            .context("Could not get self user")
+    }
 
-        Ok(self
-            .group_store
-            .read()
-            .await
-            .get_file(hash)?
-            .map(|f| {
-                if f.is_owned_by(&self_user) {
-                    None
-                } else {
-                    Some(f)
+    /// The members who have `file`. The users in a file are copies from when it was added, so
+    /// they don't know where to find anyone. Our members do.
+    pub fn owners(&self, file: &File) -> Vec<PublicUser> {
+        self.users
+            .iter()
+            .filter(|&member| file.is_owned_by(member))
+            .cloned()
+            .collect()
+    }
+
+    /// Connects to another member, if we know where to find them.
+    async fn connect(me: &PrivateUser, user: &PublicUser) -> Option<Client> {
+        let addr = if let Some(addr) = user.get_last_addr() {
+            addr
+        } else {
+            log::debug!("Don't know where to find {:?}", user);
+            return None;
+        };
+
+        let connecting = Client::connect_to(addr, me, user.get_public_key());
+        match timeout(CONNECT_TIMEOUT, connecting).await {
+            Ok(Ok(client)) => Some(client),
+            Ok(Err(e)) => {
+                log::warn!("Couldn't connect to {:?}: {:?}", user, e);
+                None
+            }
+            Err(_) => {
+                log::warn!("Connecting to {:?} timed out", user);
+                None
+            }
+        }
+    }
+
+    /// Downloads a file from all of its (online) owners in parallel, and adds it to the group.
+    /// The file will be placed at the same path relative to the group root as it has for the
+    /// people we download it from.
+    pub async fn download(&mut self, file: &File) -> Result<DownloadReport> {
+        let me = PrivateUser::load_from_store(self.global_store.read().await.deref().deref())
+            .context("Couldn't load user from global_store")?;
+
+        let mut sources: Vec<(PublicUser, Box<dyn BlockSource>)> = Vec::new();
+        for user in self.owners(file).iter().filter(|&u| u != me.public_user()) {
+            if let Some(client) = Self::connect(&me, user).await {
+                sources.push((user.clone(), Box::new(client)));
+            }
+        }
+
+        if sources.is_empty() {
+            log::info!("Nobody who has {:?} is online", file.path);
+            return Ok(DownloadReport::default());
+        }
+
+        self.download_from(file, sources).await
+    }
+
+    /// Downloads a file from the given sources and adds it to the group. Blocks are collected in
+    /// the `.dspfs` folder first, so interrupted downloads only have to fetch the missing blocks.
+    /// Once all blocks are in, the file is moved into place.
+    pub async fn download_from(
+        &mut self,
+        file: &File,
+        sources: Vec<(PublicUser, Box<dyn BlockSource>)>,
+    ) -> Result<DownloadReport> {
+        if !is_safe_path(&file.path) {
+            return Err(anyhow::anyhow!("refusing to download to {:?}", file.path));
+        }
+        if self.is_conflict(file).await? {
+            return Ok(DownloadReport {
+                conflict: true,
+                ..Default::default()
+            });
+        }
+
+        let target = self.location.join(&file.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context("failed to create parent directories")?;
+        }
+
+        let mut partial = PartialFile::open(self.dspfs_folder(), file).await?;
+        let penalised = {
+            let group_store = self.group_store.read().await;
+            let mut penalised = HashSet::new();
+            for (user, _) in &sources {
+                if group_store.get_penalty(user)?.is_active() {
+                    penalised.insert(user.clone());
                 }
-            })
-            .flatten())
+            }
+            penalised
+        };
+
+        let mut report =
+            download::download_blocks(self.uuid, file, &mut partial, sources, &penalised).await?;
+
+        // Ask others first next time
+        if !report.corrupt.is_empty() {
+            let mut group_store = self.group_store.write().await;
+            for user in &report.corrupt {
+                let mut penalty = group_store.get_penalty(user)?;
+                penalty.strike();
+                group_store.set_penalty(user, &penalty)?;
+            }
+        }
+
+        if report.complete {
+            // The file might have been changed while we were downloading
+            if self.is_conflict(file).await? {
+                report.complete = false;
+                report.conflict = true;
+                return Ok(report);
+            }
+            partial.finish(&target).await?;
+
+            // Remember what is on disk now, so we know when it is changed later
+            let metadata = fs::metadata(&target)
+                .await
+                .context("couldn't access metadata of download")?;
+            let mut file = file.clone();
+            file.metadata = Some((&metadata).into());
+
+            let self_user = self.self_user().await?;
+            self.add_file(&self_user, file).await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Returns true if there is a file at the path of `file` which we can't replace by it,
+    /// because it isn't what we last synced there: it was changed locally, or never synced at all.
+    async fn is_conflict(&self, file: &File) -> Result<bool> {
+        let target = self.location.join(&file.path);
+        let metadata = match fs::symlink_metadata(&target).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).context("couldn't access download target"),
+        };
+        if !metadata.is_file() {
+            return Ok(true);
+        }
+
+        let self_user = self.self_user().await?;
+        let synced = indexer::known_file(self.get_filetree(&self_user).await?, &file.path);
+        if matches!(&synced, Some(synced) if synced.is_unchanged(&metadata)) {
+            return Ok(false);
+        }
+
+        // The metadata changed, but maybe the contents didn't
+        let current = File::new(target).await?.hash;
+        Ok(current != file.hash && synced.map(|f| f.hash) != Some(current))
+    }
+
+    /// Adds a file to the download queue of this group. The download is attempted whenever someone
+    /// who has the file comes online, until it either succeeds or everyone who should have the
+    /// file tells us they don't have it.
+    pub async fn queue_download(&mut self, file: File) -> Result<()> {
+        self.group_store
+            .write()
+            .await
+            .queue_download(QueuedDownload::new(file))
+            .context("adding download to the queue failed")
+    }
+
+    /// Lists all downloads in the download queue, including the ones which failed.
+    pub async fn list_downloads(&self) -> Result<Vec<QueuedDownload>> {
+        self.group_store.read().await.get_downloads()
+    }
+
+    /// Removes a download from the download queue, and throws away what was already downloaded.
+    pub async fn cancel_download(&mut self, hash: &Hash) -> Result<()> {
+        let mut group_store = self.group_store.write().await;
+        let queued = group_store.get_download(hash)?;
+        group_store.remove_download(hash)?;
+        drop(group_store);
+
+        match queued {
+            Some(queued) => PartialFile::remove(self.dspfs_folder(), &queued.file).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Attempts all pending downloads
+    pub async fn process_downloads(&mut self) -> Result<()> {
+        for queued in self.list_downloads().await? {
+            if queued.state == DownloadState::Pending {
+                self.attempt_queued_download(queued).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attempts all pending downloads of files `user` has, to be used when they come online.
+    pub async fn resume_downloads(&mut self, user: &PublicUser) -> Result<()> {
+        for queued in self.list_downloads().await? {
+            if queued.state == DownloadState::Pending && queued.file.is_owned_by(user) {
+                self.attempt_queued_download(queued).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn attempt_queued_download(&mut self, mut queued: QueuedDownload) -> Result<()> {
+        let hash = queued.file.hash.clone();
+
+        let report = match self.download(&queued.file).await {
+            Ok(report) => report,
+            Err(e) => {
+                log::error!("Downloading {:?} failed: {:?}", queued.file.path, e);
+                return Ok(());
+            }
+        };
+
+        let mut group_store = self.group_store.write().await;
+
+        // It might have been cancelled in the meantime
+        if group_store.get_download(&hash)?.is_none() {
+            return Ok(());
+        }
+
+        if report.complete {
+            group_store.remove_download(&hash)
+        } else if report.conflict {
+            log::warn!(
+                "Not downloading {:?}, we have a different file there",
+                queued.file.path
+            );
+            queued.state = DownloadState::Conflict;
+            group_store.queue_download(queued)
+        } else {
+            queued.record_attempt(report, &self.self_user().await?);
+            if queued.state == DownloadState::Failed {
+                log::warn!("Nobody has {:?} anymore", queued.file.path);
+            }
+
+            group_store.queue_download(queued)
+        }
     }
 
     pub async fn get_block_contents(&self, hash: Hash, index: u64) -> Result<Option<Vec<u8>>> {
+        let mut block = if let Some(block) = self.open_block(hash, index).await? {
+            block
+        } else {
+            return Ok(None);
+        };
+
+        let mut buffer = Vec::with_capacity(block.limit() as usize);
+
+        // read block to vec
+        block
+            .read_to_end(&mut buffer)
+            .await
+            .context("reading the block failed")?;
+
+        Ok(Some(buffer))
+    }
+
+    /// Opens a block of a file we have, to read it from disk
+    pub async fn open_block(&self, hash: Hash, index: u64) -> Result<Option<Take<fs::File>>> {
         let file = if let Some(f) = self.get_local_file(hash).await? {
             f
         } else {
@@ -197,16 +1214,7 @@ impl<S: Store> Group<S> {
             .await
             .context("this block doesn't exist in this file")?;
 
-        let mut buffer = vec![0; file.block_size as usize];
-
-        // read block to vec
-        let read_bytes = open_file
-            .read(&mut buffer)
-            .await
-            .context("reading the block failed")?;
-        buffer.truncate(read_bytes);
-
-        Ok(Some(buffer))
+        Ok(Some(open_file.take(file.block_size)))
     }
 }
 
@@ -223,3 +1231,528 @@ impl<S> DerefMut for Group<S> {
         &mut self.stored_group
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_store::inmemory::InMemoryStore;
+    use crate::user::PrivateUser;
+    use tempfile::{tempdir, TempDir};
+
+    async fn test_group() -> (TempDir, Group<InMemoryStore>) {
+        let store = InMemoryStore::test_store("test").unwrap();
+        let dir = tempdir().unwrap();
+
+        let group = StoredGroup::new(dir.path());
+        std::fs::create_dir_all(group.dspfs_folder()).unwrap();
+        store.write().await.add_group(group.clone()).unwrap();
+
+        let group = group.reload(store).unwrap();
+        (dir, group)
+    }
+
+    /// A file someone else has at `path`
+    async fn remote_file(dir: &Path, path: &str) -> File {
+        let source = dir.join("source");
+        std::fs::write(&source, path).unwrap();
+
+        let mut file = File::new(source).await.unwrap();
+        file.path = path.into();
+        file
+    }
+
+    #[tokio::test]
+    async fn test_sync_selection() {
+        let (_dir, mut group) = test_group().await;
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let other = other.public_user().clone();
+
+        let tmp = tempdir().unwrap();
+        let selected = remote_file(tmp.path(), "yeet/yeet.txt").await;
+        let unselected = remote_file(tmp.path(), "yote/yeet.txt").await;
+        group.add_file(&other, selected.clone()).await.unwrap();
+        group.add_file(&other, unselected).await.unwrap();
+
+        group
+            .select(SelectionRule::Include("yeet".into()))
+            .await
+            .unwrap();
+        group.sync().await.unwrap();
+
+        // Nobody is online, so the download stays pending
+        let downloads = group.list_downloads().await.unwrap();
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(downloads[0].file.hash, selected.hash);
+        assert_eq!(downloads[0].state, DownloadState::Pending);
+
+        // The whole tree is still visible
+        let tree = group.get_filetree(&other).await.unwrap().unwrap();
+        assert_eq!(tree.iter().count(), 2);
+
+        // The selection is saved
+        let stored = group
+            .global_store
+            .read()
+            .await
+            .get_group(group.uuid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.selection, group.selection);
+    }
+
+    #[tokio::test]
+    async fn test_sync_autodownload() {
+        let (_dir, mut group) = test_group().await;
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let other = other.public_user().clone();
+        let self_user = group.self_user().await.unwrap();
+
+        let tmp = tempdir().unwrap();
+        group
+            .add_file(&other, remote_file(tmp.path(), "yeet/yeet.txt").await)
+            .await
+            .unwrap();
+        group
+            .add_file(&other, remote_file(tmp.path(), "yote/yeet.txt").await)
+            .await
+            .unwrap();
+        // We already have this one
+        let mine = remote_file(tmp.path(), "yeet.txt").await;
+        group.add_file(&other, mine.clone()).await.unwrap();
+        group.add_file(&self_user, mine).await.unwrap();
+
+        group.set_autodownload(true).await.unwrap();
+        group.sync().await.unwrap();
+
+        assert_eq!(group.list_downloads().await.unwrap().len(), 2);
+
+        let stored = group
+            .global_store
+            .read()
+            .await
+            .get_group(group.uuid)
+            .unwrap()
+            .unwrap();
+        assert!(stored.autodownload);
+    }
+
+    async fn own_paths(group: &Group<InMemoryStore>) -> Vec<PathBuf> {
+        let self_user = group.self_user().await.unwrap();
+        let mut paths: Vec<PathBuf> = match group.get_filetree(&self_user).await.unwrap() {
+            Some(tree) => tree.iter().map(|(_, f)| f.path.clone()).collect(),
+            None => Vec::new(),
+        };
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_refresh_path() {
+        let (dir, mut group) = test_group().await;
+        std::fs::create_dir_all(dir.path().join("yeet")).unwrap();
+        std::fs::write(dir.path().join("yeet/yeet.txt"), b"yeet").unwrap();
+        std::fs::write(dir.path().join("yote.txt"), b"yote").unwrap();
+        std::fs::write(dir.path().join(".dspfs/ignored"), b"yeet").unwrap();
+
+        group.refresh_path(dir.path()).await.unwrap();
+        assert_eq!(
+            own_paths(&group).await,
+            vec![PathBuf::from("yeet/yeet.txt"), PathBuf::from("yote.txt")]
+        );
+
+        // Moving a folder
+        std::fs::rename(dir.path().join("yeet"), dir.path().join("yoink")).unwrap();
+        group.refresh_path("yeet").await.unwrap();
+        group.refresh_path("yoink").await.unwrap();
+        assert_eq!(
+            own_paths(&group).await,
+            vec![PathBuf::from("yoink/yeet.txt"), PathBuf::from("yote.txt")]
+        );
+
+        // Changing a file
+        std::fs::write(dir.path().join("yote.txt"), b"yoted").unwrap();
+        group.refresh_path("yote.txt").await.unwrap();
+        let changed = File::new(dir.path().join("yote.txt")).await.unwrap();
+        assert!(group.get_local_file(changed.hash).await.unwrap().is_some());
+        assert_eq!(own_paths(&group).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_serve_own_files_only() {
+        let (dir, mut group) = test_group().await;
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let other = other.public_user().clone();
+        group.users.push(other.clone());
+
+        std::fs::write(dir.path().join("yeet.txt"), b"yeet").unwrap();
+        group.refresh_path(dir.path()).await.unwrap();
+        let ours = File::new(dir.path().join("yeet.txt")).await.unwrap();
+
+        // Someone else claims to have the same file outside of the group
+        let mut theirs = ours.clone();
+        theirs.path = "/etc/passwd".into();
+        let mut tree = FileTree::new();
+        tree.insert("etc/passwd", theirs.clone()).unwrap();
+        assert!(group.set_filetree(&other, tree, 1).await.is_err());
+        let update = FileTreeUpdate::Changes {
+            version: 1,
+            changes: vec![Change::Add(theirs.clone())],
+        };
+        assert!(group.apply_update(&other, update).await.is_err());
+
+        // Or somewhere else in the group
+        theirs.path = "yote.txt".into();
+        group.add_file(&other, theirs).await.unwrap();
+        let served = group.get_local_file(ours.hash.clone()).await.unwrap();
+        assert_eq!(served.unwrap().path, PathBuf::from("yeet.txt"));
+        assert_eq!(
+            group.get_block_contents(ours.hash, 0).await.unwrap(),
+            Some(b"yeet".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_conflict() {
+        let (dir, mut group) = test_group().await;
+        let tmp = tempdir().unwrap();
+        let theirs = remote_file(tmp.path(), "yeet.txt").await;
+
+        // Something we never synced is in the way
+        std::fs::write(dir.path().join("yeet.txt"), b"mine").unwrap();
+        let report = group.download_from(&theirs, Vec::new()).await.unwrap();
+        assert!(report.conflict && !report.complete);
+        assert_eq!(std::fs::read(dir.path().join("yeet.txt")).unwrap(), b"mine");
+
+        // Once it is synced, it may be replaced by a newer version
+        group.refresh_path("yeet.txt").await.unwrap();
+        assert!(!group.is_conflict(&theirs).await.unwrap());
+
+        // Unless it was changed locally since
+        std::fs::write(dir.path().join("yeet.txt"), b"changed").unwrap();
+        assert!(group.is_conflict(&theirs).await.unwrap());
+
+        let mut outside = theirs.clone();
+        outside.path = "../yeet.txt".into();
+        assert!(group.download_from(&outside, Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_stays_local() {
+        let (dir, mut group) = test_group().await;
+        std::fs::write(dir.path().join("yeet.txt"), b"yeet").unwrap();
+        group.refresh_path("yeet.txt").await.unwrap();
+
+        let tree = match group.get_own_filetree().await.unwrap() {
+            FileTreeUpdate::Full { tree, .. } => tree,
+            _ => panic!("expected the entire tree"),
+        };
+        assert!(tree.iter().all(|(_, f)| f.metadata.is_none()));
+
+        match group.get_own_changes(0).await.unwrap() {
+            FileTreeUpdate::Changes { changes, .. } => assert!(changes
+                .iter()
+                .flat_map(Change::files)
+                .all(|f| f.metadata.is_none())),
+            _ => panic!("expected the changes"),
+        }
+
+        // But we still remember it
+        let self_user = group.self_user().await.unwrap();
+        let ours = group.get_filetree(&self_user).await.unwrap().unwrap();
+        assert!(ours.iter().all(|(_, f)| f.metadata.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_watcher() {
+        let (dir, group) = test_group().await;
+        let _watcher = group.watch().unwrap();
+
+        std::fs::create_dir_all(dir.path().join("yeet")).unwrap();
+        std::fs::write(dir.path().join("yeet/yeet.txt"), b"yeet").unwrap();
+
+        let expected = vec![PathBuf::from("yeet/yeet.txt")];
+        for _ in 0..50 {
+            if own_paths(&group).await == expected {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(own_paths(&group).await, expected);
+
+        std::fs::remove_file(dir.path().join("yeet/yeet.txt")).unwrap();
+        for _ in 0..50 {
+            if own_paths(&group).await.is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(own_paths(&group).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let (dir, mut group) = test_group().await;
+        let (other, _) = PrivateUser::new("other").unwrap();
+        group.users.push(other.public_user().clone());
+        group
+            .select(SelectionRule::Include("yeet".into()))
+            .await
+            .unwrap();
+
+        // Copy the group folder to another computer
+        let moved = tempdir().unwrap();
+        let db = moved.path().join(".dspfs/heed.mdb");
+        std::fs::create_dir_all(&db).unwrap();
+        std::fs::copy(
+            dir.path().join(".dspfs/heed.mdb/data.mdb"),
+            db.join("data.mdb"),
+        )
+        .unwrap();
+
+        let imported = StoredGroup::import(moved.path()).unwrap();
+        assert_eq!(imported.uuid, group.uuid);
+        assert_eq!(imported.users, group.users);
+        assert_eq!(imported.selection, group.selection);
+        assert_eq!(imported.location, moved.path());
+
+        // Not a group
+        let empty = tempdir().unwrap();
+        std::fs::create_dir_all(empty.path().join(".dspfs")).unwrap();
+        assert!(StoredGroup::import(empty.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dspfsignore() {
+        let (dir, mut group) = test_group().await;
+        std::fs::create_dir_all(dir.path().join("yeet")).unwrap();
+        std::fs::write(dir.path().join("yeet/yeet.txt"), b"yeet").unwrap();
+        std::fs::write(dir.path().join("yote.txt"), b"yote").unwrap();
+        group.refresh_path("").await.unwrap();
+
+        // Adding a rule removes what it ignores
+        std::fs::write(dir.path().join(IGNORE_FILE), "yeet/\n").unwrap();
+        group.refresh_path(IGNORE_FILE).await.unwrap();
+        assert_eq!(
+            own_paths(&group).await,
+            vec![PathBuf::from(IGNORE_FILE), PathBuf::from("yote.txt")]
+        );
+
+        // Ignored files which ended up in our tree anyway are never sent
+        let self_user = group.self_user().await.unwrap();
+        let mut file = File::new(dir.path().join("yeet/yeet.txt")).await.unwrap();
+        file.path = "yeet/yeet.txt".into();
+        group.add_file(&self_user, file).await.unwrap();
+
+        let version = group.get_version(&self_user).await.unwrap();
+        match group.get_own_changes(version - 1).await.unwrap() {
+            FileTreeUpdate::Full { tree, .. } => {
+                assert_eq!(tree.iter().count(), 2);
+                assert!(tree.find("yeet/yeet.txt").is_none());
+            }
+            FileTreeUpdate::Changes { .. } => panic!("ignored file was sent"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_tree_cache() {
+        let (dir, mut group) = test_group().await;
+        std::fs::write(dir.path().join("yeet.txt"), b"yeet").unwrap();
+        group.refresh_path("").await.unwrap();
+
+        let (version, first) = group.own_shared_tree().await.unwrap();
+        let self_user = group.self_user().await.unwrap();
+        let cached = group.group_store.read().await.get_shared_tree(&self_user);
+        assert_eq!(cached.unwrap(), Some((version, first.clone())));
+
+        // A change to our tree is seen right away
+        std::fs::write(dir.path().join("yote.txt"), b"yote").unwrap();
+        group.refresh_path("yote.txt").await.unwrap();
+        let (newer, tree) = group.own_shared_tree().await.unwrap();
+        assert_eq!(newer, version + 1);
+        assert!(tree.find("yote.txt").is_some());
+
+        let (_, summary) = group.summarize_own("", first.hash()).await.unwrap();
+        assert_ne!(summary, NodeSummary::Unchanged);
+        let (_, summary) = group.summarize_own("", tree.hash()).await.unwrap();
+        assert_eq!(summary, NodeSummary::Unchanged);
+    }
+
+    #[tokio::test]
+    async fn test_membership() {
+        let (_dir, mut group) = test_group().await;
+        group.start_membership_log().await.unwrap();
+        let me = group.self_user().await.unwrap();
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let (stranger, _) = PrivateUser::new("stranger").unwrap();
+
+        assert!(group.add_member(other.public_user().clone()).await.unwrap());
+        assert!(!group.add_member(other.public_user().clone()).await.unwrap());
+        group
+            .rename_member(other.public_user(), "yeet")
+            .await
+            .unwrap();
+        assert_eq!(group.users, vec![me.clone(), other.public_user().clone()]);
+
+        // Another member gets to the same members from the log
+        let store = InMemoryStore::test_store("other").unwrap();
+        let dir = tempdir().unwrap();
+        let mut copy = StoredGroup::new(dir.path());
+        copy.uuid = group.uuid;
+        std::fs::create_dir_all(copy.dspfs_folder()).unwrap();
+        store.write().await.add_group(copy.clone()).unwrap();
+        let mut copy = copy.reload(store).unwrap();
+        // They don't start a log of their own, but wait for ours
+        assert!(copy.membership_log().await.unwrap().is_empty());
+        assert!(group.start_membership_log().await.is_err());
+
+        let ops: Vec<_> = group
+            .membership_log()
+            .await
+            .unwrap()
+            .ops()
+            .cloned()
+            .collect();
+        copy.merge_membership(ops).await.unwrap();
+        assert_eq!(copy.users, group.users);
+        assert_eq!(copy.users[1].get_username(), "yeet");
+
+        // Someone who isn't a member can't add themselves
+        let forged = SignedMembershipOp::new(
+            group.uuid,
+            copy.membership_log().await.unwrap().heads(),
+            MembershipChange::Add(stranger.public_user().clone()),
+            &stranger,
+        )
+        .unwrap();
+        copy.merge_membership(vec![forged]).await.unwrap();
+        assert_eq!(copy.users.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_removed_member() {
+        let (_dir, mut group) = test_group().await;
+        group.start_membership_log().await.unwrap();
+        let me = group.self_user().await.unwrap();
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let (stranger, _) = PrivateUser::new("stranger").unwrap();
+
+        group.add_member(other.public_user().clone()).await.unwrap();
+        group
+            .set_role(other.public_user(), Role::Admin)
+            .await
+            .unwrap();
+        let before = group.membership_log().await.unwrap().heads();
+        group.remove_member(other.public_user()).await.unwrap();
+        let after = group.membership_log().await.unwrap().heads();
+
+        // What they do after being removed doesn't count, whether or not they say they knew
+        let ops = vec![
+            SignedMembershipOp::new(
+                group.uuid,
+                after,
+                MembershipChange::Add(stranger.public_user().clone()),
+                &other,
+            )
+            .unwrap(),
+            SignedMembershipOp::new(
+                group.uuid,
+                before,
+                MembershipChange::Remove(me.get_public_key().clone()),
+                &other,
+            )
+            .unwrap(),
+        ];
+        group.merge_membership(ops).await.unwrap();
+        assert_eq!(group.users, vec![me.clone()]);
+        assert_eq!(group.role(&me), Some(Role::Admin));
+
+        // And their files aren't taken over
+        assert!(group
+            .set_filetree(other.public_user(), FileTree::new(), 0)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rename_keeps_files() {
+        let (_dir, mut group) = test_group().await;
+        group.start_membership_log().await.unwrap();
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let other = other.public_user().clone();
+
+        group.add_member(other.clone()).await.unwrap();
+        group
+            .add_file(&other, File::new_empty("yeet".into()))
+            .await
+            .unwrap();
+        group.rename_member(&other, "yeet").await.unwrap();
+
+        // Their files and change log are still where they were, under their new name
+        let renamed = group.users[1].clone();
+        assert_eq!(renamed.get_username(), "yeet");
+        let tree = group.get_filetree(&renamed).await.unwrap().unwrap();
+        assert!(tree.find("yeet").is_some());
+        assert_eq!(group.get_version(&renamed).await.unwrap(), 1);
+        assert!(matches!(
+            group.group_store.read().await.get_changes(&renamed, 0).unwrap(),
+            Some(changes) if changes.len() == 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rename_then_remove() {
+        let (_dir, mut group) = test_group().await;
+        group.start_membership_log().await.unwrap();
+        let me = group.self_user().await.unwrap();
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let other = other.public_user().clone();
+
+        group.add_member(other.clone()).await.unwrap();
+        let file = File::new_empty("yeet".into());
+        group.add_file(&me, file.clone()).await.unwrap();
+        group.add_file(&other, file.clone()).await.unwrap();
+
+        group.rename_member(&other, "yeet").await.unwrap();
+        group.remove_member(&other).await.unwrap();
+
+        // Everything we knew about them is gone, whichever name they had
+        assert!(group.get_filetree(&other).await.unwrap().is_none());
+        assert_eq!(group.get_version(&other).await.unwrap(), 0);
+        let ours = group.get_filetree(&me).await.unwrap().unwrap();
+        assert!(ours.iter().all(|(_, f)| !f.is_owned_by(&other)));
+
+        group.sync().await.unwrap();
+        assert!(group.list_downloads().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let (_dir, mut group) = test_group().await;
+        group.start_membership_log().await.unwrap();
+        let me = group.self_user().await.unwrap();
+        let (other, _) = PrivateUser::new("other").unwrap();
+        let other = other.public_user().clone();
+
+        group.add_member(other.clone()).await.unwrap();
+        group
+            .add_file(&other, File::new_empty("yeet".into()))
+            .await
+            .unwrap();
+        assert_eq!(group.role(&me), Some(Role::Admin));
+        assert_eq!(group.role(&other), Some(Role::Member));
+
+        group.set_role(&other, Role::ReadOnly).await.unwrap();
+        assert_eq!(group.role(&other), Some(Role::ReadOnly));
+
+        // What they had is gone, and we don't take over anything new
+        assert!(group.get_filetree(&other).await.unwrap().is_none());
+        let mut tree = FileTree::new();
+        tree.insert("yeet", File::new_empty("yeet".into())).unwrap();
+        assert!(group.set_filetree(&other, tree, 1).await.is_err());
+        let update = FileTreeUpdate::Changes {
+            version: 1,
+            changes: vec![Change::Add(File::new_empty("yeet".into()))],
+        };
+        assert!(group.apply_update(&other, update).await.is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::fs::file::File;
+use crate::fs::hash::{Hash, BLOCK_HASHING_ALGORITHM};
+use anyhow::{Context, Result};
+use std::io::{ErrorKind, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A file which is being downloaded. Blocks are written to a temporary file in the `.dspfs` folder,
+/// next to a bitmap of the blocks we already have (and verified). When a download is interrupted,
+/// it can continue where it left off instead of starting over.
+///
+/// The bitmap is only updated after a block has been written and synced, so a bit is never set for
+/// a block that isn't on disk. If the bitmap turns out to be damaged, the download simply starts
+/// over. Every block is checked once more before the file is moved into place, in case the disk
+/// lost something anyway.
+pub struct PartialFile {
+    file: File,
+    data: fs::File,
+    data_path: PathBuf,
+    bitmap_path: PathBuf,
+    bitmap: Vec<u8>,
+}
+
+impl PartialFile {
+    /// Downloads of the same contents to different paths each get their own partial file
+    fn paths(dspfs_folder: &Path, file: &File) -> (PathBuf, PathBuf) {
+        let mut key = file.hash.bytes().to_vec();
+        key.extend_from_slice(file.path.as_os_str().as_bytes());
+        let name = Hash::hash_block(BLOCK_HASHING_ALGORITHM, &key);
+
+        let folder = dspfs_folder.join("partial");
+        (
+            folder.join(name.to_string()),
+            folder.join(format!("{}.bitmap", name)),
+        )
+    }
+
+    /// Opens the partial download of `file`, or starts a new one if there is none.
+    pub async fn open(dspfs_folder: impl AsRef<Path>, file: &File) -> Result<Self> {
+        let (data_path, bitmap_path) = Self::paths(dspfs_folder.as_ref(), file);
+        fs::create_dir_all(dspfs_folder.as_ref().join("partial"))
+            .await
+            .context("failed to create partial download folder")?;
+
+        let bitmap_len = file.num_blocks().div_ceil(8) as usize;
+        let bitmap = match fs::read(&bitmap_path).await {
+            Ok(bitmap) if bitmap.len() == bitmap_len => bitmap,
+            _ => vec![0; bitmap_len],
+        };
+        let resuming = bitmap.iter().any(|&b| b != 0);
+
+        let data = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!resuming)
+            .open(&data_path)
+            .await
+            .context("failed to open partial download")?;
+
+        Ok(Self {
+            file: file.clone(),
+            data,
+            data_path,
+            bitmap_path,
+            bitmap,
+        })
+    }
+
+    pub fn has_block(&self, index: u64) -> bool {
+        self.bitmap[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    /// Iterates over the indices of all blocks we don't have yet
+    pub fn missing_blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.file.num_blocks()).filter(move |&i| !self.has_block(i))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing_blocks().next().is_none()
+    }
+
+    /// Writes a block to the partial file and marks it as done. The block must already be verified.
+    pub async fn write_block(&mut self, index: u64, block: &[u8]) -> Result<()> {
+        self.data
+            .seek(SeekFrom::Start(index * self.file.block_size))
+            .await
+            .context("failed to seek in partial download")?;
+        self.data
+            .write_all(block)
+            .await
+            .context("failed to write block")?;
+        self.data
+            .sync_data()
+            .await
+            .context("failed to sync partial download")?;
+
+        self.bitmap[(index / 8) as usize] |= 1 << (index % 8);
+        self.save_bitmap().await
+    }
+
+    async fn save_bitmap(&self) -> Result<()> {
+        fs::write(&self.bitmap_path, &self.bitmap)
+            .await
+            .context("failed to save block bitmap")
+    }
+
+    /// Moves a completely downloaded file to its final location, once every block in it checks
+    /// out. Blocks which don't are marked as missing again, so they are downloaded again.
+    pub async fn finish(mut self, target: impl AsRef<Path>) -> Result<()> {
+        if !self.is_complete() {
+            return Err(anyhow::anyhow!("download isn't complete yet"));
+        }
+
+        self.data
+            .sync_all()
+            .await
+            .context("failed to sync partial download")?;
+
+        self.data
+            .seek(SeekFrom::Start(0))
+            .await
+            .context("failed to seek in partial download")?;
+        let mut corrupt = Vec::new();
+        for index in 0..self.file.num_blocks() {
+            let mut block = Vec::with_capacity(self.file.block_size as usize);
+            (&mut self.data)
+                .take(self.file.block_size)
+                .read_to_end(&mut block)
+                .await
+                .context("failed to read partial download")?;
+            if !self.file.verify_block(index, &block) {
+                corrupt.push(index);
+            }
+        }
+        if !corrupt.is_empty() {
+            for index in &corrupt {
+                self.bitmap[(index / 8) as usize] &= !(1 << (index % 8));
+            }
+            self.save_bitmap().await?;
+            return Err(anyhow::anyhow!(
+                "blocks {:?} of the download were damaged on disk",
+                corrupt
+            ));
+        }
+        drop(self.data);
+
+        fs::rename(&self.data_path, target.as_ref())
+            .await
+            .context("failed to move download into place")?;
+        fs::remove_file(&self.bitmap_path)
+            .await
+            .context("failed to remove block bitmap")
+    }
+
+    /// Throws away the partial download of `file`, if there is one.
+    pub async fn remove(dspfs_folder: impl AsRef<Path>, file: &File) -> Result<()> {
+        let (data_path, bitmap_path) = Self::paths(dspfs_folder.as_ref(), file);
+
+        for path in &[data_path, bitmap_path] {
+            match fs::remove_file(path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e).context("failed to remove partial download")
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_partial_resume() {
+        let dir = tempdir().unwrap();
+
+        // Three blocks
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 7) as u8).collect();
+        let source = dir.path().join("source");
+        std::fs::File::create(&source)
+            .unwrap()
+            .write_all(&data)
+            .unwrap();
+        let file = File::new(source).await.unwrap();
+        let bs = file.block_size as usize;
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        assert_eq!(partial.missing_blocks().collect::<Vec<_>>(), vec![0, 1, 2]);
+        partial.write_block(1, &data[bs..2 * bs]).await.unwrap();
+        drop(partial);
+
+        // Continue where we left off
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        assert_eq!(partial.missing_blocks().collect::<Vec<_>>(), vec![0, 2]);
+        partial.write_block(2, &data[2 * bs..]).await.unwrap();
+        partial.write_block(0, &data[..bs]).await.unwrap();
+        assert!(partial.is_complete());
+
+        let target = dir.path().join("target");
+        partial.finish(&target).await.unwrap();
+
+        assert!(std::fs::read(&target).unwrap() == data);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("partial"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_finish_incomplete() {
+        let dir = tempdir().unwrap();
+        let file = File::new_empty("test".into());
+
+        let partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        assert!(partial.finish(dir.path().join("target")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_partial_damaged() {
+        let dir = tempdir().unwrap();
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 7) as u8).collect();
+        let source = dir.path().join("source");
+        std::fs::write(&source, &data).unwrap();
+        let file = File::new(source).await.unwrap();
+        let bs = file.block_size as usize;
+
+        let mut partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        partial.write_block(0, &data[..bs]).await.unwrap();
+        partial.write_block(1, &data[bs..2 * bs]).await.unwrap();
+        partial.write_block(2, &data[2 * bs..]).await.unwrap();
+
+        // Something got lost on its way to the disk
+        let (data_path, _) = PartialFile::paths(dir.path(), &file);
+        let mut damaged = std::fs::read(&data_path).unwrap();
+        damaged[bs] ^= 1;
+        std::fs::write(&data_path, damaged).unwrap();
+
+        let target = dir.path().join("target");
+        assert!(partial.finish(&target).await.is_err());
+        assert!(!target.exists());
+
+        let partial = PartialFile::open(dir.path(), &file).await.unwrap();
+        assert_eq!(partial.missing_blocks().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_partial_per_path() {
+        let dir = tempdir().unwrap();
+        let first = File::new_empty("first".into());
+        let second = File::new_empty("second".into());
+
+        let mut partial = PartialFile::open(dir.path(), &first).await.unwrap();
+        partial.write_block(0, &[]).await.unwrap();
+
+        // The same contents somewhere else is another download
+        let partial = PartialFile::open(dir.path(), &second).await.unwrap();
+        assert_eq!(partial.missing_blocks().count(), 1);
+
+        PartialFile::remove(dir.path(), &first).await.unwrap();
+        let partial = PartialFile::open(dir.path(), &first).await.unwrap();
+        assert_eq!(partial.missing_blocks().count(), 1);
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A glob pattern over paths relative to the root of a group, such as `photos/**/*.jpg`.
+/// A rule applies to everything below a folder it matches, so `photos` selects everything
+/// in the `photos` folder.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum SelectionRule {
+    Include(String),
+    Exclude(String),
+}
+
+impl SelectionRule {
+    fn pattern(&self) -> &str {
+        match self {
+            SelectionRule::Include(pattern) | SelectionRule::Exclude(pattern) => pattern,
+        }
+    }
+}
+
+/// The files of other members of a group we want to have locally (sparse checkout).
+/// A file is selected when it matches at least one include rule, and no exclude rules.
+/// By default nothing is selected.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Selection {
+    rules: Vec<SelectionRule>,
+}
+
+impl Selection {
+    /// Adds a rule to the selection. Errors if the pattern isn't a valid glob.
+    pub fn add(&mut self, rule: SelectionRule) -> Result<()> {
+        GlobBuilder::new(rule.pattern())
+            .build()
+            .context("invalid selection pattern")?;
+
+        if !self.rules.contains(&rule) {
+            self.rules.push(rule);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a rule from the selection. Returns false if the rule wasn't part of the selection.
+    pub fn remove(&mut self, rule: &SelectionRule) -> bool {
+        let len = self.rules.len();
+        self.rules.retain(|r| r != rule);
+        len != self.rules.len()
+    }
+
+    pub fn rules(&self) -> &[SelectionRule] {
+        &self.rules
+    }
+
+    /// Compiles the selection so it can be matched against paths
+    pub fn matcher(&self) -> Result<SelectionMatcher> {
+        let mut include = GlobSetBuilder::new();
+        let mut exclude = GlobSetBuilder::new();
+
+        for rule in &self.rules {
+            let glob = GlobBuilder::new(rule.pattern())
+                .literal_separator(true)
+                .build()
+                .context("invalid selection pattern")?;
+
+            match rule {
+                SelectionRule::Include(_) => include.add(glob),
+                SelectionRule::Exclude(_) => exclude.add(glob),
+            };
+        }
+
+        Ok(SelectionMatcher {
+            include: include.build()?,
+            exclude: exclude.build()?,
+        })
+    }
+}
+
+pub struct SelectionMatcher {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl SelectionMatcher {
+    /// Returns true if the file at `path` (relative to the group root) is selected.
+    pub fn is_selected(&self, path: impl AsRef<Path>) -> bool {
+        let mut included = false;
+
+        // A rule matching a folder applies to everything in it
+        for p in path.as_ref().ancestors() {
+            if p.as_os_str().is_empty() {
+                break;
+            }
+            if self.exclude.is_match(p) {
+                return false;
+            }
+            included |= self.include.is_match(p);
+        }
+
+        included
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(rules: Vec<SelectionRule>) -> SelectionMatcher {
+        let mut selection = Selection::default();
+        for rule in rules {
+            selection.add(rule).unwrap();
+        }
+        selection.matcher().unwrap()
+    }
+
+    #[test]
+    fn test_empty_selection() {
+        let m = selection(vec![]);
+
+        assert!(!m.is_selected("yeet.txt"));
+        assert!(!m.is_selected("yeet/yeet.txt"));
+    }
+
+    #[test]
+    fn test_select_folder() {
+        let m = selection(vec![SelectionRule::Include("yeet".into())]);
+
+        assert!(m.is_selected("yeet/yeet.txt"));
+        assert!(m.is_selected("yeet/yote/yeet.txt"));
+        assert!(!m.is_selected("yote/yeet.txt"));
+        assert!(!m.is_selected("yeet.txt"));
+    }
+
+    #[test]
+    fn test_select_glob() {
+        let m = selection(vec![
+            SelectionRule::Include("**/*.txt".into()),
+            SelectionRule::Exclude("yote".into()),
+        ]);
+
+        assert!(m.is_selected("yeet.txt"));
+        assert!(m.is_selected("yeet/yeet.txt"));
+        assert!(!m.is_selected("yote/yeet.txt"));
+        assert!(!m.is_selected("yeet/yeet.jpg"));
+    }
+
+    #[test]
+    fn test_star_stays_in_folder() {
+        let m = selection(vec![SelectionRule::Include("*.txt".into())]);
+
+        assert!(m.is_selected("yeet.txt"));
+        assert!(!m.is_selected("yeet/yeet.txt"));
+    }
+
+    #[test]
+    fn test_remove_rule() {
+        let mut s = Selection::default();
+        let rule = SelectionRule::Include("yeet".into());
+
+        s.add(rule.clone()).unwrap();
+        s.add(rule.clone()).unwrap();
+        assert_eq!(s.rules().len(), 1);
+
+        assert!(s.remove(&rule));
+        assert!(!s.remove(&rule));
+        assert!(!s.matcher().unwrap().is_selected("yeet/yeet.txt"));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let mut s = Selection::default();
+        assert!(s.add(SelectionRule::Include("[".into())).is_err());
+    }
+}
@@ -1,9 +1,15 @@
 use crate::fs::file::File;
+use crate::fs::filetree::FileTree;
+use crate::fs::group::changelog::Change;
+use crate::fs::group::download::{Penalty, QueuedDownload};
+use crate::fs::group::membership::SignedMembershipOp;
+use crate::fs::group::StoredGroup;
 use crate::fs::hash::Hash;
-use crate::user::PublicUser;
+use crate::user::{PublicKey, PublicUser};
 use anyhow::Result;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use uuid::Uuid;
 
 /// Thread safe group store
 pub type SharedGroupStore = Arc<RwLock<Box<dyn GroupStore>>>;
@@ -24,13 +30,91 @@ pub trait GroupStore: Send + Sync {
     /// Gets a specific file given a filehash
     fn get_file(&self, hash: Hash) -> Result<Option<File>>;
 
+    /// Gets the file tree of a user
+    fn get_filetree(&self, user: &PublicUser) -> Result<Option<FileTree>>;
+
+    /// Replaces the file tree of a user with one they sent us, and updates who has which file.
+    /// The users of each file in the tree are merged with the users we already knew of.
+    /// `version` is the version of the tree, and their change log starts over from there.
+    fn set_filetree(&mut self, user: &PublicUser, filetree: FileTree, version: u64) -> Result<()>;
+
+    /// Gets the file trees of all users we know the files of, by their public key
+    fn get_filetrees(&self) -> Result<Vec<(PublicKey, FileTree)>>;
+
+    /// Gets the version of a user's file tree. Every change to the tree increments the version.
+    fn get_version(&self, user: &PublicUser) -> Result<u64>;
+
+    /// Gets what a user shares of their file tree and the version it is of, as saved by
+    /// [set_shared_tree](GroupStore::set_shared_tree). It is forgotten whenever their tree changes.
+    fn get_shared_tree(&self, user: &PublicUser) -> Result<Option<(u64, FileTree)>>;
+
+    /// Saves what a user shares of their file tree at `version`
+    fn set_shared_tree(&mut self, user: &PublicUser, version: u64, tree: &FileTree) -> Result<()>;
+
+    /// Gets all changes to a user's file tree after `since`, in order. Returns None if
+    /// these changes were compacted away, or if `since` is newer than what we know of.
+    fn get_changes(&self, user: &PublicUser, since: u64) -> Result<Option<Vec<Change>>>;
+
     /// Changes a user's file from old to new.
     fn update_file(&mut self, user: &PublicUser, old: &File, new: File) -> Result<()> {
         self.delete_file(user, old)?;
         self.add_file(user, new)
     }
 
+    /// Saves the new metadata of files in a user's file tree, which are the same otherwise.
+    /// This isn't a change to their tree, so it isn't logged. See [File::is_unchanged].
+    fn update_metadata(&mut self, user: &PublicUser, files: Vec<File>) -> Result<()>;
+
     /// Deletes a file from a user's file tree, and updates who has this file.
     /// Errors if the file did not exist.
     fn delete_file(&mut self, user: &PublicUser, file: &File) -> Result<()>;
+
+    /// Applies a batch of changes to a user's file tree at once, in order.
+    fn apply_changes(&mut self, user: &PublicUser, changes: Vec<Change>) -> Result<()> {
+        for change in changes {
+            match change {
+                Change::Add(file) => self.add_file(user, file)?,
+                Change::Update { old, new } => self.update_file(user, &old, new)?,
+                Change::Delete(file) => self.delete_file(user, &file)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets everything about a user: their file tree, their change log, and that they have
+    /// any files.
+    fn remove_user(&mut self, user: &PublicUser) -> Result<()>;
+
+    /// Puts a download in the download queue, replacing the queued download of the same file.
+    fn queue_download(&mut self, download: QueuedDownload) -> Result<()>;
+
+    /// Gets the queued download of a file
+    fn get_download(&self, hash: &Hash) -> Result<Option<QueuedDownload>>;
+
+    /// Lists all downloads in the download queue
+    fn get_downloads(&self) -> Result<Vec<QueuedDownload>>;
+
+    /// Removes a download from the download queue
+    fn remove_download(&mut self, hash: &Hash) -> Result<()>;
+
+    /// Gets how much we distrust a user for sending us corrupt blocks
+    fn get_penalty(&self, user: &PublicUser) -> Result<Penalty>;
+
+    /// Saves how much we distrust a user for sending us corrupt blocks
+    fn set_penalty(&mut self, user: &PublicUser, penalty: &Penalty) -> Result<()>;
+
+    /// Saves who this group is, so it can be imported again from its `.dspfs` folder
+    fn set_group(&mut self, group: &StoredGroup) -> Result<()>;
+
+    /// Gets the group this store belongs to, if it was saved
+    fn get_group(&self) -> Result<Option<StoredGroup>>;
+
+    /// Adds ops to the membership log of the group. Ops which were already in it are ignored.
+    fn add_membership_ops(&mut self, ops: Vec<SignedMembershipOp>) -> Result<()>;
+
+    /// Gets all ops in the membership log of the group, in no particular order
+    fn get_membership_ops(&self) -> Result<Vec<SignedMembershipOp>>;
+
+    /// Remembers that the invitation with this id was redeemed. Returns false if it already was.
+    fn redeem_invitation(&mut self, id: Uuid) -> Result<bool>;
 }
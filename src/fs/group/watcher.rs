@@ -0,0 +1,86 @@
+use crate::fs::group::Group;
+use crate::global_store::Store;
+use anyhow::{Context, Result};
+use notify::{immediate_watcher, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::{timeout, Duration, Instant};
+
+/// How long a folder has to be quiet before we look at what changed. Files are often written
+/// in many small steps, which we don't want to hash over and over again.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Folders that never calm down are still looked at this often.
+const MAX_DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// Watches the folder of a group, and keeps our file tree up to date with whatever happens in it.
+/// Stops watching when dropped.
+pub struct GroupWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl GroupWatcher {
+    pub fn new<S: Store + 'static>(group: Group<S>) -> Result<Self> {
+        let (tx, rx) = unbounded_channel();
+        let dspfs_folder = group.dspfs_folder();
+
+        let mut watcher = immediate_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    // Our own bookkeeping changes all the time, and is never shared
+                    for path in event.paths {
+                        if path.starts_with(&dspfs_folder) {
+                            continue;
+                        }
+                        // The receiving end only stops when the watcher is dropped
+                        let _ = tx.send(path);
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => log::error!("watching group failed; error = {:?}", e),
+            }
+        })
+        .context("failed to create watcher")?;
+
+        watcher
+            .watch(&group.location, RecursiveMode::Recursive)
+            .context("failed to watch group folder")?;
+
+        tokio::spawn(process_changes(group, rx));
+
+        Ok(Self { _watcher: watcher })
+    }
+}
+
+/// Collects changed paths until things calm down (or for [`MAX_DEBOUNCE`] at most), and then
+/// refreshes them all at once.
+async fn process_changes<S: Store + 'static>(
+    mut group: Group<S>,
+    mut rx: UnboundedReceiver<PathBuf>,
+) {
+    while let Some(path) = rx.recv().await {
+        let mut changed = HashSet::new();
+        changed.insert(path);
+        let deadline = Instant::now() + MAX_DEBOUNCE;
+
+        loop {
+            let wait = DEBOUNCE.min(deadline.saturating_duration_since(Instant::now()));
+            match timeout(wait, rx.recv()).await {
+                Ok(Some(path)) => {
+                    changed.insert(path);
+                }
+                // Quiet for long enough, or waited long enough
+                Err(_) => break,
+                // The watcher is gone
+                Ok(None) => return,
+            }
+        }
+
+        for path in changed {
+            if let Err(e) = group.refresh_path(&path).await {
+                log::error!("updating {:?} failed; error = {:?}", path, e);
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 pub const BLOCK_HASHING_ALGORITHM: HashingAlgorithm = HashingAlgorithm::BLAKE3;
 
@@ -39,3 +40,12 @@ impl Hash {
         self.hash.as_slice()
     }
 }
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.hash {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
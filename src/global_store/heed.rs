@@ -1,6 +1,6 @@
 use crate::fs::group::StoredGroup;
 use crate::global_store::{SharedStore, Store};
-use crate::user::PublicUser;
+use crate::user::{LegacyPublicUser, PublicUser};
 use anyhow::{Context, Result};
 use heed::types::{SerdeBincode, UnalignedSlice, UnalignedType};
 use heed::{Database, Env, EnvOpenOptions, PolyDatabase};
@@ -13,6 +13,27 @@ use uuid::Uuid;
 use zerocopy::AsBytes;
 use zerocopy::Unaligned;
 
+/// The format of what is in the store. Stores from before it was recorded are migrated when
+/// they are opened, stores from newer versions of dspfs are refused.
+const STORE_VERSION: u32 = 1;
+
+/// A [StoredGroup] as stored before stores had a format version
+#[derive(serde::Deserialize)]
+struct LegacyStoredGroup {
+    uuid: Uuid,
+    users: Vec<LegacyPublicUser>,
+    location: PathBuf,
+}
+
+impl From<LegacyStoredGroup> for StoredGroup {
+    fn from(legacy: LegacyStoredGroup) -> Self {
+        let mut group = StoredGroup::new(legacy.location);
+        group.uuid = legacy.uuid;
+        group.users = legacy.users.into_iter().map(PublicUser::from).collect();
+        group
+    }
+}
+
 pub struct HeedStore {
     db_path: PathBuf,
     env: Env,
@@ -34,12 +55,68 @@ impl HeedStore {
         let main_db = env.create_poly_database(None)?;
         let groups_db = env.create_database(Some("groups"))?;
 
-        Ok(Self {
+        let store = Self {
             db_path: path.as_ref().to_path_buf(),
             env,
             main_db,
             groups_db,
-        })
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// Brings a store written by an older version of dspfs up to date, see [STORE_VERSION]
+    fn migrate(&self) -> Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+
+        let version = self
+            .main_db
+            .get::<_, UnalignedType<PolyKey>, SerdeBincode<u32>>(&wtxn, &PolyKey::Version)
+            .context("Accessing the DB went wrong")?;
+        match version {
+            Some(STORE_VERSION) => return Ok(()),
+            Some(version) => {
+                return Err(anyhow::anyhow!(
+                    "store was written by a newer version of dspfs (format {}, we know {})",
+                    version,
+                    STORE_VERSION
+                ))
+            }
+            None => (),
+        }
+
+        // Users only had an ip address, and groups nothing but their members
+        if let Some(me) = self
+            .main_db
+            .get::<_, UnalignedType<PolyKey>, SerdeBincode<LegacyPublicUser>>(&wtxn, &PolyKey::Me)
+            .context("couldn't read the user of an old store")?
+        {
+            self.main_db
+                .put::<_, UnalignedType<PolyKey>, SerdeBincode<PublicUser>>(
+                    &mut wtxn,
+                    &PolyKey::Me,
+                    &me.into(),
+                )?;
+        }
+
+        let groups = self
+            .groups_db
+            .as_polymorph()
+            .iter::<_, SerdeBincode<Uuid>, SerdeBincode<LegacyStoredGroup>>(&wtxn)?
+            .collect::<Result<Vec<_>, _>>()
+            .context("couldn't read the groups of an old store")?;
+        for (uuid, group) in groups {
+            self.groups_db.put(&mut wtxn, &uuid, &group.into())?;
+        }
+
+        self.main_db
+            .put::<_, UnalignedType<PolyKey>, SerdeBincode<u32>>(
+                &mut wtxn,
+                &PolyKey::Version,
+                &STORE_VERSION,
+            )?;
+        wtxn.commit()?;
+        Ok(())
     }
 }
 
@@ -48,6 +125,7 @@ impl HeedStore {
 enum PolyKey {
     Me,
     SigningKey,
+    Version,
 }
 
 impl Store for HeedStore {
@@ -188,3 +266,57 @@ impl Store for HeedStore {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::{PrivateUser, PublicKey};
+    use std::net::IpAddr;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("global.mdb");
+        let store = HeedStore::new_or_load(&path).unwrap();
+
+        // What a user and a group looked like before the format was recorded
+        type Legacy = (PublicKey, String, Option<IpAddr>);
+        let (user, _) = PrivateUser::new("user").unwrap();
+        let key = user.get_public_key().clone();
+        let me: Legacy = (key.clone(), "user".into(), Some([1, 2, 3, 4].into()));
+        let uuid = Uuid::new_v4();
+        let group = (uuid, vec![me.clone()], dir.path().join("group"));
+
+        let mut wtxn = store.env.write_txn().unwrap();
+        store
+            .main_db
+            .put::<_, UnalignedType<PolyKey>, SerdeBincode<Legacy>>(&mut wtxn, &PolyKey::Me, &me)
+            .unwrap();
+        store
+            .groups_db
+            .as_polymorph()
+            .put::<_, SerdeBincode<Uuid>, SerdeBincode<_>>(&mut wtxn, &uuid, &group)
+            .unwrap();
+        store
+            .main_db
+            .delete::<_, UnalignedType<PolyKey>>(&mut wtxn, &PolyKey::Version)
+            .unwrap();
+        wtxn.commit().unwrap();
+        drop(store);
+
+        let store = HeedStore::new_or_load(&path).unwrap();
+        let me = store.get_self_user().unwrap().unwrap();
+        assert_eq!(me.get_public_key(), &key);
+        assert_eq!(me.get_username(), "user");
+        assert_eq!(me.get_last_addr(), None);
+
+        let group = store.get_group(uuid).unwrap().unwrap();
+        assert_eq!(group.users, vec![me]);
+        assert_eq!(group.location, dir.path().join("group"));
+
+        // Opening it again doesn't migrate anything
+        drop(store);
+        assert!(HeedStore::new_or_load(&path).is_ok());
+    }
+}
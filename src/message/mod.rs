@@ -1,22 +1,69 @@
+use crate::fs::filetree::NodeSummary;
+use crate::fs::group::invitation::Invitation;
+use crate::fs::group::membership::SignedMembershipOp;
 use crate::fs::hash::Hash;
 use crate::user::PublicUser;
 use anyhow::{Context, Result};
 use ring::signature::Ed25519KeyPair;
 use std::fmt::Debug;
+use std::path::PathBuf;
 use uuid::Uuid;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum ErrorMessage {
     /// Someone asked for this file but we don't have it
     FileNotFound,
+    /// Someone tried to join a group with an invitation we don't accept
+    InvalidInvitation,
+    /// We don't know who sent the request, or they aren't a member of the group it is about
+    NotAuthorized,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum Message {
+    // Starts the handshake of an encrypted stream, and answers it
     Init {
         user: PublicUser,
         pubkey: Vec<u8>,
+        // the protocol version and optional features the sender speaks
+        version: u32,
+        capabilities: Vec<String>,
+        // fresh for every handshake, so an Init can't be replayed
+        nonce: Vec<u8>,
+        // the nonce of the Init this one answers
+        peer_nonce: Option<Vec<u8>>,
     },
+
+    // Finishes the handshake of an encrypted stream, proving we derived the same keys
+    HandshakeConfirm(Vec<u8>),
+
+    // Everything after this is sealed with the next key of the sender
+    Rekey,
+
+    // Opens a channel of a multiplexed connection, before anything is sent on it
+    ChannelOpen {
+        id: u32,
+    },
+
+    // Part of a message on one of the channels of a multiplexed connection
+    Channel {
+        id: u32,
+        data: Vec<u8>,
+        // whether this is the end of the message
+        last: bool,
+    },
+
+    // Lets the other side send `credit` more bytes on a channel
+    ChannelCredit {
+        id: u32,
+        credit: u32,
+    },
+
+    // Closes a channel
+    ChannelClose {
+        id: u32,
+    },
+
     String(String),
     FileBlockRequest {
         groupuuid: Uuid,
@@ -24,8 +71,49 @@ pub enum Message {
         index: u64,
     },
 
-    // Returns a file requested by a file request
-    FileBlock(Vec<u8>),
+    // Answers a file block request, the block follows as a byte stream
+    FileBlock,
+
+    // Asks for all files someone has in a group
+    FileTreeRequest {
+        groupuuid: Uuid,
+    },
+
+    // Asks what changed in the files someone has in a group after version `since`
+    FileTreeChangesRequest {
+        groupuuid: Uuid,
+        since: u64,
+    },
+
+    // Answers a file tree (changes) request, the serialized files (or changes) follow as a
+    // byte stream
+    FileTree,
+
+    // Asks what is in a folder in the file tree of someone, unless it has hash `known`
+    FileTreeNodeRequest {
+        groupuuid: Uuid,
+        path: PathBuf,
+        known: Hash,
+    },
+
+    // Returns what is in the folder requested by a file tree node request,
+    // and the version of the entire tree
+    FileTreeNode {
+        version: u64,
+        summary: NodeSummary,
+    },
+
+    // Asks to be let into a group with an invitation
+    InvitationRedeem(Invitation),
+
+    // Asks for the membership log of a group
+    MembershipLogRequest {
+        groupuuid: Uuid,
+    },
+
+    // Returns the membership log of a group, after redeeming an invitation or a membership
+    // log request
+    MembershipLog(Vec<SignedMembershipOp>),
 
     // Something went wrong!
     Error(ErrorMessage),
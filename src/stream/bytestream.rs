@@ -0,0 +1,332 @@
+use crate::stream::EncryptedStream;
+use ring::aead::MAX_TAG_LEN;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The size of the plaintext of every record, including its header
+pub const RECORD_LEN: usize = 16 * 1024;
+
+/// Every record starts with a flags byte and the length of the data in it as a little endian u32
+const HEADER_LEN: usize = 5;
+const DATA_LEN: usize = RECORD_LEN - HEADER_LEN;
+const SEALED_LEN: usize = RECORD_LEN + MAX_TAG_LEN;
+
+/// This is the last record of the byte stream
+const END: u8 = 1;
+/// Records after this one are sealed with the next key
+const REKEY: u8 = 2;
+
+fn to_io(e: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// ByteStream sends and receives raw bytes over an [EncryptedStream], so large payloads can be
+/// copied straight between disk and socket without holding them in memory.
+///
+/// Bytes are sent in encrypted records of a fixed size, padded when needed. Writing ends with
+/// `shutdown`, which tells the other side they read everything, after which the [EncryptedStream]
+/// can send messages again. Reading returns 0 bytes once the other side shut down its writing.
+/// Both sides have to agree when to switch to a byte stream, for example after a request.
+///
+/// A byte stream which is dropped before it is shut down or read to the end leaves the
+/// [EncryptedStream] halfway through it, so the [EncryptedStream] refuses to be used after that.
+/// The connection has to be closed then.
+pub struct ByteStream<'a, T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> {
+    es: &'a mut EncryptedStream<T>,
+
+    // data for the record we are writing, and the sealed record we are writing out
+    buffered: Vec<u8>,
+    sealed: Vec<u8>,
+    written: usize,
+    write_started: bool,
+    write_ended: bool,
+
+    // the record we are reading, how much of it arrived, and what is left to read of its data
+    record: Vec<u8>,
+    filled: usize,
+    start: usize,
+    end: usize,
+    read_started: bool,
+    read_ended: bool,
+}
+
+impl<'a, T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> ByteStream<'a, T> {
+    pub(super) fn new(es: &'a mut EncryptedStream<T>) -> Self {
+        Self {
+            es,
+            buffered: Vec::with_capacity(DATA_LEN),
+            sealed: Vec::new(),
+            written: 0,
+            write_started: false,
+            write_ended: false,
+            record: Vec::new(),
+            filled: 0,
+            start: 0,
+            end: 0,
+            read_started: false,
+            read_ended: false,
+        }
+    }
+
+    /// Seals everything we buffered into one record
+    fn seal(&mut self, mut flags: u8) -> io::Result<()> {
+        let rekey = self.es.rekey_due();
+        if rekey {
+            flags |= REKEY;
+        }
+
+        let mut record = Vec::with_capacity(SEALED_LEN);
+        record.push(flags);
+        record.extend_from_slice(&(self.buffered.len() as u32).to_le_bytes());
+        record.extend_from_slice(&self.buffered);
+        record.resize(RECORD_LEN, 0);
+        self.es.seal(&mut record).map_err(to_io)?;
+        if rekey {
+            self.es.next_sealing_key().map_err(to_io)?;
+        }
+
+        self.buffered.clear();
+        self.sealed = record;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Writes out the sealed record, if there is one
+    fn poll_write_sealed(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.sealed.len() {
+            let n = ready!(Pin::new(self.es.inner()).poll_write(cx, &self.sealed[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+
+        self.sealed.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reads and opens the next record
+    fn poll_read_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.read_started = true;
+        self.record.resize(SEALED_LEN, 0);
+        while self.filled < SEALED_LEN {
+            let n =
+                ready!(Pin::new(self.es.inner()).poll_read(cx, &mut self.record[self.filled..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.filled += n;
+        }
+        self.filled = 0;
+
+        let plaintext = self.es.open(&mut self.record).map_err(to_io)?;
+        let flags = plaintext[0];
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&plaintext[1..HEADER_LEN]);
+        let length = u32::from_le_bytes(length) as usize;
+        if length > DATA_LEN {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record is longer than a record can be",
+            )));
+        }
+
+        if flags & REKEY != 0 {
+            self.es.next_opening_key().map_err(to_io)?;
+        }
+        self.read_ended = flags & END != 0;
+        self.start = HEADER_LEN;
+        self.end = HEADER_LEN + length;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> AsyncRead for ByteStream<'_, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.es.check_broken().map_err(to_io)?;
+        loop {
+            if this.start < this.end {
+                let n = buf.len().min(this.end - this.start);
+                buf[..n].copy_from_slice(&this.record[this.start..this.start + n]);
+                this.start += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.read_ended {
+                return Poll::Ready(Ok(0));
+            }
+
+            ready!(this.poll_read_record(cx))?;
+        }
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> AsyncWrite for ByteStream<'_, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.es.check_broken().map_err(to_io)?;
+        if this.write_ended {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        ready!(this.poll_write_sealed(cx))?;
+        this.write_started = true;
+
+        let n = buf.len().min(DATA_LEN - this.buffered.len());
+        this.buffered.extend_from_slice(&buf[..n]);
+        if this.buffered.len() == DATA_LEN {
+            this.seal(0)?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Sends what was written so far, padding the last record
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_sealed(cx))?;
+        if !this.buffered.is_empty() {
+            this.seal(0)?;
+            ready!(this.poll_write_sealed(cx))?;
+        }
+        Pin::new(this.es.inner()).poll_flush(cx)
+    }
+
+    /// Ends the byte stream, but not the [EncryptedStream] under it
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_sealed(cx))?;
+        if !this.write_ended {
+            this.seal(END)?;
+            this.write_ended = true;
+            ready!(this.poll_write_sealed(cx))?;
+        }
+        Pin::new(this.es.inner()).poll_flush(cx)
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> Drop for ByteStream<'_, T> {
+    fn drop(&mut self) {
+        let writing = self.write_started && (!self.write_ended || !self.sealed.is_empty());
+        let reading = self.read_started && !self.read_ended;
+        if writing || reading {
+            self.es.set_broken();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::Message;
+    use crate::stream::bytestream::{DATA_LEN, SEALED_LEN};
+    use crate::stream::encryptedstream::RekeyPolicy;
+    use crate::stream::EncryptedStream;
+    use crate::user::PrivateUser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    async fn connected_pair() -> (EncryptedStream<UnixStream>, EncryptedStream<UnixStream>) {
+        let (u1, _) = PrivateUser::new("test1").unwrap();
+        let (u2, _) = PrivateUser::new("test2").unwrap();
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let receiver = tokio::spawn(EncryptedStream::receiver(rx, u2));
+        let initiator = EncryptedStream::initiator(tx, &u1).await.unwrap();
+        (initiator, receiver.await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_copy_file() {
+        let (mut initiator, mut receiver) = connected_pair().await;
+        initiator.set_rekey_policy(RekeyPolicy {
+            messages: 3,
+            ..Default::default()
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let data: Vec<u8> = (0..10 * DATA_LEN + 1234).map(|i| i as u8).collect();
+        tokio::fs::write(&path, &data).await.unwrap();
+
+        let sender = tokio::spawn(async move {
+            let mut file = tokio::fs::File::open(path).await.unwrap();
+            let mut bs = initiator.byte_stream();
+            tokio::io::copy(&mut file, &mut bs).await.unwrap();
+            bs.shutdown().await.unwrap();
+            drop(bs);
+
+            // Back to messages afterwards
+            initiator
+                .send_message(Message::String("done".into()))
+                .await
+                .unwrap();
+        });
+
+        let mut received = Vec::new();
+        receiver
+            .byte_stream()
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+        assert!(
+            matches!(receiver.recv_message(0).await.unwrap(), Message::String(s) if s == "done")
+        );
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fixed_size_records() {
+        let (u1, _) = PrivateUser::new("test1").unwrap();
+        let (u2, _) = PrivateUser::new("test2").unwrap();
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let receiver = tokio::spawn(EncryptedStream::receiver(rx, u2));
+        let mut initiator = EncryptedStream::initiator(tx, &u1).await.unwrap();
+        let mut receiver = receiver.await.unwrap().unwrap();
+        let raw = receiver.inner();
+
+        // A byte and a flush is a whole record, and so is the end
+        let mut bs = initiator.byte_stream();
+        bs.write_all(&[1]).await.unwrap();
+        bs.flush().await.unwrap();
+        bs.shutdown().await.unwrap();
+        drop(bs);
+
+        let mut records = vec![0u8; 2 * SEALED_LEN];
+        raw.read_exact(&mut records).await.unwrap();
+        drop(initiator);
+        assert_eq!(raw.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_byte_stream() {
+        let (mut initiator, mut receiver) = connected_pair().await;
+
+        // Nothing happened yet, so we can go on with messages
+        drop(initiator.byte_stream());
+        initiator
+            .send_message(Message::String("a".into()))
+            .await
+            .unwrap();
+        assert!(matches!(receiver.recv_message(0).await.unwrap(), Message::String(s) if s == "a"));
+
+        // Halfway through, the stream can't be used anymore
+        let mut bs = initiator.byte_stream();
+        bs.write_all(&[1, 2, 3]).await.unwrap();
+        drop(bs);
+        assert!(initiator
+            .send_message(Message::String("b".into()))
+            .await
+            .is_err());
+        assert!(initiator.byte_stream().write_all(&[4]).await.is_err());
+    }
+}
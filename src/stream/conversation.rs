@@ -0,0 +1,93 @@
+use crate::message::Message;
+use crate::stream::{Channel, EncryptedStream};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Anything requests can be sent and answered over: a whole [EncryptedStream], or a [Channel]
+/// of a [Multiplexer](crate::stream::Multiplexer).
+#[async_trait]
+pub trait Conversation: Send {
+    async fn send_message(&mut self, message: Message) -> Result<()>;
+
+    /// Receives the next message, which may be at most `limit` bytes long (0 means no limit)
+    async fn recv_message(&mut self, limit: usize) -> Result<Message>;
+
+    /// Sends everything in `reader` as raw bytes, without holding it all in memory
+    async fn send_bytes(&mut self, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()>;
+
+    /// Receives raw bytes sent with `send_bytes`. Fails when there are more than `limit`.
+    async fn recv_bytes(&mut self, limit: usize) -> Result<Vec<u8>>;
+}
+
+#[async_trait]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Conversation for EncryptedStream<T> {
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        EncryptedStream::send_message(self, message).await
+    }
+
+    async fn recv_message(&mut self, limit: usize) -> Result<Message> {
+        EncryptedStream::recv_message(self, limit).await
+    }
+
+    async fn send_bytes(&mut self, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let mut bytes = self.byte_stream();
+        tokio::io::copy(reader, &mut bytes)
+            .await
+            .context("failed to send bytes")?;
+        bytes.shutdown().await?;
+        Ok(())
+    }
+
+    async fn recv_bytes(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut received = Vec::new();
+        self.byte_stream()
+            .take(limit as u64 + 1)
+            .read_to_end(&mut received)
+            .await
+            .context("failed to receive bytes")?;
+
+        if received.len() > limit {
+            return Err(anyhow::anyhow!("received more than {} bytes", limit));
+        }
+        Ok(received)
+    }
+}
+
+#[async_trait]
+impl Conversation for Channel {
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        Channel::send_message(self, message).await
+    }
+
+    async fn recv_message(&mut self, limit: usize) -> Result<Message> {
+        Channel::recv_message(self, limit).await
+    }
+
+    async fn send_bytes(&mut self, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        Channel::send_bytes(self, reader).await
+    }
+
+    async fn recv_bytes(&mut self, limit: usize) -> Result<Vec<u8>> {
+        Channel::recv_bytes(self, limit).await
+    }
+}
+
+#[async_trait]
+impl<C: Conversation + ?Sized> Conversation for &mut C {
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        (**self).send_message(message).await
+    }
+
+    async fn recv_message(&mut self, limit: usize) -> Result<Message> {
+        (**self).recv_message(limit).await
+    }
+
+    async fn send_bytes(&mut self, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        (**self).send_bytes(reader).await
+    }
+
+    async fn recv_bytes(&mut self, limit: usize) -> Result<Vec<u8>> {
+        (**self).recv_bytes(limit).await
+    }
+}
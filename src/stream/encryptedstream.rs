@@ -1,17 +1,21 @@
 use crate::message::{Message, SignedMessage};
+use crate::stream::ByteStream;
+use crate::user::PublicKey as UserKey;
 use crate::user::{PrivateUser, PublicUser};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use ring::aead::*;
 use ring::agreement;
 use ring::agreement::{EphemeralPrivateKey, PublicKey, UnparsedPublicKey};
+use ring::digest::{digest, Digest, SHA256};
 use ring::error::Unspecified;
-use ring::pbkdf2::{derive, PBKDF2_HMAC_SHA256};
-use serde::export::Formatter;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SecureRandom;
 use std::fmt;
 use std::fmt::Debug;
-use std::num::NonZeroU32;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::fmt::Formatter;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 
 #[async_trait]
 trait WriteWithLength {
@@ -80,10 +84,16 @@ impl<T: AsyncReadExt + Unpin + Send + Sync> ReadWithLength for T {
 pub struct EncryptedStream<T: AsyncReadExt + AsyncWriteExt + Unpin> {
     stream: T,
     pub other_user: PublicUser,
+    // whether we started the handshake
+    initiator: bool,
+    // optional features both sides support
+    capabilities: Vec<String>,
+    // set when a byte stream was left halfway, after which we can't make sense of the stream
+    broken: bool,
 
     // symmetric key pair
-    opening_key: OpeningKey<NonceGenerator>,
-    sealing_key: SealingKey<NonceGenerator>,
+    opener: Opener,
+    sealer: Sealer,
 }
 
 impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> Debug for EncryptedStream<T> {
@@ -92,15 +102,123 @@ impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> Debug for EncryptedS
     }
 }
 
-type KDF = dyn FnOnce(&[u8]) -> std::result::Result<[u8; 32], ring::error::Unspecified>;
+/// The version of the handshake and of what is sent after it. Peers speaking another version are refused.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The sender periodically replaces its key, see [RekeyPolicy]
+pub const REKEY: &str = "rekey";
+
+/// Requests are sent on channels of a [Multiplexer](crate::stream::Multiplexer)
+pub const MULTIPLEX: &str = "multiplex";
+
+/// Optional features we support. A stream uses the ones both ends support.
+pub const CAPABILITIES: &[&str] = &[REKEY, MULTIPLEX];
+
+/// HKDF info labels, so that both directions of a stream get their own key.
+const INITIATOR_TO_RECEIVER: &[u8] = b"dspfs initiator to receiver";
+const RECEIVER_TO_INITIATOR: &[u8] = b"dspfs receiver to initiator";
+const NEXT_KEY: &[u8] = b"dspfs next key";
+
+/// When an EncryptedStream replaces the key it seals with. Whichever limit is reached first
+/// triggers it. The elapsed time is only checked when a message is sent.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub messages: u64,
+    pub bytes: u64,
+    pub interval: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            messages: 1 << 20,
+            bytes: 1 << 30,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Derives the secret which follows `secret`. This only works one way, so whoever
+/// gets hold of the current key can't read anything sent with an older one.
+fn next_secret(secret: &[u8; 32]) -> Result<[u8; 32], Unspecified> {
+    let mut next = [0u8; 32];
+    Salt::new(HKDF_SHA256, &[])
+        .extract(secret)
+        .expand(&[NEXT_KEY], HKDF_SHA256)?
+        .fill(&mut next)?;
+    Ok(next)
+}
+
+/// The serialized Init messages the two sides sent each other. These contain both identity keys,
+/// both ephemeral keys, both nonces and both capability lists. Both sides build it in the same
+/// order, so both end up with the same keys.
+struct Transcript<'a> {
+    initiator_init: &'a [u8],
+    receiver_init: &'a [u8],
+}
+
+impl Transcript<'_> {
+    fn digest(&self) -> Digest {
+        let mut bytes = Vec::with_capacity(self.initiator_init.len() + self.receiver_init.len());
+        bytes.extend_from_slice(self.initiator_init);
+        bytes.extend_from_slice(self.receiver_init);
+        digest(&SHA256, &bytes)
+    }
+
+    /// Derives the (initiator to receiver, receiver to initiator) keys from the ECDH shared secret
+    /// with HKDF-SHA256, salted with the hash of this transcript.
+    fn derive_keys(&self, shared_secret: &[u8]) -> Result<([u8; 32], [u8; 32]), Unspecified> {
+        let prk = Salt::new(HKDF_SHA256, self.digest().as_ref()).extract(shared_secret);
+
+        let mut initiator_to_receiver = [0u8; 32];
+        prk.expand(&[INITIATOR_TO_RECEIVER], HKDF_SHA256)?
+            .fill(&mut initiator_to_receiver)?;
+        let mut receiver_to_initiator = [0u8; 32];
+        prk.expand(&[RECEIVER_TO_INITIATOR], HKDF_SHA256)?
+            .fill(&mut receiver_to_initiator)?;
+
+        Ok((initiator_to_receiver, receiver_to_initiator))
+    }
+}
+
+/// What the other side told us in their Init message
+struct PeerInit {
+    user: PublicUser,
+    ephemeral: UnparsedPublicKey<Vec<u8>>,
+    capabilities: Vec<String>,
+    nonce: Vec<u8>,
+    peer_nonce: Option<Vec<u8>>,
+}
+
+/// The capabilities in `ours` which are also in `theirs`, in our order.
+fn common_capabilities(ours: &[&str], theirs: &[String]) -> Vec<String> {
+    ours.iter()
+        .filter(|c| theirs.iter().any(|t| t == *c))
+        .map(|c| c.to_string())
+        .collect()
+}
 
-// TODO: Verify PublicKey
 impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> EncryptedStream<T> {
     /// closes this encryptedstream
     pub fn close(self) {
         drop(self);
     }
 
+    /// Whether both sides of this stream support an optional feature
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Whether we are the side which started the handshake
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// Changes when this stream rekeys. Only has effect if the other side supports [REKEY].
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.sealer.policy = policy;
+    }
+
     /// Generates a ephemeral keypair for use with ECDH using Ring
     fn generate_ephemeral_keypair() -> Result<(PublicKey, EphemeralPrivateKey)> {
         // FIXME: should be passed down
@@ -116,45 +234,79 @@ impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> EncryptedStream<T> {
         Ok((my_public_key, my_private_key))
     }
 
-    /// Creates a symmetric CHACHA20_POLY1305 keypair to encrypt and decrypt all communiction
-    /// the shared_key should have been obtained using ECDH
-    fn create_symmetric_keypair(
-        shared_key: &[u8],
-    ) -> Result<(OpeningKey<NonceGenerator>, SealingKey<NonceGenerator>)> {
-        // TODO: Is this good practice? Should you make a sealing key and opening key from one shared secret?
-        //       Should we use different salts for the sealer and opener? Maybe use our username as sealer and
-        //       the other user's name as opener? Are the same key?
-        let unbound_key1 = UnboundKey::new(&CHACHA20_POLY1305, &shared_key)
-            .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
-        let unbound_key2 = UnboundKey::new(&CHACHA20_POLY1305, &shared_key)
+    /// Generates a fresh nonce for an Init message, so that it can't be replayed
+    fn generate_nonce() -> Result<Vec<u8>> {
+        let mut nonce = vec![0u8; 32];
+        ring::rand::SystemRandom::new()
+            .fill(&mut nonce)
             .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
+        Ok(nonce)
+    }
 
-        let opening_key = OpeningKey::new(unbound_key1, NonceGenerator::default());
-        let sealing_key = SealingKey::new(unbound_key2, NonceGenerator::default());
-
-        Ok((opening_key, sealing_key))
+    /// Sets up the CHACHA20_POLY1305 keys to encrypt and decrypt all communication.
+    /// The two secrets must be different, as both keys start at the same nonce.
+    fn new(
+        stream: T,
+        initiator: bool,
+        other_user: PublicUser,
+        capabilities: Vec<String>,
+        opening_secret: [u8; 32],
+        sealing_secret: [u8; 32],
+    ) -> Result<Self> {
+        let rekey = capabilities.iter().any(|c| c == REKEY);
+        Ok(Self {
+            stream,
+            other_user,
+            initiator,
+            capabilities,
+            broken: false,
+            opener: Opener::new(opening_secret)?,
+            sealer: Sealer::new(sealing_secret, rekey)?,
+        })
     }
 
     /// initiator is used to initiate an EncryptedStream
     /// this will use ECDH for key exchange and CHACHA20_POLY1305 as symmetric encryption
-    pub async fn initiator(mut stream: T, user: &PrivateUser) -> Result<Self> {
+    pub async fn initiator(stream: T, user: &PrivateUser) -> Result<Self> {
+        Self::initiate(stream, user, None).await
+    }
+
+    /// The same as [initiator](EncryptedStream::initiator), but aborts the handshake unless
+    /// the other side turns out to have the key `expected`. Use this whenever we know who we
+    /// are connecting to, so whoever else answers never gets to see our requests.
+    pub async fn initiator_expecting(
+        stream: T,
+        user: &PrivateUser,
+        expected: &UserKey,
+    ) -> Result<Self> {
+        Self::initiate(stream, user, Some(expected)).await
+    }
+
+    async fn initiate(
+        mut stream: T,
+        user: &PrivateUser,
+        expected: Option<&UserKey>,
+    ) -> Result<Self> {
         let (my_public_key, my_private_key) = Self::generate_ephemeral_keypair()?;
+        let my_nonce = Self::generate_nonce()?;
 
         let my_init_msg = Message::Init {
             user: user.public_user().to_owned(),
             pubkey: my_public_key.as_ref().to_vec(),
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            nonce: my_nonce.clone(),
+            peer_nonce: None,
         };
 
         // Sign the init message
-        let signedmsg = my_init_msg
+        let my_signed_init = my_init_msg
             .sign(user.get_keypair())
-            .context("failed to sign message")?
-            .serialize()
-            .context("failed to serialize message")?;
+            .context("failed to sign message")?;
 
         // Send a message to the user we want to connect to to initiate the Diffie Helmann exchange
         stream
-            .write_with_length(&signedmsg)
+            .write_with_length(&my_signed_init.serialize()?)
             .await
             .context("failed to write message to stream")?;
 
@@ -162,27 +314,43 @@ impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> EncryptedStream<T> {
         let other_init_message = Self::read_signed_message(&mut stream)
             .await
             .context("failed to read response from stream")?;
-        let (other_user, peer_public_key) = Self::extract_verify(&other_init_message)
+        let peer = Self::extract_verify(&other_init_message, expected)
             .context("could not verify the identity of the incoming message")?;
 
-        // PBKDF
-        let shared_key: [u8; 32] = ring::agreement::agree_ephemeral(
+        // The answer must be to this Init, and not one from an earlier handshake
+        if peer.peer_nonce.as_ref() != Some(&my_nonce) {
+            return Err(anyhow::anyhow!("response was not to our init message"));
+        }
+
+        let transcript = Transcript {
+            initiator_init: &my_signed_init.message,
+            receiver_init: &other_init_message.message,
+        };
+        let (sealing_secret, opening_secret) = ring::agreement::agree_ephemeral(
             my_private_key,
-            &peer_public_key,
+            &peer.ephemeral,
             ring::error::Unspecified,
-            Self::kdff(user.public_user().to_owned(), other_user.clone()),
+            |secret| transcript.derive_keys(secret),
         )
         .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
 
-        let (opening_key, sealing_key) = Self::create_symmetric_keypair(&shared_key)
-            .context("failed to generate symmetric keypair from shared secret")?;
-
-        Ok(Self {
+        let mut es = Self::new(
             stream,
-            other_user,
-            opening_key,
-            sealing_key,
-        })
+            true,
+            peer.user,
+            common_capabilities(CAPABILITIES, &peer.capabilities),
+            opening_secret,
+            sealing_secret,
+        )
+        .context("failed to generate symmetric keypair from shared secret")?;
+
+        // Prove we have the keys, then make them prove it too
+        let confirmation = transcript.digest().as_ref().to_vec();
+        es.send_message(Message::HandshakeConfirm(confirmation.clone()))
+            .await?;
+        es.recv_confirmation(&confirmation).await?;
+
+        Ok(es)
     }
 
     /// receiver is used to receive an EncryptedStream
@@ -197,85 +365,109 @@ impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> EncryptedStream<T> {
             .context("failed to read message from stream")?;
 
         // Extract message parts and apply diffie hellman to create a shared secret
-        let (other_user, peer_public_key) = Self::extract_verify(&signed_init_message)
+        let peer = Self::extract_verify(&signed_init_message, None)
             .context("could not verify the identity of the incoming message")?;
 
         // Now we know the identity of the other user, send something back
         let our_init_msg = Message::Init {
             user: user.public_user().to_owned(),
             pubkey: my_public_key.as_ref().to_vec(),
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            nonce: Self::generate_nonce()?,
+            peer_nonce: Some(peer.nonce),
         };
 
         // Sign, Seal, Deliver
-        let signedmsg = our_init_msg
+        let our_signed_init = our_init_msg
             .sign(user.get_keypair())
-            .context("failed to sign message")?
-            .serialize()
-            .context("failed to serialize message")?;
+            .context("failed to sign message")?;
         stream
-            .write_with_length(&signedmsg)
+            .write_with_length(&our_signed_init.serialize()?)
             .await
             .context("failed to write message to stream")?;
 
-        // TODO: Is this good practice? Should you make a sealing key and opening key from one shared secret?
-        //       Should we use different salts for the sealer and opener? Maybe use our username as sealer and
-        //       the other user's name as opener?
-        let shared_key = ring::agreement::agree_ephemeral(
+        let transcript = Transcript {
+            initiator_init: &signed_init_message.message,
+            receiver_init: &our_signed_init.message,
+        };
+        let (opening_secret, sealing_secret) = ring::agreement::agree_ephemeral(
             my_private_key,
-            &peer_public_key,
+            &peer.ephemeral,
             ring::error::Unspecified,
-            Self::kdff(other_user.clone(), user.public_user().to_owned()),
+            |secret| transcript.derive_keys(secret),
         )
         .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
 
         // Generate the CHACHA20_POLY1305 keypair
-        let (opening_key, sealing_key) = Self::create_symmetric_keypair(&shared_key)
-            .context("failed to generate symmetric keypair from shared secret")?;
-
-        Ok(Self {
+        let mut es = Self::new(
             stream,
-            other_user,
-            opening_key,
-            sealing_key,
-        })
+            false,
+            peer.user,
+            common_capabilities(CAPABILITIES, &peer.capabilities),
+            opening_secret,
+            sealing_secret,
+        )
+        .context("failed to generate symmetric keypair from shared secret")?;
+
+        // Someone replaying an old Init can't derive these keys, so they can't confirm
+        let confirmation = transcript.digest().as_ref().to_vec();
+        es.recv_confirmation(&confirmation).await?;
+        es.send_message(Message::HandshakeConfirm(confirmation))
+            .await?;
+
+        Ok(es)
     }
 
-    /// kdff returns a key derivation function to be used with ring's derive.
-    /// it uses PBKDF2_HMAC_SHA256 as the algorithm for this
-    /// and (for now) concats the intitiator's username and receiver's username as salt.
-    fn kdff(initiator: PublicUser, receiver: PublicUser) -> Box<KDF> {
-        Box::new(move |key_material| {
-            let mut salt = initiator.get_username().to_owned();
-            salt.push_str(receiver.get_username());
-
-            let mut shared_key = [0; 32];
-            derive(
-                PBKDF2_HMAC_SHA256,
-                // DO NOT MAKE 0
-                NonZeroU32::new(42u32).unwrap(),
-                salt.as_bytes(),
-                key_material,
-                &mut shared_key,
-            );
-
-            Ok(shared_key)
-        })
+    /// Receives the key confirmation of the other side, which must contain `expected`
+    async fn recv_confirmation(&mut self, expected: &[u8]) -> Result<()> {
+        match self
+            .recv_message(1024)
+            .await
+            .context("failed to receive key confirmation")?
+        {
+            Message::HandshakeConfirm(confirmation) if confirmation == expected => Ok(()),
+            _ => Err(anyhow::anyhow!("invalid key confirmation")),
+        }
     }
 
-    /// extract_verify extracts and verifies the message using the embedded key
-    /// you should verify that the PublicUser matches the key you expect.
+    /// extract_verify extracts and verifies the message using the embedded key.
+    /// When we know who we expect, any other key is rejected before anything else happens.
     fn extract_verify(
         signed_message: &SignedMessage,
-    ) -> Result<(PublicUser, UnparsedPublicKey<Vec<u8>>)> {
-        let message: Message = bincode::deserialize(&signed_message.message)
-            .context("failed to deserialize message")?;
+        expected: Option<&UserKey>,
+    ) -> Result<PeerInit> {
+        // Versions of dspfs from before the handshake had a version already differ in here
+        let message: Message = bincode::deserialize(&signed_message.message).context(
+            "failed to deserialize init message, the other side may run an incompatible version",
+        )?;
 
         // Extract message
-        let (user, key) = match message {
-            Message::Init { user, pubkey } => (
+        let peer = match message {
+            Message::Init {
                 user,
-                agreement::UnparsedPublicKey::new(&agreement::X25519, pubkey),
-            ),
+                pubkey,
+                version,
+                capabilities,
+                nonce,
+                peer_nonce,
+            } => {
+                if version != PROTOCOL_VERSION {
+                    return Err(anyhow::anyhow!(
+                        "unsupported protocol version {} (we speak {})",
+                        version,
+                        PROTOCOL_VERSION
+                    ));
+                }
+
+                PeerInit {
+                    user,
+                    ephemeral: agreement::UnparsedPublicKey::new(&agreement::X25519, pubkey),
+                    capabilities,
+                    nonce,
+                    peer_nonce,
+                }
+            }
             _ => {
                 return Err(anyhow::anyhow!(
                     "unexpected message type found: expected connection initialization message"
@@ -283,13 +475,22 @@ impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> EncryptedStream<T> {
             }
         };
 
+        if let Some(expected) = expected {
+            if peer.user.get_public_key() != expected {
+                return Err(anyhow::anyhow!(
+                    "connected to {:?} instead of who we expected",
+                    peer.user
+                ));
+            }
+        }
+
         // Check signature when we know their public key
-        // TODO: Maybe take another user as an argument so that we can verify identity early
-        user.get_public_key()
+        peer.user
+            .get_public_key()
             .ring()
             .verify(&signed_message.message, &signed_message.signature)
             .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
-        Ok((user, key))
+        Ok(peer)
     }
 
     /// Reads one signed message and decodes it. Just to make other functions in this struct a little smaller and more readable.
@@ -303,35 +504,249 @@ impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + Sync> EncryptedStream<T> {
 
     /// Encrypts + Sends a [Message]
     pub async fn send_message(&mut self, message: Message) -> Result<()> {
+        self.check_broken()?;
+        self.sealer.send_message(&mut self.stream, message).await
+    }
+
+    /// Marks this stream as unusable, see [ByteStream]
+    pub(super) fn set_broken(&mut self) {
+        self.broken = true;
+    }
+
+    pub(super) fn check_broken(&self) -> Result<()> {
+        if self.broken {
+            return Err(anyhow::anyhow!(
+                "stream is unusable after a byte stream was left unfinished"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether we should move on to the next key before sealing anything else
+    pub(super) fn rekey_due(&self) -> bool {
+        self.sealer.rekey_due()
+    }
+
+    /// Moves on to the next sealing key. The other side has to be told about this first.
+    pub(super) fn next_sealing_key(&mut self) -> Result<()> {
+        self.sealer.next_key()
+    }
+
+    /// Moves on to the next opening key, after the other side told us they did
+    pub(super) fn next_opening_key(&mut self) -> Result<()> {
+        self.opener.next_key()
+    }
+
+    /// Encrypts `data` in place, appending the tag
+    pub(super) fn seal(&mut self, data: &mut Vec<u8>) -> Result<()> {
+        self.sealer.seal(data)
+    }
+
+    /// Decrypts `data` in place, returning the plaintext part of it
+    pub(super) fn open<'d>(&mut self, data: &'d mut [u8]) -> Result<&'d mut [u8]> {
+        self.opener.open(data)
+    }
+
+    /// The stream we are encrypting
+    pub(super) fn inner(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    /// Decrypts + Receives a [Message]
+    pub async fn recv_message(&mut self, limit: usize) -> Result<Message> {
+        self.check_broken()?;
+        self.opener.recv_message(&mut self.stream, limit).await
+    }
+
+    /// Switches to sending and receiving raw bytes, see [ByteStream]
+    pub fn byte_stream(&mut self) -> ByteStream<'_, T> {
+        ByteStream::new(self)
+    }
+
+    /// Splits this stream in a half which receives and a half which sends,
+    /// so that both can be used at the same time.
+    pub fn split(self) -> (MessageReader<ReadHalf<T>>, MessageWriter<WriteHalf<T>>) {
+        let (read, write) = tokio::io::split(self.stream);
+        (
+            MessageReader {
+                stream: read,
+                opener: self.opener,
+            },
+            MessageWriter {
+                stream: write,
+                sealer: self.sealer,
+            },
+        )
+    }
+}
+
+/// The receiving half of an [EncryptedStream]
+pub struct MessageReader<R: AsyncReadExt + Unpin + Send + Sync> {
+    stream: R,
+    opener: Opener,
+}
+
+impl<R: AsyncReadExt + Unpin + Send + Sync> MessageReader<R> {
+    /// Decrypts + Receives a [Message]
+    pub async fn recv_message(&mut self, limit: usize) -> Result<Message> {
+        self.opener.recv_message(&mut self.stream, limit).await
+    }
+}
+
+/// The sending half of an [EncryptedStream]
+pub struct MessageWriter<W: AsyncWriteExt + Unpin + Send + Sync> {
+    stream: W,
+    sealer: Sealer,
+}
+
+impl<W: AsyncWriteExt + Unpin + Send + Sync> MessageWriter<W> {
+    /// The stream we are encrypting
+    pub(super) fn inner(&mut self) -> &mut W {
+        &mut self.stream
+    }
+
+    /// Encrypts + Sends a [Message]
+    pub async fn send_message(&mut self, message: Message) -> Result<()> {
+        self.sealer.send_message(&mut self.stream, message).await
+    }
+}
+
+fn unbound_key(secret: &[u8]) -> Result<UnboundKey> {
+    UnboundKey::new(&CHACHA20_POLY1305, secret)
+        .map_err(|_| anyhow::anyhow!("unspecified ring error"))
+}
+
+/// Seals everything one side sends, and moves on to the next key according to its [RekeyPolicy]
+struct Sealer {
+    key: SealingKey<NonceGenerator>,
+    secret: [u8; 32],
+
+    // whether the other side supports rekeying, when to do it,
+    // and how much we sealed since the last time
+    rekey: bool,
+    policy: RekeyPolicy,
+    messages: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl Sealer {
+    fn new(secret: [u8; 32], rekey: bool) -> Result<Self> {
+        Ok(Self {
+            key: SealingKey::new(unbound_key(&secret)?, NonceGenerator::default()),
+            secret,
+            rekey,
+            policy: RekeyPolicy::default(),
+            messages: 0,
+            bytes: 0,
+            since: Instant::now(),
+        })
+    }
+
+    fn rekey_due(&self) -> bool {
+        self.rekey
+            && (self.messages >= self.policy.messages
+                || self.bytes >= self.policy.bytes
+                || self.since.elapsed() >= self.policy.interval)
+    }
+
+    fn next_key(&mut self) -> Result<()> {
+        self.secret =
+            next_secret(&self.secret).map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
+        self.key = SealingKey::new(unbound_key(&self.secret)?, NonceGenerator::default());
+        self.messages = 0;
+        self.bytes = 0;
+        self.since = Instant::now();
+        Ok(())
+    }
+
+    fn seal(&mut self, data: &mut Vec<u8>) -> Result<()> {
+        self.messages += 1;
+        self.bytes += data.len() as u64;
+
+        self.key
+            .seal_in_place_append_tag(Aad::empty(), data)
+            .map_err(|_| anyhow::anyhow!("unspecified ring error"))
+    }
+
+    async fn send_message<W: AsyncWriteExt + Unpin + Send + Sync>(
+        &mut self,
+        stream: &mut W,
+        message: Message,
+    ) -> Result<()> {
+        if self.rekey_due() {
+            self.seal_and_write(stream, &Message::Rekey)
+                .await
+                .context("failed to send rekey message")?;
+            self.next_key()?;
+        }
+
+        self.seal_and_write(stream, &message).await
+    }
+
+    async fn seal_and_write<W: AsyncWriteExt + Unpin + Send + Sync>(
+        &mut self,
+        stream: &mut W,
+        message: &Message,
+    ) -> Result<()> {
         let mut serialized_message =
-            bincode::serialize(&message).context("failed to serialize message")?;
-        self.sealing_key
-            .seal_in_place_append_tag(Aad::empty(), &mut serialized_message)
-            .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
+            bincode::serialize(message).context("failed to serialize message")?;
+        self.seal(&mut serialized_message)?;
 
-        self.stream
+        stream
             .write_with_length(&serialized_message)
             .await
             .context("failed to write message")?;
 
         Ok(())
     }
+}
 
-    /// Decrypts + Receives a [Message]
-    pub async fn recv_message(&mut self, limit: usize) -> Result<Message> {
-        let mut msg = self
-            .stream
-            .read_with_length_limited(limit)
-            .await
-            .context("failed to read message")?;
+/// Opens everything the other side sends, following them to their next key when they say so
+struct Opener {
+    key: OpeningKey<NonceGenerator>,
+    secret: [u8; 32],
+}
 
-        self.opening_key
-            .open_in_place(Aad::empty(), &mut msg)
-            .map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
+impl Opener {
+    fn new(secret: [u8; 32]) -> Result<Self> {
+        Ok(Self {
+            key: OpeningKey::new(unbound_key(&secret)?, NonceGenerator::default()),
+            secret,
+        })
+    }
 
-        let dmsg = bincode::deserialize(&msg).context("failed to deserialize message")?;
+    fn next_key(&mut self) -> Result<()> {
+        self.secret =
+            next_secret(&self.secret).map_err(|_| anyhow::anyhow!("unspecified ring error"))?;
+        self.key = OpeningKey::new(unbound_key(&self.secret)?, NonceGenerator::default());
+        Ok(())
+    }
 
-        Ok(dmsg)
+    fn open<'d>(&mut self, data: &'d mut [u8]) -> Result<&'d mut [u8]> {
+        self.key
+            .open_in_place(Aad::empty(), data)
+            .map_err(|_| anyhow::anyhow!("unspecified ring error"))
+    }
+
+    async fn recv_message<R: AsyncReadExt + Unpin + Send + Sync>(
+        &mut self,
+        stream: &mut R,
+        limit: usize,
+    ) -> Result<Message> {
+        loop {
+            let mut msg = stream
+                .read_with_length_limited(limit)
+                .await
+                .context("failed to read message")?;
+            let msg = self.open(&mut msg)?;
+
+            match bincode::deserialize(msg).context("failed to deserialize message")? {
+                // The other side moved on to the next key, follow them
+                Message::Rekey => self.next_key()?,
+                dmsg => return Ok(dmsg),
+            }
+        }
     }
 }
 
@@ -362,11 +777,14 @@ impl NonceSequence for NonceGenerator {
 mod tests {
     use crate::init;
     use crate::message::Message;
-    use crate::stream::encryptedstream::{EncryptedStream, NonceGenerator};
+    use crate::stream::encryptedstream::{
+        common_capabilities, next_secret, EncryptedStream, NonceGenerator, ReadWithLength,
+        RekeyPolicy, Transcript, WriteWithLength, REKEY,
+    };
     use crate::user::PrivateUser;
     use log::*;
-    use ring::aead::NonceSequence;
-    use tokio::net::{TcpListener, TcpStream};
+    use ring::aead::{Aad, NonceSequence};
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
     use tokio::time::{delay_for, Duration};
 
     #[test]
@@ -385,6 +803,217 @@ mod tests {
         assert_ne!(a.as_ref(), b.as_ref())
     }
 
+    #[test]
+    fn test_directional_keys() {
+        let transcript = Transcript {
+            initiator_init: &[1; 64],
+            receiver_init: &[2; 64],
+        };
+
+        let (i2r, r2i) = transcript.derive_keys(&[3; 32]).unwrap();
+        assert_ne!(i2r, r2i);
+        assert_ne!(i2r, [3; 32]);
+
+        // Every part of the transcript goes into the keys
+        let other = Transcript {
+            receiver_init: &[4; 64],
+            ..transcript
+        };
+        let (other_i2r, other_r2i) = other.derive_keys(&[3; 32]).unwrap();
+        assert_ne!(i2r, other_i2r);
+        assert_ne!(r2i, other_r2i);
+    }
+
+    #[test]
+    fn test_common_capabilities() {
+        let theirs = vec!["b".to_string(), "c".to_string(), "a".to_string()];
+        assert_eq!(
+            common_capabilities(&["a", "b", "d"], &theirs),
+            vec!["a", "b"]
+        );
+        assert!(common_capabilities(&["a"], &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let (u1, _) = PrivateUser::new("test1").unwrap();
+        let (u2, _) = PrivateUser::new("test2").unwrap();
+
+        // Record a handshake, relaying every frame between the two sides
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (mut c, d) = UnixStream::pair().unwrap();
+        let receiver = tokio::spawn(EncryptedStream::receiver(d, u2));
+        let initiator = tokio::spawn(async move { EncryptedStream::initiator(a, &u1).await });
+        let mut from_initiator = Vec::new();
+        let mut from_receiver = Vec::new();
+        for _ in 0..2 {
+            let frame = b.read_with_length().await.unwrap();
+            c.write_with_length(&frame).await.unwrap();
+            from_initiator.push(frame);
+            let frame = c.read_with_length().await.unwrap();
+            b.write_with_length(&frame).await.unwrap();
+            from_receiver.push(frame);
+        }
+        assert!(initiator.await.unwrap().is_ok());
+        assert!(receiver.await.unwrap().is_ok());
+
+        // Replaying the initiator's side to a receiver fails at key confirmation
+        let (u4, _) = PrivateUser::new("test4").unwrap();
+        let (mut e, f) = UnixStream::pair().unwrap();
+        let receiver = tokio::spawn(EncryptedStream::receiver(f, u4));
+        for frame in &from_initiator {
+            e.write_with_length(frame).await.unwrap();
+        }
+        assert!(receiver.await.unwrap().is_err());
+
+        // Replaying the receiver's side to a new initiator fails right away
+        let (u3, _) = PrivateUser::new("test3").unwrap();
+        let (g, mut h) = UnixStream::pair().unwrap();
+        let initiator = tokio::spawn(async move { EncryptedStream::initiator(g, &u3).await });
+        h.read_with_length().await.unwrap();
+        h.write_with_length(&from_receiver[0]).await.unwrap();
+        assert!(initiator.await.unwrap().is_err());
+    }
+
+    async fn connected_pair() -> (EncryptedStream<UnixStream>, EncryptedStream<UnixStream>) {
+        let (u1, _) = PrivateUser::new("test1").unwrap();
+        let (u2, _) = PrivateUser::new("test2").unwrap();
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let receiver = tokio::spawn(EncryptedStream::receiver(rx, u2));
+        let initiator = EncryptedStream::initiator(tx, &u1).await.unwrap();
+        (initiator, receiver.await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_both_directions() {
+        let (mut initiator, mut receiver) = connected_pair().await;
+
+        // Both sides have sealed one message so far, so they are at the same nonce. With a shared
+        // key these would be encrypted with the same key and nonce, so the ciphertexts would match.
+        let mut from_initiator = b"same plaintext".to_vec();
+        initiator
+            .sealer
+            .key
+            .seal_in_place_append_tag(Aad::empty(), &mut from_initiator)
+            .unwrap();
+        let mut from_receiver = b"same plaintext".to_vec();
+        receiver
+            .sealer
+            .key
+            .seal_in_place_append_tag(Aad::empty(), &mut from_receiver)
+            .unwrap();
+        assert_ne!(from_initiator, from_receiver);
+
+        assert_eq!(
+            receiver
+                .opener
+                .key
+                .open_in_place(Aad::empty(), &mut from_initiator)
+                .unwrap(),
+            b"same plaintext"
+        );
+        assert_eq!(
+            initiator
+                .opener
+                .key
+                .open_in_place(Aad::empty(), &mut from_receiver)
+                .unwrap(),
+            b"same plaintext"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rekey() {
+        let (mut initiator, mut receiver) = connected_pair().await;
+        assert!(initiator.supports(REKEY));
+        let first_secret = initiator.sealer.secret;
+
+        initiator.set_rekey_policy(RekeyPolicy {
+            messages: 2,
+            ..Default::default()
+        });
+        for i in 0..5 {
+            initiator
+                .send_message(Message::String(i.to_string()))
+                .await
+                .unwrap();
+            match receiver.recv_message(0).await.unwrap() {
+                Message::String(s) => assert_eq!(s, i.to_string()),
+                _ => unreachable!(),
+            }
+        }
+
+        // Rekeyed twice, and the receiver followed along
+        let twice = next_secret(&next_secret(&first_secret).unwrap()).unwrap();
+        assert_eq!(initiator.sealer.secret, twice);
+        assert_eq!(receiver.opener.secret, twice);
+
+        // The other direction wasn't touched
+        assert_eq!(receiver.sealer.secret, initiator.opener.secret);
+        receiver.set_rekey_policy(RekeyPolicy {
+            bytes: 1,
+            ..Default::default()
+        });
+        let first_secret = receiver.sealer.secret;
+        receiver
+            .send_message(Message::String("a".into()))
+            .await
+            .unwrap();
+        receiver
+            .send_message(Message::String("b".into()))
+            .await
+            .unwrap();
+        assert!(matches!(initiator.recv_message(0).await.unwrap(), Message::String(s) if s == "a"));
+        assert!(matches!(initiator.recv_message(0).await.unwrap(), Message::String(s) if s == "b"));
+        assert_ne!(receiver.sealer.secret, first_secret);
+        assert_eq!(receiver.sealer.secret, initiator.opener.secret);
+    }
+
+    #[tokio::test]
+    async fn test_no_rekey_without_capability() {
+        let (mut initiator, mut receiver) = connected_pair().await;
+        // As if the other side didn't support rekeying
+        initiator.sealer.rekey = false;
+        initiator.set_rekey_policy(RekeyPolicy {
+            messages: 1,
+            ..Default::default()
+        });
+        let secret = initiator.sealer.secret;
+
+        for _ in 0..3 {
+            initiator
+                .send_message(Message::String("a".into()))
+                .await
+                .unwrap();
+            receiver.recv_message(0).await.unwrap();
+        }
+        assert_eq!(initiator.sealer.secret, secret);
+    }
+
+    #[tokio::test]
+    async fn test_expected_identity() {
+        let (u1, _) = PrivateUser::new("test1").unwrap();
+        let (u2, _) = PrivateUser::new("test2").unwrap();
+        let (impostor, _) = PrivateUser::new("test2").unwrap();
+
+        let (tx, rx) = tokio::net::UnixStream::pair().unwrap();
+        tokio::spawn(EncryptedStream::receiver(rx, impostor));
+        assert!(
+            EncryptedStream::initiator_expecting(tx, &u1, u2.get_public_key())
+                .await
+                .is_err()
+        );
+
+        let (tx, rx) = tokio::net::UnixStream::pair().unwrap();
+        let expected = u2.get_public_key().clone();
+        tokio::spawn(EncryptedStream::receiver(rx, u2));
+        let es = EncryptedStream::initiator_expecting(tx, &u1, &expected)
+            .await
+            .unwrap();
+        assert_eq!(*es.other_user.get_public_key(), expected);
+    }
+
     #[tokio::test]
     async fn test_encrypted_stream() {
         init();
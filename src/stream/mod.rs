@@ -1,4 +1,10 @@
+mod bytestream;
+mod conversation;
 mod encryptedstream;
+mod multiplex;
 mod punch;
 
-pub use encryptedstream::EncryptedStream;
+pub use bytestream::ByteStream;
+pub use conversation::Conversation;
+pub use encryptedstream::{EncryptedStream, MULTIPLEX};
+pub use multiplex::{Channel, Multiplexer};
@@ -0,0 +1,685 @@
+use crate::message::Message;
+use crate::stream::encryptedstream::{MessageReader, MessageWriter};
+use crate::stream::EncryptedStream;
+use crate::user::PublicUser;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+
+/// How many bytes may be sent on a channel before the other side has to hand out more credit
+const WINDOW: u32 = 256 * 1024;
+
+/// Messages on a channel are sent in fragments of at most this many bytes
+const FRAGMENT_LEN: usize = 16 * 1024;
+
+/// What every fragment costs on top of its data, so that even empty fragments use up credit
+const FRAGMENT_COST: u32 = 64;
+
+/// How many channels the other side may have open at once
+const MAX_CHANNELS: usize = 64;
+
+/// The largest message we accept on the connection itself, which is a fragment and its framing
+const MESSAGE_LIMIT: usize = 2 * FRAGMENT_LEN;
+
+/// Part of a message sent on a channel
+struct Fragment {
+    data: Vec<u8>,
+    // whether this is the end of the message
+    last: bool,
+}
+
+impl Fragment {
+    fn cost(&self) -> u32 {
+        self.data.len() as u32 + FRAGMENT_COST
+    }
+}
+
+/// How many bytes we may still send on a channel
+#[derive(Default)]
+struct Credit {
+    bytes: AtomicU32,
+    // woken up when there is more credit, or when the channel is closed
+    notify: Notify,
+}
+
+/// What the connection keeps about each open channel
+struct ChannelState {
+    inbox: Sender<Fragment>,
+    credit: Arc<Credit>,
+    // how many bytes the other side may still send
+    granted: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
+}
+
+impl ChannelState {
+    /// Wakes up anyone waiting on this channel, and makes them fail
+    fn close(self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.credit.notify.notify();
+    }
+}
+
+type Channels = Arc<Mutex<HashMap<u32, ChannelState>>>;
+
+/// Multiplexer runs any number of channels over one [EncryptedStream], so that for example a
+/// file tree sync and block downloads to the same peer don't have to wait for each other.
+///
+/// Each channel has its own flow control: a side can send [WINDOW] bytes on a channel before
+/// the other side reads them, after which it waits for the other side to catch up. Messages
+/// are sent in fragments, so a large message can't hold up the other channels, and the other
+/// side never buffers more than [WINDOW] bytes for each channel.
+///
+/// Opening a channel tells the other side about it, who picks it up with `accept`. Once a
+/// channel is closed its id is never used again, so anything still underway on it is ignored.
+///
+/// When both sides support [MULTIPLEX](crate::stream::MULTIPLEX),
+/// [Client](crate::dspfs::client::Client) sends every request on its own channel, and the server
+/// answers each channel on its own. Clones share the same connection.
+#[derive(Clone)]
+pub struct Multiplexer {
+    pub other_user: PublicUser,
+    outgoing: Sender<Message>,
+    channels: Channels,
+    incoming: Arc<AsyncMutex<Receiver<Channel>>>,
+    // locked until the channel with this id is announced
+    next_id: Arc<AsyncMutex<u32>>,
+}
+
+impl Multiplexer {
+    /// Takes over an encrypted stream. Both sides have to do this at the same time.
+    pub fn new<T>(es: EncryptedStream<T>) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let other_user = es.other_user.clone();
+        // The side which started the handshake uses odd channel ids, the other side even ones
+        let next_id = if es.is_initiator() { 1 } else { 2 };
+        let (reader, writer) = es.split();
+
+        let (outgoing, outgoing_rx) = channel(MAX_CHANNELS);
+        let (incoming_tx, incoming) = channel(MAX_CHANNELS);
+        let channels = Channels::default();
+
+        tokio::spawn(write_loop(writer, outgoing_rx));
+        tokio::spawn(read_loop(
+            reader,
+            channels.clone(),
+            outgoing.clone(),
+            incoming_tx,
+            next_id % 2,
+        ));
+
+        Self {
+            other_user,
+            outgoing,
+            channels,
+            incoming: Arc::new(AsyncMutex::new(incoming)),
+            next_id: Arc::new(AsyncMutex::new(next_id)),
+        }
+    }
+
+    /// Opens a new channel, and tells the other side about it
+    pub async fn open(&self) -> Result<Channel> {
+        // Channels are announced in the order of their ids, see [dispatch]
+        let mut next_id = self.next_id.lock().await;
+        let id = *next_id;
+        *next_id += 2;
+        let channel = new_channel(id, &self.channels, &self.outgoing);
+
+        self.outgoing
+            .clone()
+            .send(Message::ChannelOpen { id })
+            .await
+            .map_err(|_| anyhow::anyhow!("connection is closed"))?;
+        Ok(channel)
+    }
+
+    /// Waits for the other side to open a channel. Returns None once the connection is gone.
+    pub async fn accept(&self) -> Option<Channel> {
+        self.incoming.lock().await.recv().await
+    }
+}
+
+fn new_channel(id: u32, channels: &Channels, outgoing: &Sender<Message>) -> Channel {
+    // Every fragment costs at least FRAGMENT_COST, so the other side can't send more than this
+    let (inbox, inbox_rx) = channel((WINDOW / FRAGMENT_COST) as usize);
+    let credit = Arc::new(Credit {
+        bytes: AtomicU32::new(WINDOW),
+        ..Default::default()
+    });
+    let granted = Arc::new(AtomicU32::new(WINDOW));
+    let closed = Arc::new(AtomicBool::new(false));
+
+    channels.lock().unwrap().insert(
+        id,
+        ChannelState {
+            inbox,
+            credit: credit.clone(),
+            granted: granted.clone(),
+            closed: closed.clone(),
+        },
+    );
+
+    Channel {
+        id,
+        outgoing: outgoing.clone(),
+        channels: channels.clone(),
+        inbox: inbox_rx,
+        credit,
+        granted,
+        closed,
+        consumed: 0,
+    }
+}
+
+/// Sends a message which doesn't have to wait for anything, like credit or closing a channel.
+/// When the connection is busy, it is sent once there is room.
+fn send_control(outgoing: &Sender<Message>, message: Message) {
+    if let Err(TrySendError::Full(message)) = outgoing.clone().try_send(message) {
+        let mut outgoing = outgoing.clone();
+        if let Ok(handle) = Handle::try_current() {
+            handle.spawn(async move {
+                let _ = outgoing.send(message).await;
+            });
+        }
+    }
+}
+
+/// Sends everything the channels want to send, until they are all gone
+async fn write_loop<T: AsyncRead + AsyncWrite + Unpin + Send + Sync>(
+    mut writer: MessageWriter<WriteHalf<T>>,
+    mut outgoing: Receiver<Message>,
+) {
+    while let Some(message) = outgoing.recv().await {
+        if let Err(e) = writer.send_message(message).await {
+            log::warn!("Multiplexed connection failed: {:?}", e);
+            return;
+        }
+    }
+
+    let _ = writer.inner().shutdown().await;
+}
+
+/// Hands out everything the other side sends to the right channel. When the connection
+/// ends, so do all channels on it.
+async fn read_loop<T: AsyncRead + AsyncWrite + Unpin + Send + Sync>(
+    mut reader: MessageReader<ReadHalf<T>>,
+    channels: Channels,
+    outgoing: Sender<Message>,
+    mut incoming: Sender<Channel>,
+    our_parity: u32,
+) {
+    if let Err(e) = dispatch(&mut reader, &channels, &outgoing, &mut incoming, our_parity).await {
+        log::warn!("Multiplexed connection failed: {:?}", e);
+    }
+
+    for (_, state) in channels.lock().unwrap().drain() {
+        state.close();
+    }
+}
+
+async fn dispatch<T: AsyncRead + AsyncWrite + Unpin + Send + Sync>(
+    reader: &mut MessageReader<ReadHalf<T>>,
+    channels: &Channels,
+    outgoing: &Sender<Message>,
+    incoming: &mut Sender<Channel>,
+    our_parity: u32,
+) -> Result<()> {
+    // The highest id of a channel the other side opened
+    let mut highest = 0;
+
+    loop {
+        match reader.recv_message(MESSAGE_LIMIT).await? {
+            Message::ChannelOpen { id } => {
+                if id % 2 == our_parity || id <= highest {
+                    return Err(anyhow::anyhow!("channel {} can't be opened", id));
+                }
+                highest = id;
+
+                let theirs = channels
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .filter(|&i| i % 2 != our_parity)
+                    .count();
+                if theirs >= MAX_CHANNELS {
+                    return Err(anyhow::anyhow!("too many channels were opened"));
+                }
+                // Nobody accepting channels means the channel is dropped (and closed) right away
+                let _ = incoming.try_send(new_channel(id, channels, outgoing));
+            }
+            Message::Channel { id, data, last } => {
+                let fragment = Fragment { data, last };
+                if fragment.data.len() > FRAGMENT_LEN {
+                    return Err(anyhow::anyhow!("fragment on channel {} is too long", id));
+                }
+
+                // Channels which aren't open were closed, by either side
+                if let Some(state) = channels.lock().unwrap().get_mut(&id) {
+                    let cost = fragment.cost();
+                    let spent =
+                        state
+                            .granted
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |g| {
+                                g.checked_sub(cost)
+                            });
+                    if spent.is_err() {
+                        return Err(anyhow::anyhow!(
+                            "channel {} was sent more than its credit",
+                            id
+                        ));
+                    }
+                    if let Err(TrySendError::Full(_)) = state.inbox.try_send(fragment) {
+                        return Err(anyhow::anyhow!("channel {} has too many fragments", id));
+                    }
+                }
+            }
+            Message::ChannelCredit { id, credit } => {
+                if let Some(state) = channels.lock().unwrap().get(&id) {
+                    state.credit.bytes.fetch_add(credit, Ordering::SeqCst);
+                    state.credit.notify.notify();
+                }
+            }
+            Message::ChannelClose { id } => {
+                if let Some(state) = channels.lock().unwrap().remove(&id) {
+                    state.close();
+                }
+            }
+            message => log::warn!("Received message outside of any channel: {:?}", message),
+        }
+    }
+}
+
+/// One logical stream of messages on a [Multiplexer]. Closed when dropped.
+pub struct Channel {
+    id: u32,
+    outgoing: Sender<Message>,
+    channels: Channels,
+    inbox: Receiver<Fragment>,
+    credit: Arc<Credit>,
+    granted: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
+    // bytes we read but didn't give the other side credit for yet
+    consumed: u32,
+}
+
+impl Channel {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Sends a message, waiting until the other side has room for it. Fails once the channel
+    /// is closed.
+    pub async fn send_message(&mut self, message: Message) -> Result<()> {
+        let data = bincode::serialize(&message).context("failed to serialize message")?;
+
+        let mut fragments = data.chunks(FRAGMENT_LEN).peekable();
+        while let Some(fragment) = fragments.next() {
+            self.send_fragment(Fragment {
+                data: fragment.to_vec(),
+                last: fragments.peek().is_none(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_fragment(&mut self, fragment: Fragment) -> Result<()> {
+        let cost = fragment.cost();
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("channel {} is closed", self.id));
+            }
+
+            // We are the only one taking credit, so nobody can take it in between
+            if self.credit.bytes.load(Ordering::SeqCst) >= cost {
+                self.credit.bytes.fetch_sub(cost, Ordering::SeqCst);
+                break;
+            }
+            self.credit.notify.notified().await;
+        }
+
+        self.outgoing
+            .send(Message::Channel {
+                id: self.id,
+                data: fragment.data,
+                last: fragment.last,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("connection is closed"))
+    }
+
+    /// Receives the next message, which may be at most `limit` bytes long (0 means no limit),
+    /// and lets the other side send more when we caught up. A message which is too long
+    /// closes the channel.
+    pub async fn recv_message(&mut self, limit: usize) -> Result<Message> {
+        let data = self.recv_fragments(limit).await?;
+        bincode::deserialize(&data).context("failed to deserialize message")
+    }
+
+    /// Sends everything in `reader` as raw bytes. When reading fails halfway, the channel is
+    /// closed, as the other side can't tell.
+    pub async fn send_bytes(&mut self, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let mut buffer = vec![0; FRAGMENT_LEN];
+        loop {
+            let read = match reader.read(&mut buffer).await {
+                Ok(read) => read,
+                Err(e) => {
+                    self.close();
+                    return Err(e).context("failed to read what to send");
+                }
+            };
+
+            // An empty fragment ends the bytes
+            self.send_fragment(Fragment {
+                data: buffer[..read].to_vec(),
+                last: read == 0,
+            })
+            .await?;
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receives raw bytes sent with `send_bytes`. More than `limit` closes the channel.
+    pub async fn recv_bytes(&mut self, limit: usize) -> Result<Vec<u8>> {
+        self.recv_fragments(limit).await
+    }
+
+    /// Receives fragments up to the last one, which may be at most `limit` bytes together
+    /// (0 means no limit)
+    async fn recv_fragments(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        loop {
+            let fragment = self.recv_fragment().await?;
+            data.extend_from_slice(&fragment.data);
+            if limit != 0 && data.len() > limit {
+                self.close();
+                return Err(anyhow::anyhow!("message was larger than the read limit"));
+            }
+            if fragment.last {
+                return Ok(data);
+            }
+        }
+    }
+
+    async fn recv_fragment(&mut self) -> Result<Fragment> {
+        let fragment = self
+            .inbox
+            .recv()
+            .await
+            .with_context(|| format!("channel {} is closed", self.id))?;
+
+        // Fragments cost much less than half the window, so the other side never waits for
+        // credit which we are holding on to
+        self.consumed += fragment.cost();
+        if self.consumed >= WINDOW / 2 {
+            self.granted.fetch_add(self.consumed, Ordering::SeqCst);
+            send_control(
+                &self.outgoing,
+                Message::ChannelCredit {
+                    id: self.id,
+                    credit: self.consumed,
+                },
+            );
+            self.consumed = 0;
+        }
+
+        Ok(fragment)
+    }
+
+    /// Stops using this channel, and tells the other side
+    fn close(&mut self) {
+        if let Some(state) = self.channels.lock().unwrap().remove(&self.id) {
+            state.close();
+            send_control(&self.outgoing, Message::ChannelClose { id: self.id });
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::Message;
+    use crate::stream::multiplex::{Channel, Multiplexer, MAX_CHANNELS, WINDOW};
+    use crate::stream::EncryptedStream;
+    use crate::user::PrivateUser;
+    use std::time::Duration;
+    use tokio::net::UnixStream;
+    use tokio::time::timeout;
+
+    async fn connected_pair() -> (Multiplexer, Multiplexer) {
+        let (u1, _) = PrivateUser::new("test1").unwrap();
+        let (u2, _) = PrivateUser::new("test2").unwrap();
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let receiver = tokio::spawn(EncryptedStream::receiver(rx, u2));
+        let initiator = EncryptedStream::initiator(tx, &u1).await.unwrap();
+        let receiver = receiver.await.unwrap().unwrap();
+        (Multiplexer::new(initiator), Multiplexer::new(receiver))
+    }
+
+    fn string(message: Message) -> String {
+        match message {
+            Message::String(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    /// A message of about a kilobyte
+    fn filler(i: u32) -> Message {
+        Message::String(format!("{:01000}", i))
+    }
+
+    /// Sends on a channel nobody reads until it runs out of credit. Returns how many messages fit.
+    async fn fill(channel: &mut Channel) -> u32 {
+        let mut sent = 0;
+        while timeout(
+            Duration::from_millis(100),
+            channel.send_message(filler(sent)),
+        )
+        .await
+        .is_ok()
+        {
+            sent += 1;
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let (m1, m2) = connected_pair().await;
+
+        let mut a = m1.open().await.unwrap();
+        let mut b = m1.open().await.unwrap();
+        let mut c = m2.open().await.unwrap();
+        assert_ne!(a.id(), b.id());
+        assert_ne!(a.id(), c.id());
+        assert_ne!(b.id(), c.id());
+
+        a.send_message(Message::String("a".into())).await.unwrap();
+        b.send_message(Message::String("b".into())).await.unwrap();
+        c.send_message(Message::String("c".into())).await.unwrap();
+
+        let mut a2 = m2.accept().await.unwrap();
+        let mut b2 = m2.accept().await.unwrap();
+        let mut c2 = m1.accept().await.unwrap();
+        assert_eq!(a2.id(), a.id());
+        assert_eq!(string(a2.recv_message(0).await.unwrap()), "a");
+        assert_eq!(string(b2.recv_message(0).await.unwrap()), "b");
+        assert_eq!(string(c2.recv_message(0).await.unwrap()), "c");
+
+        b2.send_message(Message::String("reply".into()))
+            .await
+            .unwrap();
+        assert_eq!(string(b.recv_message(0).await.unwrap()), "reply");
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() {
+        let (m1, m2) = connected_pair().await;
+        let mut slow = m1.open().await.unwrap();
+        let mut fast = m1.open().await.unwrap();
+
+        // Credit is counted in bytes
+        let sent = fill(&mut slow).await;
+        assert!(sent > 0 && sent < WINDOW / 1000);
+
+        // Other channels don't have to wait for it
+        fast.send_message(Message::String("fast".into()))
+            .await
+            .unwrap();
+        let mut slow2 = m2.accept().await.unwrap();
+        let mut fast2 = m2.accept().await.unwrap();
+        assert_eq!(string(fast2.recv_message(0).await.unwrap()), "fast");
+
+        // Reading from the slow channel lets the sender continue
+        for i in 0..sent / 2 + 1 {
+            assert_eq!(
+                string(slow2.recv_message(0).await.unwrap()),
+                string(filler(i))
+            );
+        }
+        timeout(
+            Duration::from_secs(1),
+            slow.send_message(Message::String("more".into())),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (m1, m2) = connected_pair().await;
+
+        let mut a = m1.open().await.unwrap();
+        a.send_message(Message::String("a".into())).await.unwrap();
+        let mut a2 = m2.accept().await.unwrap();
+        assert_eq!(string(a2.recv_message(0).await.unwrap()), "a");
+
+        drop(a);
+        assert!(a2.recv_message(0).await.is_err());
+        // However often they try
+        for _ in 0..64 {
+            let sent = timeout(
+                Duration::from_secs(1),
+                a2.send_message(Message::String("b".into())),
+            );
+            assert!(sent.await.unwrap().is_err());
+        }
+
+        // Also when they were waiting for credit when it was closed
+        let mut c = m1.open().await.unwrap();
+        fill(&mut c).await;
+        let waiting = tokio::spawn(async move {
+            let sent = c.send_message(filler(0)).await;
+            (c, sent)
+        });
+        drop(m2.accept().await.unwrap());
+        let (mut c, sent) = timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(sent.is_err());
+        assert!(c.send_message(Message::String("c".into())).await.is_err());
+
+        // The connection ending closes every channel
+        let mut b = m1.open().await.unwrap();
+        b.send_message(Message::String("b".into())).await.unwrap();
+        let mut b2 = m2.accept().await.unwrap();
+        drop(m1);
+        drop(b);
+        assert!(b2.recv_message(0).await.is_ok());
+        assert!(b2.recv_message(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_too_many_channels() {
+        let (m1, m2) = connected_pair().await;
+
+        let mut opened = Vec::new();
+        for _ in 0..MAX_CHANNELS {
+            let mut channel = m1.open().await.unwrap();
+            channel
+                .send_message(Message::String("hi".into()))
+                .await
+                .unwrap();
+            opened.push(channel);
+        }
+        let mut first = m2.accept().await.unwrap();
+        assert_eq!(string(first.recv_message(0).await.unwrap()), "hi");
+
+        // One more than allowed ends the connection
+        let mut extra = m1.open().await.unwrap();
+        extra
+            .send_message(Message::String("hi".into()))
+            .await
+            .unwrap();
+        let closed = timeout(Duration::from_secs(1), first.recv_message(0));
+        assert!(closed.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_closed_channel_stays_closed() {
+        let (m1, m2) = connected_pair().await;
+
+        let mut a = m1.open().await.unwrap();
+        let a2 = m2.accept().await.unwrap();
+        drop(a2);
+
+        // Whatever was still underway on it doesn't open it again
+        let _ = a.send_message(Message::String("late".into())).await;
+        let mut b = m1.open().await.unwrap();
+        b.send_message(Message::String("b".into())).await.unwrap();
+
+        let mut b2 = m2.accept().await.unwrap();
+        assert_eq!(b2.id(), b.id());
+        assert_eq!(string(b2.recv_message(0).await.unwrap()), "b");
+    }
+
+    #[tokio::test]
+    async fn test_large_message() {
+        let (m1, m2) = connected_pair().await;
+        let mut a = m1.open().await.unwrap();
+        let mut a2 = m2.accept().await.unwrap();
+
+        // Larger than the window is fine, as long as it is read
+        let sender = tokio::spawn(async move {
+            a.send_message(Message::String("7".repeat(4 * WINDOW as usize)))
+                .await
+                .unwrap();
+            a
+        });
+        assert_eq!(
+            string(a2.recv_message(0).await.unwrap()),
+            "7".repeat(4 * WINDOW as usize)
+        );
+        let mut a = sender.await.unwrap();
+
+        // But not larger than the reader wants, which closes the channel
+        let sender = tokio::spawn(async move {
+            let _ = a.send_message(Message::String("7".repeat(1000))).await;
+            a
+        });
+        assert!(a2.recv_message(100).await.is_err());
+        assert!(a2.recv_message(0).await.is_err());
+        let mut a = sender.await.unwrap();
+        let closed = timeout(Duration::from_secs(1), async {
+            while a.send_message(Message::String("a".into())).await.is_ok() {}
+        });
+        assert!(closed.await.is_ok());
+    }
+}
@@ -3,9 +3,10 @@ mod public;
 
 use anyhow::{Context, Result};
 pub use private::PrivateUser;
+pub(crate) use public::LegacyPublicUser;
 pub use public::PublicUser;
 use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
-use serde::export::TryFrom;
+use std::convert::TryFrom;
 use zerocopy::{AsBytes, LayoutVerified};
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Eq, PartialEq, Debug, AsBytes)]
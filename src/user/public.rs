@@ -1,6 +1,6 @@
 use crate::user::PublicKey;
 use std::hash::{Hash, Hasher};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 
 type SymmetricKey = u8;
 
@@ -9,9 +9,25 @@ pub struct PublicUser {
     // ed25519 public key
     public_key: PublicKey,
     username: String,
+    last_addr: Option<SocketAddr>,
+}
+
+/// A [PublicUser] as stored before stores had a format version. It only knew the ip address of
+/// a user, and not the port their server listens on.
+#[derive(serde::Deserialize)]
+pub(crate) struct LegacyPublicUser {
+    public_key: PublicKey,
+    username: String,
+    #[allow(dead_code)]
     last_ip: Option<IpAddr>,
 }
 
+impl From<LegacyPublicUser> for PublicUser {
+    fn from(legacy: LegacyPublicUser) -> Self {
+        Self::new(legacy.public_key, &legacy.username)
+    }
+}
+
 impl Hash for PublicUser {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.public_key.0.hash(state);
@@ -31,7 +47,7 @@ impl PublicUser {
         Self {
             public_key,
             username: username.into(),
-            last_ip: None,
+            last_addr: None,
         }
     }
 
@@ -42,4 +58,17 @@ impl PublicUser {
     pub fn get_username(&self) -> &String {
         &self.username
     }
+
+    pub fn set_username(&mut self, username: impl Into<String>) {
+        self.username = username.into();
+    }
+
+    /// The address this user's dspfs server was last seen listening on
+    pub fn get_last_addr(&self) -> Option<SocketAddr> {
+        self.last_addr
+    }
+
+    pub fn set_last_addr(&mut self, addr: SocketAddr) {
+        self.last_addr = Some(addr);
+    }
 }